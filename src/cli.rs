@@ -1,6 +1,11 @@
+use std::{net::SocketAddr, path::PathBuf};
+
 use clap::{Parser, Subcommand};
 
+use crate::{config::discovery::DiscoveryMode, transfers::tracker::Direction};
+
 const DEFAULT_UDP_PORT: &str = "7070";
+const DEFAULT_WEBSOCKET_PORT: &str = "7071";
 
 #[derive(Parser)]
 #[command(name = "alacrite")]
@@ -15,23 +20,35 @@ pub struct Args {
     #[arg(short, long, env = "ALACRITE_CONFIG")]
     pub config: Option<String>,
 
-    /// WebSocket transfer port
-    // #[arg(short, long, env = "ALACRITE_WS_PORT", default_value = DEFAULT_WEBSOCKET_PORT)]
-    // pub ws_port: u16,
+    /// WebSocket transfer port, the port given to `--local` is used in local mode
+    #[arg(long, env = "ALACRITE_WS_PORT", default_value = DEFAULT_WEBSOCKET_PORT)]
+    pub ws_port: u16,
+
+    /// Download directory, overriding the config file
+    #[arg(short, long, env = "ALACRITE_DOWNLOAD_DIR")]
+    pub download_dir: Option<PathBuf>,
 
-    /// Download directory
-    // #[arg(short, long, env = "ALACRITE_DOWNLOAD_DIR")]
-    // pub download_dir: Option<String>,
+    /// Control socket of the daemon
+    #[arg(long, env = "ALACRITE_SOCKET")]
+    pub socket: Option<PathBuf>,
 
-    /// Enable local mode for testing (disables UDP discovery)
+    /// Enable local mode for testing (replaces LAN discovery with discovery on this host)
     /// Format: IP:PORT (e.g., 127.0.0.1:3000)
-    // #[arg(long, env = "ALACRITE_LOCAL")]
-    // pub local: Option<String>,
+    #[arg(long, env = "ALACRITE_LOCAL")]
+    pub local: Option<SocketAddr>,
 
     /// UDP broadcast discovery port
     #[arg(short, long, env = "ALACRITE_UDP_PORT", default_value = DEFAULT_UDP_PORT)]
     pub udp_port: u16,
 
+    /// How to reach other peers during discovery, overriding the config file
+    #[arg(long, env = "ALACRITE_DISCOVERY_MODE", value_enum)]
+    pub discovery_mode: Option<DiscoveryMode>,
+
+    /// Shared secret of a group to discover peers in, may be repeated
+    #[arg(short, long = "group", env = "ALACRITE_GROUPS")]
+    pub groups: Vec<String>,
+
     /// Peer name for identification
     // #[arg(short, long, env = "ALACRITE_NAME", default_value = "alacrite-peer")]
     // pub name: String,
@@ -43,11 +60,21 @@ pub struct Args {
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum Command {
-    /// Start UDP broadcast discovery
+    /// Scan for peers once, print them and exit
+    ///
+    /// Exits with an error when no peer responds, for use in scripts and health checks.
     Discover {
         /// Show detailed information
         #[arg(long)]
         verbose: bool,
+
+        /// Seconds to wait for responses, overriding the config file
+        #[arg(short, long)]
+        timeout: Option<u64>,
+
+        /// Print peers as JSON, for scripting
+        #[arg(long)]
+        json: bool,
     },
 
     /// List discovered peers and known peers
@@ -55,16 +82,185 @@ pub enum Command {
         /// Show detailed information
         #[arg(long)]
         verbose: bool,
+
+        /// Measure throughput to each peer, sending it a few megabytes
+        #[arg(long)]
+        bandwidth: bool,
+
+        /// Print peers as JSON, for scripting
+        #[arg(long)]
+        json: bool,
+
+        /// Keep running and print peers as they join and leave
+        #[arg(long)]
+        watch: bool,
+
+        #[command(subcommand)]
+        action: Option<PeersCommand>,
+    },
+
+    /// Forward discovery packets between this subnet and relays on other subnets
+    Relay {
+        /// Address of a relay on another subnet as host:port, may be repeated
+        #[arg(long = "to", value_delimiter = ',')]
+        relays: Vec<String>,
     },
 
-    /// Send files to a specific peer
+    /// Wake a sleeping peer with a Wake-on-LAN magic packet
+    Wake {
+        /// Peer by ID, ID prefix, label, or hostname
+        peer: String,
+    },
+
+    /// Run discovery and receive files in the background, controlled by the other commands
+    Daemon,
+
+    /// Send files to a specific peer, through the daemon
     Send {
-        /// Target peer by ID or name
+        /// Target peer by ID, ID prefix, label, or hostname
         #[arg(long, short = 't')]
         to: String,
 
         /// Files or directories to send
         #[arg(required = true)]
-        paths: Vec<String>,
+        paths: Vec<PathBuf>,
+
+        /// Most bytes per second to send, instead of the per-peer limit of the config file
+        /// Takes a K, M or G suffix (e.g. 500K, 2M), the overall limit still applies
+        #[arg(long, value_parser = parse_rate)]
+        limit: Option<u64>,
+    },
+
+    /// Accept a file offered to the daemon
+    Accept {
+        /// Offer by ID or ID prefix, may be left out when only one offer is waiting
+        offer: Option<String>,
+    },
+
+    /// Reject a file offered to the daemon
+    Reject {
+        /// Offer by ID or ID prefix, may be left out when only one offer is waiting
+        offer: Option<String>,
+
+        /// Reason shown to the sender
+        #[arg(long)]
+        reason: Option<String>,
+    },
+
+    /// List the daemon's active, queued and recently finished transfers
+    Transfers {
+        /// Print transfers as JSON, for scripting
+        #[arg(long)]
+        json: bool,
+
+        /// Keep running and print transfers as they are queued, progress and finish
+        #[arg(long)]
+        watch: bool,
     },
+
+    /// Pause a running transfer, from either end
+    Pause {
+        /// Transfer by ID or ID prefix, may be left out when only one is unfinished
+        transfer: Option<String>,
+    },
+
+    /// Resume a paused transfer, from either end
+    Resume {
+        /// Transfer by ID or ID prefix, may be left out when only one is unfinished
+        transfer: Option<String>,
+    },
+
+    /// Cancel a queued or running transfer, from either end
+    ///
+    /// The partial file is deleted unless partial downloads are enabled.
+    Cancel {
+        /// Transfer by ID or ID prefix, may be left out when only one is unfinished
+        transfer: Option<String>,
+    },
+
+    /// Pair with another peer so each trusts the other's key, through the daemon
+    ///
+    /// Without a code, shows one and waits for another peer to type it in with
+    /// `alacrite pair <CODE>`. The code itself is never sent.
+    Pair {
+        /// Code shown by the other peer
+        code: Option<String>,
+
+        /// Peer showing the code by ID, ID prefix, label, or hostname, every online peer
+        /// is tried when left out
+        #[arg(long, requires = "code")]
+        with: Option<String>,
+    },
+
+    /// List finished transfers, including those of earlier runs
+    History {
+        /// Only transfers with this peer, by ID, ID prefix, label, hostname, or IP address
+        #[arg(long)]
+        peer: Option<String>,
+
+        /// Only sent or only received files
+        #[arg(long, value_enum)]
+        direction: Option<Direction>,
+
+        /// Only transfers finished on or after this date (YYYY-MM-DD) or RFC 3339 time
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Only transfers finished before this date (YYYY-MM-DD) or RFC 3339 time
+        #[arg(long)]
+        until: Option<String>,
+
+        /// Only the most recent transfers
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Print transfers as JSON, for scripting
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum PeersCommand {
+    /// Remember a peer to probe directly, for networks that block broadcast and mDNS
+    Add {
+        /// Address of the peer's discovery port as host:port (e.g., 10.0.2.15:7070)
+        address: String,
+    },
+
+    /// Forget a peer added with `peers add`
+    Remove {
+        /// Address the peer was added with
+        address: String,
+    },
+
+    /// Name a peer, or clear its name when no label is given
+    Label {
+        /// Peer by ID, ID prefix, label, or hostname
+        peer: String,
+
+        /// Name shown for the peer in `peers`
+        label: Option<String>,
+    },
+}
+
+/// Parse a number of bytes with an optional binary K, M or G suffix
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    let rate = rate.trim();
+    let (number, multiplier) = match rate.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&rate[..index], 1024.0),
+        Some((index, 'M' | 'm')) => (&rate[..index], 1024.0 * 1024.0),
+        Some((index, 'G' | 'g')) => (&rate[..index], 1024.0 * 1024.0 * 1024.0),
+        _ => (rate, 1.0),
+    };
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{rate} isn't a number of bytes, like 500K or 2M"))?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err("The limit has to be above zero".to_string());
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok((number * multiplier).round().max(1.0) as u64)
 }
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use color_eyre::{Result, eyre::eyre};
+
+use crate::{
+    commands::peers,
+    daemon::control::{ControlClient, ControlRequest, ControlResponse},
+    network_discovery::udp_broadcast::UdpBroadcastDiscovery,
+    ssh::key_manager::KeyManager,
+};
+
+/// Scan for peers for `timeout` and print the ones that responded
+///
+/// Fails when no peer responded, so scripts can tell from the exit code.
+pub fn run(
+    mut discovery: UdpBroadcastDiscovery,
+    timeout: Duration,
+    key_manager: &KeyManager,
+    verbose: bool,
+    json: bool,
+) -> Result<()> {
+    let live = discovery.scan(timeout)?;
+    let rows = peers::rows(&live, discovery.registry(), key_manager, false);
+
+    if json {
+        peers::print_json(&rows)?;
+    } else {
+        peers::print_table(&rows, verbose);
+    }
+
+    if live.is_empty() {
+        return Err(eyre!("No peers responded within {}s", timeout.as_secs()));
+    }
+
+    Ok(())
+}
+
+/// Have a running daemon, which holds the discovery port, scan for peers for `timeout`
+/// and print the ones that responded
+///
+/// Fails when no peer responded, like [`run`].
+pub async fn from_daemon(
+    mut client: ControlClient,
+    timeout: Duration,
+    verbose: bool,
+    json: bool,
+) -> Result<()> {
+    let request = ControlRequest::Discover {
+        timeout_secs: timeout.as_secs(),
+    };
+    let ControlResponse::Peers { peers: rows } = client.request(&request).await? else {
+        return Err(eyre!("Unexpected response from the daemon"));
+    };
+
+    if json {
+        peers::print_json(&rows)?;
+    } else {
+        peers::print_table(&rows, verbose);
+    }
+
+    if rows.is_empty() {
+        return Err(eyre!("No peers responded within {}s", timeout.as_secs()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use color_eyre::{Result, eyre::eyre};
+
+use crate::{
+    commands::{
+        peers::{self, format_bytes},
+        transfers,
+    },
+    network_discovery::registry::PeerRegistry,
+    transfers::{
+        history::TransferLog,
+        tracker::{Direction, Transfer},
+    },
+};
+
+/// Which finished transfers to show
+pub struct HistoryFilter<'a> {
+    /// Peer by id, id prefix, label, hostname, or IP address
+    pub peer: Option<&'a str>,
+    pub direction: Option<Direction>,
+    /// Date or RFC 3339 time the transfers finished at or after
+    pub since: Option<&'a str>,
+    /// Date or RFC 3339 time the transfers finished before
+    pub until: Option<&'a str>,
+    /// Only the most recent ones
+    pub limit: Option<usize>,
+}
+
+/// Print finished transfers from the transfer log, oldest first
+pub fn run(
+    data_dir: &Path,
+    registry: &PeerRegistry,
+    filter: &HistoryFilter,
+    json: bool,
+) -> Result<()> {
+    let since = filter.since.map(parse_time).transpose()?;
+    let until = filter.until.map(parse_time).transpose()?;
+
+    let mut transfers: Vec<Transfer> = TransferLog::new(data_dir)
+        .load()?
+        .into_iter()
+        .filter(|transfer| {
+            let finished_at = transfer.finished_at.unwrap_or(transfer.queued_at);
+
+            filter
+                .peer
+                .is_none_or(|query| matches_peer(transfer, registry, query))
+                && filter
+                    .direction
+                    .is_none_or(|direction| transfer.direction == direction)
+                && since.is_none_or(|since| finished_at >= since)
+                && until.is_none_or(|until| finished_at < until)
+        })
+        .collect();
+
+    if let Some(limit) = filter.limit {
+        transfers.drain(..transfers.len().saturating_sub(limit));
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&transfers)?);
+        return Ok(());
+    }
+
+    if transfers.is_empty() {
+        println!("No transfers found");
+        return Ok(());
+    }
+
+    let mut table = vec![
+        ["DATE", "DIR", "PEER", "FILE", "SIZE", "STATE", "HASH"]
+            .map(str::to_string)
+            .to_vec(),
+    ];
+
+    for transfer in &transfers {
+        #[allow(clippy::cast_precision_loss)]
+        table.push(vec![
+            format_time(transfer.finished_at.unwrap_or(transfer.queued_at)),
+            transfers::direction(transfer.direction).to_string(),
+            transfer.peer_name(),
+            transfer.filename.clone(),
+            format_bytes(transfer.size as f64),
+            transfers::state(transfer),
+            transfers::hash_status(transfer.hash_status).to_string(),
+        ]);
+    }
+
+    peers::print_aligned(&table);
+    Ok(())
+}
+
+fn matches_peer(transfer: &Transfer, registry: &PeerRegistry, query: &str) -> bool {
+    let label = transfer
+        .peer
+        .as_ref()
+        .and_then(|id| registry.peers.get(id))
+        .and_then(|known| known.label.as_deref());
+
+    transfer
+        .peer
+        .as_ref()
+        .is_some_and(|id| id.starts_with(query))
+        || transfer.hostname.as_deref() == Some(query)
+        || label == Some(query)
+        || transfer.address.ip().to_canonical().to_string() == query
+}
+
+/// Parse a date as local midnight, or an RFC 3339 time, into a Unix timestamp
+fn parse_time(time: &str) -> Result<u64> {
+    let timestamp = if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        let midnight = date
+            .and_hms_opt(0, 0, 0)
+            .and_then(|midnight| Local.from_local_datetime(&midnight).earliest())
+            .ok_or_else(|| eyre!("{time} has no midnight in the local time zone"))?;
+        midnight.timestamp()
+    } else {
+        DateTime::parse_from_rfc3339(time)
+            .map_err(|_| eyre!("Invalid date {time}, expected YYYY-MM-DD or an RFC 3339 time"))?
+            .timestamp()
+    };
+
+    Ok(u64::try_from(timestamp).unwrap_or(0))
+}
+
+fn format_time(timestamp: u64) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|timestamp| Local.timestamp_opt(timestamp, 0).single())
+        .map_or_else(
+            || "-".to_string(),
+            |time| time.format("%Y-%m-%d %H:%M").to_string(),
+        )
+}
@@ -0,0 +1,8 @@
+pub mod discover;
+pub mod history;
+pub mod offers;
+pub mod pair;
+pub mod peers;
+pub mod send;
+pub mod transfers;
+pub mod wake;
@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use color_eyre::{Result, eyre::eyre};
+
+use crate::{
+    commands::peers::{self, format_bytes},
+    daemon::control::{ControlClient, ControlRequest, ControlResponse},
+    network_discovery::probe,
+    transfers::offers::Offer,
+};
+
+/// Accept a file offered to the daemon, by id prefix or the only one waiting
+pub async fn accept(socket: &Path, offer: Option<&str>) -> Result<()> {
+    let mut client = ControlClient::connect(socket).await?;
+    let offer = pick(&mut client, offer).await?;
+
+    let ControlResponse::Accepted { offer } = client
+        .request(&ControlRequest::Accept { offer: Some(offer) })
+        .await?
+    else {
+        return Err(eyre!("Unexpected response from the daemon"));
+    };
+
+    println!("Accepted {} from {}", offer.filename, sender(&offer));
+    Ok(())
+}
+
+/// Reject a file offered to the daemon, by id prefix or the only one waiting
+pub async fn reject(socket: &Path, offer: Option<&str>, reason: Option<String>) -> Result<()> {
+    let mut client = ControlClient::connect(socket).await?;
+    let offer = pick(&mut client, offer).await?;
+
+    let ControlResponse::Rejected { offer } = client
+        .request(&ControlRequest::Reject {
+            offer: Some(offer),
+            reason,
+        })
+        .await?
+    else {
+        return Err(eyre!("Unexpected response from the daemon"));
+    };
+
+    println!("Rejected {} from {}", offer.filename, sender(&offer));
+    Ok(())
+}
+
+/// The offer to decide on, listing the offers waiting when none was given and there isn't
+/// exactly one
+async fn pick(client: &mut ControlClient, offer: Option<&str>) -> Result<String> {
+    if let Some(offer) = offer {
+        return Ok(offer.to_string());
+    }
+
+    let ControlResponse::Offers { offers } = client.request(&ControlRequest::Offers).await? else {
+        return Err(eyre!("Unexpected response from the daemon"));
+    };
+
+    match offers.as_slice() {
+        [offer] => Ok(offer.id.clone()),
+        [] => Err(eyre!("No offers are waiting")),
+        _ => {
+            print_offers(&offers);
+            Err(eyre!("{} offers are waiting, pick one by id", offers.len()))
+        }
+    }
+}
+
+fn print_offers(offers: &[Offer]) {
+    let mut table = vec![
+        ["ID", "FROM", "FILE", "SIZE", "OFFERED"]
+            .map(str::to_string)
+            .to_vec(),
+    ];
+
+    for offer in offers {
+        #[allow(clippy::cast_precision_loss)]
+        table.push(vec![
+            offer.id.clone(),
+            sender(offer),
+            offer.filename.clone(),
+            format_bytes(offer.size as f64),
+            peers::format_age(probe::seen_ago(offer.received_at)),
+        ]);
+    }
+
+    peers::print_aligned(&table);
+}
+
+fn sender(offer: &Offer) -> String {
+    offer
+        .hostname
+        .clone()
+        .unwrap_or_else(|| offer.from.ip().to_canonical().to_string())
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+
+use color_eyre::{Result, eyre::eyre};
+
+use crate::{
+    daemon::control::{ControlClient, ControlRequest, ControlResponse},
+    websockets::pairing::{self, PAIRING_TIMEOUT},
+};
+
+/// Show a code and wait for another peer to type it in, or pair with the peer showing
+/// `code`
+pub async fn run(socket: &Path, code: Option<&str>, with: Option<String>) -> Result<()> {
+    let mut client = ControlClient::connect(socket).await?;
+
+    let request = code.map_or_else(
+        || {
+            let code = pairing::code();
+            println!("Pairing code: {code}");
+            println!(
+                "Type it in on the other peer with `alacrite pair {code}` within {} minutes",
+                PAIRING_TIMEOUT.as_secs() / 60
+            );
+            ControlRequest::AwaitPairing { code }
+        },
+        |code| ControlRequest::Pair {
+            code: code.to_string(),
+            with,
+        },
+    );
+
+    let ControlResponse::Paired { peer_id, hostname } = client.request(&request).await? else {
+        return Err(eyre!("Unexpected response from the daemon"));
+    };
+
+    match hostname {
+        Some(hostname) => println!("Paired with {hostname} ({peer_id})"),
+        None => println!("Paired with {peer_id}"),
+    }
+    Ok(())
+}
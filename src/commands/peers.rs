@@ -0,0 +1,380 @@
+use std::{collections::HashMap, net::SocketAddr, path::Path, thread, time::Duration};
+
+use color_eyre::{Result, eyre::eyre};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    cli::PeersCommand,
+    daemon::control::{ControlClient, ControlRequest, ControlResponse},
+    network_discovery::{
+        probe::{self, PeerProbe},
+        registry::{PeerRegistry, RegistryEdit},
+        udp_broadcast::{PeerEvent, PeerId, PeerInfo, UdpBroadcastDiscovery},
+    },
+    ssh::key_manager::KeyManager,
+};
+
+/// Add, remove or label a peer, through the daemon listening on `socket` if one is
+/// running, as it would save over the registry otherwise
+pub async fn manage(
+    socket: &Path,
+    registry: &mut PeerRegistry,
+    action: &PeersCommand,
+) -> Result<()> {
+    let edit = match action {
+        PeersCommand::Add { address } => RegistryEdit::AddStaticPeer {
+            address: address.clone(),
+        },
+        PeersCommand::Remove { address } => RegistryEdit::RemoveStaticPeer {
+            address: address.clone(),
+        },
+        PeersCommand::Label { peer, label } => RegistryEdit::Label {
+            peer: peer.clone(),
+            label: label.clone(),
+        },
+    };
+
+    let outcome = if let Some(mut client) = ControlClient::try_connect(socket).await? {
+        let request = ControlRequest::EditPeers { edit };
+        let ControlResponse::Edited { outcome } = client.request(&request).await? else {
+            return Err(eyre!("Unexpected response from the daemon"));
+        };
+        outcome
+    } else {
+        let outcome = registry.apply(edit)?;
+        registry.save()?;
+        outcome
+    };
+
+    info!("{outcome}");
+    Ok(())
+}
+
+/// Scan for peers for `timeout` and list them along with every peer seen before
+pub fn list(
+    mut discovery: UdpBroadcastDiscovery,
+    timeout: Duration,
+    key_manager: &KeyManager,
+    verbose: bool,
+    json: bool,
+) -> Result<()> {
+    let live = discovery.scan(timeout)?;
+    let rows = rows(&live, discovery.registry(), key_manager, true);
+
+    if json {
+        print_json(&rows)
+    } else {
+        print_table(&rows, verbose);
+        Ok(())
+    }
+}
+
+/// List the peers known to a running daemon, having it measure throughput to the online
+/// ones when `bandwidth`
+pub async fn list_from_daemon(
+    mut client: ControlClient,
+    bandwidth: bool,
+    verbose: bool,
+    json: bool,
+) -> Result<()> {
+    let request = ControlRequest::Peers { bandwidth };
+    let ControlResponse::Peers { peers: rows } = client.request(&request).await? else {
+        return Err(eyre!("Unexpected response from the daemon"));
+    };
+
+    if json {
+        print_json(&rows)
+    } else {
+        print_table(&rows, verbose);
+        Ok(())
+    }
+}
+
+/// A peer as listed by `alacrite peers`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerRow {
+    pub label: Option<String>,
+    pub hostname: String,
+    pub id: PeerId,
+    pub address: String,
+    /// When the peer was last seen, as a Unix timestamp
+    pub last_seen: u64,
+    /// Whether the peer answered during this scan
+    pub online: bool,
+    /// Whether we explicitly trust the peer's key
+    pub trusted: bool,
+    /// Whether the peer freshly signed its messages with its trusted key
+    pub verified: bool,
+    /// Seconds the peer's clock is ahead of ours, when too far off to verify it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_skew: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "PeerProbe::is_empty")]
+    pub probe: PeerProbe,
+}
+
+/// Merge peers found online with what the registry remembers about them, adding every
+/// other peer in the registry as offline when `include_offline`
+#[must_use]
+pub fn rows(
+    live: &[PeerInfo],
+    registry: &PeerRegistry,
+    key_manager: &KeyManager,
+    include_offline: bool,
+) -> Vec<PeerRow> {
+    let live: HashMap<&str, &PeerInfo> = live.iter().map(|peer| (peer.id.as_str(), peer)).collect();
+
+    let mut rows: Vec<PeerRow> = registry
+        .peers
+        .iter()
+        .filter(|(id, _)| include_offline || live.contains_key(id.as_str()))
+        .map(|(id, known)| {
+            let row = live.get(id.as_str()).map_or_else(
+                || PeerRow {
+                    label: None,
+                    hostname: known.hostname.clone(),
+                    id: id.clone(),
+                    address: SocketAddr::new(known.ip, known.port).to_string(),
+                    last_seen: known.last_seen,
+                    online: false,
+                    trusted: key_manager.is_peer_trusted(id),
+                    verified: false,
+                    clock_skew: None,
+                    fingerprint: None,
+                    probe: PeerProbe::default(),
+                },
+                |peer| live_row(peer, key_manager),
+            );
+
+            PeerRow {
+                label: known.label.clone(),
+                ..row
+            }
+        })
+        .collect();
+
+    // Peers missing from the registry, in case it couldn't be saved
+    rows.extend(
+        live.values()
+            .filter(|peer| !registry.peers.contains_key(&peer.id))
+            .map(|peer| live_row(peer, key_manager)),
+    );
+
+    rows.sort_by(|a, b| {
+        b.online
+            .cmp(&a.online)
+            .then_with(|| a.hostname.cmp(&b.hostname))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    rows
+}
+
+fn live_row(peer: &PeerInfo, key_manager: &KeyManager) -> PeerRow {
+    PeerRow {
+        label: None,
+        hostname: peer.hostname.clone(),
+        id: peer.id.clone(),
+        address: peer.socket_addr().to_string(),
+        last_seen: peer.last_seen,
+        online: true,
+        trusted: key_manager.is_peer_trusted(&peer.id),
+        verified: peer.verified,
+        clock_skew: peer.clock_skew,
+        fingerprint: peer.fingerprint.clone(),
+        probe: peer.probe.clone(),
+    }
+}
+
+/// Print peers as a table, adding probe results and key fingerprints when `verbose`
+pub fn print_table(rows: &[PeerRow], verbose: bool) {
+    if rows.is_empty() {
+        println!("No peers found");
+        return;
+    }
+
+    let mut table = vec![
+        ["LABEL", "HOSTNAME", "ID", "ADDRESS", "LAST SEEN", "KEY"]
+            .map(str::to_string)
+            .to_vec(),
+    ];
+    if verbose {
+        table[0].extend(["RTT", "BANDWIDTH", "LOST", "FINGERPRINT"].map(str::to_string));
+    }
+
+    for row in rows {
+        let mut cells = vec![
+            row.label.clone().unwrap_or_else(|| "-".to_string()),
+            row.hostname.clone(),
+            row.id.clone(),
+            row.address.clone(),
+            if row.online {
+                format_age(probe::seen_ago(row.last_seen))
+            } else {
+                format!("{} (offline)", format_age(probe::seen_ago(row.last_seen)))
+            },
+            key_status(row),
+        ];
+
+        if verbose {
+            cells.extend([
+                row.probe
+                    .rtt_ms
+                    .map_or_else(|| "-".to_string(), |rtt| format!("{rtt:.1} ms")),
+                row.probe
+                    .bandwidth
+                    .map_or_else(|| "-".to_string(), format_bandwidth),
+                row.probe.pings_lost.to_string(),
+                row.fingerprint.clone().unwrap_or_else(|| "-".to_string()),
+            ]);
+        }
+
+        table.push(cells);
+    }
+
+    print_aligned(&table);
+}
+
+/// How far the peer's key can be relied on: verified by its trusted key, not trusted so
+/// anyone could claim its id, or signed too far from our clock to check
+fn key_status(row: &PeerRow) -> String {
+    match (row.online, row.clock_skew, row.trusted, row.verified) {
+        (true, Some(skew), _, _) => format!("clock off {skew:+}s"),
+        (true, None, _, true) => "verified".to_string(),
+        (true, None, _, false) => "untrusted".to_string(),
+        (false, _, true, _) => "trusted".to_string(),
+        (false, _, false, _) => "-".to_string(),
+    }
+}
+
+/// Print rows of cells with every column padded to its widest cell
+pub fn print_aligned(table: &[Vec<String>]) {
+    let columns = table.first().map_or(0, Vec::len);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            table
+                .iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    for row in table {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Print peers as a JSON array
+pub fn print_json(rows: &[PeerRow]) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(rows)?);
+    Ok(())
+}
+
+/// Print peers joining and leaving until discovery stops, one JSON object per line
+/// when `json`
+pub fn watch(
+    mut discovery: UdpBroadcastDiscovery,
+    registry: &PeerRegistry,
+    json: bool,
+) -> Result<()> {
+    let events = discovery.subscribe();
+    thread::spawn(move || {
+        if let Err(e) = discovery.start_listening() {
+            error!("Discovery stopped: {}", e);
+        }
+    });
+
+    for event in events {
+        print_event(&event, registry, json)?;
+    }
+
+    Ok(())
+}
+
+/// Print the peers a running daemon sees joining and leaving, starting with the ones
+/// online, until it stops
+pub async fn watch_from_daemon(
+    mut client: ControlClient,
+    registry: &PeerRegistry,
+    json: bool,
+) -> Result<()> {
+    client.send(&ControlRequest::WatchPeers).await?;
+
+    loop {
+        let ControlResponse::Peer { event } = client.response().await? else {
+            return Err(eyre!("Unexpected response from the daemon"));
+        };
+        print_event(&event, registry, json)?;
+    }
+}
+
+fn print_event(event: &PeerEvent, registry: &PeerRegistry, json: bool) -> Result<()> {
+    let (sign, peer) = match event {
+        PeerEvent::Joined(peer) => ('+', peer),
+        PeerEvent::Left(peer) => ('-', peer),
+        PeerEvent::Updated(_) => return Ok(()),
+    };
+    if json {
+        println!("{}", serde_json::to_string(event)?);
+        return Ok(());
+    }
+
+    let label = registry
+        .peers
+        .get(&peer.id)
+        .and_then(|known| known.label.as_deref())
+        .unwrap_or("-");
+
+    println!(
+        "{} {}  {}  {}  {}",
+        sign,
+        label,
+        peer.hostname,
+        peer.id,
+        peer.socket_addr()
+    );
+    Ok(())
+}
+
+/// Format a number of seconds as a short age, e.g. "3h ago"
+#[must_use]
+pub fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{seconds}s ago"),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+/// Format bytes per second with a binary unit, e.g. "11.2 MiB/s"
+#[must_use]
+pub fn format_bandwidth(bytes_per_second: f64) -> String {
+    format!("{}/s", format_bytes(bytes_per_second))
+}
+
+/// Format a number of bytes with a binary unit, e.g. "11.2 MiB"
+#[must_use]
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{value:.0} {}", UNITS[unit])
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
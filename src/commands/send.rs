@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{Result, eyre::eyre};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use crate::{
+    commands::{
+        peers::{format_bandwidth, format_bytes},
+        transfers::format_duration,
+    },
+    daemon::control::{ControlClient, ControlRequest, ControlResponse},
+    transfers::{
+        TransferId,
+        tracker::{Transfer, TransferEvent, TransferState},
+    },
+    websockets::handlers::client::{SendOutcome, SentFile},
+};
+
+const PROGRESS_TEMPLATE: &str = "{prefix:30!} [{bar:30}] {percent:>3}% {msg}";
+
+/// Have the daemon send files to a peer, waiting until every file was sent or rejected
+pub async fn run(socket: &Path, to: &str, paths: &[PathBuf], limit: Option<u64>) -> Result<()> {
+    // The daemon may run in another directory
+    let paths = paths
+        .iter()
+        .map(|path| {
+            path.canonicalize()
+                .map_err(|e| eyre!("Can't send {}: {}", path.display(), e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut client = ControlClient::connect(socket).await?;
+    let request = ControlRequest::Send {
+        to: to.to_string(),
+        paths,
+        progress: true,
+        limit,
+    };
+    client.send(&request).await?;
+    let files = wait_until_sent(&mut client).await?;
+
+    let mut unsent = 0;
+    for file in &files {
+        #[allow(clippy::cast_precision_loss)]
+        let size = format_bytes(file.size as f64);
+
+        match &file.outcome {
+            SendOutcome::Sent => println!("Sent {} ({})", file.name, size),
+            SendOutcome::Rejected { reason } => {
+                unsent += 1;
+                println!(
+                    "{} ({}) was rejected: {}",
+                    file.name,
+                    size,
+                    reason.as_deref().unwrap_or("no reason given")
+                );
+            }
+            SendOutcome::Cancelled { by_receiver } => {
+                unsent += 1;
+                let by = if *by_receiver { " by the receiver" } else { "" };
+                println!("{} ({}) was cancelled{}", file.name, size, by);
+            }
+        }
+    }
+
+    if unsent > 0 {
+        return Err(eyre!("{} of {} files weren't sent", unsent, files.len()));
+    }
+
+    Ok(())
+}
+
+/// Draw a progress bar per file until the daemon answers with what was sent
+async fn wait_until_sent(client: &mut ControlClient) -> Result<Vec<SentFile>> {
+    let mut bars = ProgressBars::default();
+
+    loop {
+        match client.response().await? {
+            ControlResponse::Transfer { event } => bars.update(&event),
+            ControlResponse::Sent { files } => return Ok(files),
+            _ => return Err(eyre!("Unexpected response from the daemon")),
+        }
+    }
+}
+
+/// Progress bars of the files being sent, drawn on stderr when it is a terminal
+#[derive(Default)]
+struct ProgressBars {
+    progress: MultiProgress,
+    bars: HashMap<TransferId, ProgressBar>,
+}
+
+impl ProgressBars {
+    fn update(&mut self, event: &TransferEvent) {
+        let transfer = event.transfer();
+        let bar = self
+            .bars
+            .entry(transfer.id.clone())
+            .or_insert_with(|| add_bar(&self.progress, transfer));
+
+        if let TransferEvent::Finished(_) = event {
+            bar.finish_and_clear();
+            return;
+        }
+
+        bar.set_position(transfer.transferred);
+        bar.set_message(status(transfer));
+    }
+}
+
+fn add_bar(progress: &MultiProgress, transfer: &Transfer) -> ProgressBar {
+    let style = ProgressStyle::with_template(PROGRESS_TEMPLATE)
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ");
+
+    progress.add(
+        ProgressBar::new(transfer.size)
+            .with_style(style)
+            .with_prefix(transfer.filename.clone()),
+    )
+}
+
+/// Throughput and time left of a running transfer, or why it isn't running
+fn status(transfer: &Transfer) -> String {
+    match transfer.state {
+        TransferState::Queued => "waiting to be accepted".to_string(),
+        TransferState::Paused => "paused".to_string(),
+        _ => match (transfer.bytes_per_second, transfer.eta_secs) {
+            (Some(speed), Some(eta)) => {
+                format!("{}, {} left", format_bandwidth(speed), format_duration(eta))
+            }
+            _ => String::new(),
+        },
+    }
+}
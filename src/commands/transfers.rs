@@ -0,0 +1,184 @@
+use std::path::Path;
+
+use color_eyre::{Result, eyre::eyre};
+
+use crate::{
+    commands::peers::{self, format_bandwidth, format_bytes},
+    daemon::control::{ControlClient, ControlRequest, ControlResponse},
+    transfers::tracker::{Direction, HashStatus, Transfer, TransferEvent, TransferState},
+};
+
+/// List the daemon's active, queued and recently finished transfers
+pub async fn list(socket: &Path, json: bool) -> Result<()> {
+    let mut client = ControlClient::connect(socket).await?;
+    let ControlResponse::Transfers { transfers } =
+        client.request(&ControlRequest::Transfers).await?
+    else {
+        return Err(eyre!("Unexpected response from the daemon"));
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&transfers)?);
+        return Ok(());
+    }
+
+    if transfers.is_empty() {
+        println!("No transfers yet");
+        return Ok(());
+    }
+
+    let mut table = vec![
+        [
+            "ID", "DIR", "PEER", "FILE", "STATE", "PROGRESS", "SPEED", "ETA", "HASH",
+        ]
+        .map(str::to_string)
+        .to_vec(),
+    ];
+
+    for transfer in &transfers {
+        table.push(vec![
+            transfer.id.clone(),
+            direction(transfer.direction).to_string(),
+            transfer.peer_name(),
+            transfer.filename.clone(),
+            state(transfer),
+            progress(transfer),
+            transfer
+                .bytes_per_second
+                .map_or_else(|| "-".to_string(), format_bandwidth),
+            transfer
+                .eta_secs
+                .map_or_else(|| "-".to_string(), format_duration),
+            hash_status(transfer.hash_status).to_string(),
+        ]);
+    }
+
+    peers::print_aligned(&table);
+    Ok(())
+}
+
+/// Print the daemon's transfers as they are queued, progress and finish, one JSON object
+/// per line when `json`
+pub async fn watch(socket: &Path, json: bool) -> Result<()> {
+    let mut client = ControlClient::connect(socket).await?;
+    client.send(&ControlRequest::WatchTransfers).await?;
+
+    loop {
+        let ControlResponse::Transfer { event } = client.response().await? else {
+            return Err(eyre!("Unexpected response from the daemon"));
+        };
+
+        if json {
+            println!("{}", serde_json::to_string(&event)?);
+            continue;
+        }
+
+        let transfer = event.transfer();
+        let event = match event {
+            TransferEvent::Queued(_) => "queued",
+            TransferEvent::Updated(_) => "updated",
+            TransferEvent::Progress(_) => "progress",
+            TransferEvent::Finished(_) => "finished",
+        };
+        let speed = transfer
+            .bytes_per_second
+            .map_or_else(|| "-".to_string(), format_bandwidth);
+
+        println!(
+            "{:8}  {}  {}  {}  {}  {}  {}",
+            event,
+            direction(transfer.direction),
+            transfer.peer_name(),
+            transfer.filename,
+            state(transfer),
+            progress(transfer),
+            speed
+        );
+    }
+}
+
+/// Pause, resume or cancel a transfer through the daemon
+pub async fn control(socket: &Path, request: &ControlRequest) -> Result<()> {
+    let mut client = ControlClient::connect(socket).await?;
+
+    let (done, transfer) = match client.request(request).await? {
+        ControlResponse::Paused { transfer } => ("Paused", transfer),
+        ControlResponse::Resumed { transfer } => ("Resumed", transfer),
+        ControlResponse::Cancelled { transfer } => ("Cancelled", transfer),
+        _ => return Err(eyre!("Unexpected response from the daemon")),
+    };
+
+    let preposition = match transfer.direction {
+        Direction::Outgoing => "to",
+        Direction::Incoming => "from",
+    };
+    println!(
+        "{} {} {} {}",
+        done,
+        transfer.filename,
+        preposition,
+        transfer.peer_name()
+    );
+    Ok(())
+}
+
+#[must_use]
+pub const fn direction(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Outgoing => "out",
+        Direction::Incoming => "in",
+    }
+}
+
+/// State of a transfer, with the reason it was rejected or failed
+#[must_use]
+pub fn state(transfer: &Transfer) -> String {
+    let state = match transfer.state {
+        TransferState::Queued => "queued",
+        TransferState::Active => "active",
+        TransferState::Paused => "paused",
+        TransferState::Completed => "completed",
+        TransferState::Rejected => "rejected",
+        TransferState::Cancelled => "cancelled",
+        TransferState::Failed => "failed",
+    };
+
+    transfer
+        .error
+        .as_ref()
+        .map_or_else(|| state.to_string(), |error| format!("{state}: {error}"))
+}
+
+#[must_use]
+pub const fn hash_status(status: HashStatus) -> &'static str {
+    match status {
+        HashStatus::Pending => "-",
+        HashStatus::Verified => "verified",
+        HashStatus::Mismatch => "MISMATCH",
+    }
+}
+
+/// Bytes transferred out of the file's size, e.g. "41% of 3.0 MiB"
+#[must_use]
+pub fn progress(transfer: &Transfer) -> String {
+    #[allow(clippy::cast_precision_loss)]
+    let size = format_bytes(transfer.size as f64);
+
+    if transfer.size == 0 || transfer.transferred >= transfer.size {
+        return size;
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    let percent = transfer.transferred as f64 / transfer.size as f64 * 100.0;
+    format!("{percent:.0}% of {size}")
+}
+
+/// Format a number of seconds as a short duration, e.g. "2m 05s"
+#[must_use]
+pub fn format_duration(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
@@ -0,0 +1,25 @@
+use color_eyre::{Result, eyre::eyre};
+use tracing::info;
+
+use crate::network_discovery::{registry::PeerRegistry, wake};
+
+/// Wake a remembered peer with a Wake-on-LAN magic packet
+pub fn run(registry: &PeerRegistry, query: &str) -> Result<()> {
+    let (id, peer) = registry.find(query)?;
+
+    let mac = peer.mac.ok_or_else(|| {
+        eyre!(
+            "MAC address of {} ({}) is unknown, it has to be discovered on a local subnet first",
+            peer.hostname,
+            id
+        )
+    })?;
+
+    let targets = wake::wake(mac, peer.ip, peer.interface.as_deref())?;
+    info!(
+        "Sent magic packet for {} ({}) to {:?}",
+        peer.hostname, mac, targets
+    );
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// Limits on how fast files are sent and received, in bytes per second
+/// Leaving a limit out means unlimited
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Sending to every peer together
+    pub max_upload_rate: Option<u64>,
+    /// Receiving from every peer together
+    pub max_download_rate: Option<u64>,
+    /// Sending to a single peer, `send --limit` overrides it for one send
+    pub max_upload_rate_per_peer: Option<u64>,
+    /// Receiving from a single peer
+    pub max_download_rate_per_peer: Option<u64>,
+}
@@ -1,7 +1,8 @@
 use serde::Deserialize;
 
 use crate::config::{
-    downloads::DownloadsConfig, notifications::NotificationsConfig, sharing::SharingConfig,
+    bandwidth::BandwidthConfig, discovery::DiscoveryConfig, downloads::DownloadsConfig,
+    notifications::NotificationsConfig, sharing::SharingConfig,
 };
 
 #[derive(Debug, Deserialize, Clone, Default)]
@@ -10,4 +11,6 @@ pub struct CoreConfig {
     pub downloads: DownloadsConfig,
     pub sharing: SharingConfig,
     pub notifications: NotificationsConfig,
+    pub discovery: DiscoveryConfig,
+    pub bandwidth: BandwidthConfig,
 }
@@ -0,0 +1,90 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How discovery messages are sent to other peers over IPv4
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    /// Directed broadcast to every subnet
+    #[default]
+    Broadcast,
+    /// A multicast group, for networks that drop broadcast traffic
+    Multicast,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    pub mode: DiscoveryMode,
+    /// Group to send to and listen on in multicast mode
+    pub multicast_group: Ipv4Addr,
+    /// How many routers multicast messages may cross, 1 keeps them on the local subnet
+    pub multicast_ttl: u32,
+    /// Interfaces to run discovery on, by name (e.g. "eth0") or CIDR (e.g. "192.168.1.0/24")
+    /// Every non-loopback interface is used when this is empty
+    pub include_interfaces: Vec<String>,
+    /// Interfaces to never run discovery on, by name or CIDR
+    /// Useful for skipping Docker bridges and VPN tunnels
+    pub exclude_interfaces: Vec<String>,
+    /// Packets accepted per second from a single address, the rest are dropped
+    ///
+    /// Packets forwarded by configured relays are counted against the peer that sent them.
+    pub max_packets_per_second: f64,
+    /// Packets a single address may send in a burst before being rate limited
+    pub packet_burst: f64,
+    /// Discovery responses sent per second to a single address, or peer behind a relay
+    pub max_responses_per_second: f64,
+    /// Most peers tracked at once, peers beyond this are ignored
+    pub max_peers: usize,
+    /// Shared secrets of the groups to discover peers in
+    /// Only peers sharing one of these groups are discovered, or only peers without any
+    /// groups when this is empty
+    pub groups: Vec<String>,
+    /// Peers to probe directly as `host:port`, for networks that block broadcast and mDNS
+    pub static_peers: Vec<String>,
+    /// Forward discovery packets between subnets, as `alacrite relay` does
+    pub relay: bool,
+    /// Relays on other subnets to forward discovery packets to, as `host:port`
+    /// Relayed packets are only taken from these, so peers that aren't relays list the
+    /// relay on their own subnet here to discover peers on the other subnets
+    pub relays: Vec<String>,
+    /// Most relays a discovery packet may pass through
+    pub max_relay_hops: u8,
+    /// Only discover peers on this host, announcing this WebSocket address, as `--local`
+    /// does. Lets several peers run on one machine, each on its own address or port
+    pub local: Option<SocketAddr>,
+    /// Measure throughput to newly discovered peers with a short WebSocket transfer
+    pub probe_bandwidth: bool,
+    /// How long `discover` and `peers` listen for peers before listing them, in seconds
+    pub scan_timeout_secs: u64,
+    /// WebSocket port announced to other peers so they can send us files, set by `daemon`
+    #[serde(skip)]
+    pub ws_port: Option<u16>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            mode: DiscoveryMode::default(),
+            multicast_group: Ipv4Addr::new(239, 255, 70, 70),
+            multicast_ttl: 1,
+            include_interfaces: Vec::default(),
+            exclude_interfaces: Vec::default(),
+            max_packets_per_second: 10.0,
+            packet_burst: 20.0,
+            max_responses_per_second: 1.0,
+            max_peers: 256,
+            groups: Vec::default(),
+            static_peers: Vec::default(),
+            relay: false,
+            relays: Vec::default(),
+            max_relay_hops: 4,
+            local: None,
+            probe_bandwidth: false,
+            scan_timeout_secs: 3,
+            ws_port: None,
+        }
+    }
+}
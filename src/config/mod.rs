@@ -1,4 +1,6 @@
+pub mod bandwidth;
 pub mod core;
+pub mod discovery;
 pub mod downloads;
 pub mod notifications;
 pub mod persistance;
@@ -1,11 +1,14 @@
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
 pub struct SharingConfig {
     /// Maximum number of files in the confirmation queue
     pub max_queue_length: u32,
     /// User needs to confirm the download within this time
     pub confirmation_timeout_seconds: u32,
+    /// Compress chunks of files that aren't compressed already, if both ends allow it
+    pub compression: bool,
 }
 
 impl Default for SharingConfig {
@@ -13,6 +16,7 @@ impl Default for SharingConfig {
         Self {
             max_queue_length: 10,
             confirmation_timeout_seconds: 10,
+            compression: true,
         }
     }
 }
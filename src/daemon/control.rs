@@ -0,0 +1,318 @@
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use color_eyre::{Result, eyre::eyre};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        UnixListener, UnixStream,
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::mpsc,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    commands::peers::PeerRow,
+    daemon::Daemon,
+    network_discovery::{registry::RegistryEdit, udp_broadcast::PeerEvent},
+    transfers::{
+        offers::Offer,
+        tracker::{Transfer, TransferEvent},
+    },
+    websockets::handlers::client::SentFile,
+};
+
+/// Requests the CLI sends to the daemon, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Every online and remembered peer
+    Peers {
+        /// Measure throughput to each online peer first, sending it a few megabytes
+        #[serde(default)]
+        bandwidth: bool,
+    },
+    /// Broadcast a discovery request, answered with the peers heard from within
+    /// `timeout_secs`
+    Discover { timeout_secs: u64 },
+    /// Stream [`ControlResponse::Peer`] events of peers joining and leaving, starting with
+    /// the ones online, until the connection is closed
+    WatchPeers,
+    /// Offer files to a peer, answered once every file was sent or rejected
+    Send {
+        /// Peer by id, id prefix, label or hostname, or a WebSocket address
+        to: String,
+        /// Absolute paths, as the daemon may run in another directory
+        paths: Vec<PathBuf>,
+        /// Stream [`ControlResponse::Transfer`] events of the files until they are sent
+        #[serde(default)]
+        progress: bool,
+        /// Bytes per second to send at most, instead of the per-peer upload limit
+        #[serde(default)]
+        limit: Option<u64>,
+    },
+    /// Offers waiting for a decision
+    Offers,
+    /// Accept an offer by id prefix, or the only one waiting
+    Accept { offer: Option<String> },
+    /// Reject an offer by id prefix, or the only one waiting
+    Reject {
+        offer: Option<String>,
+        reason: Option<String>,
+    },
+    /// Transfers of this session, active and queued ones and the last finished ones
+    Transfers,
+    /// Stream [`ControlResponse::Transfer`] events of every transfer until the connection
+    /// is closed
+    WatchTransfers,
+    /// Pause a transfer by id prefix, or the only unfinished one
+    Pause { transfer: Option<String> },
+    /// Resume a paused transfer by id prefix, or the only unfinished one
+    Resume { transfer: Option<String> },
+    /// Cancel a queued or running transfer by id prefix, or the only unfinished one
+    Cancel { transfer: Option<String> },
+    /// Apply an edit made with `alacrite peers` to the daemon's registry
+    EditPeers { edit: RegistryEdit },
+    /// Wait for a peer to type in `code`, answered once the two trust each other's keys
+    AwaitPairing { code: String },
+    /// Pair with the peer waiting for `code`, answered once the two trust each other's keys
+    Pair {
+        code: String,
+        /// Peer by id, id prefix, label or hostname, or a WebSocket address, every online
+        /// peer is tried if none is given
+        #[serde(default)]
+        with: Option<String>,
+    },
+}
+
+/// Answers to [`ControlRequest`]s, one JSON object per line
+///
+/// Requests that stream events get any number of [`ControlResponse::Transfer`] or
+/// [`ControlResponse::Peer`] before their answer.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Transfer {
+        #[serde(flatten)]
+        event: TransferEvent,
+    },
+    Peer {
+        #[serde(flatten)]
+        event: PeerEvent,
+    },
+    Peers {
+        peers: Vec<PeerRow>,
+    },
+    /// What an edit changed, for the user
+    Edited {
+        outcome: String,
+    },
+    Sent {
+        files: Vec<SentFile>,
+    },
+    Offers {
+        offers: Vec<Offer>,
+    },
+    Accepted {
+        offer: Offer,
+    },
+    Rejected {
+        offer: Offer,
+    },
+    Transfers {
+        transfers: Vec<Transfer>,
+    },
+    Paused {
+        transfer: Transfer,
+    },
+    Resumed {
+        transfer: Transfer,
+    },
+    Cancelled {
+        transfer: Transfer,
+    },
+    Paired {
+        peer_id: String,
+        hostname: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Where the daemon listens when no socket is given, one per port in local mode so
+/// several daemons can run on one host
+#[must_use]
+pub fn default_socket_path(data_dir: &Path, local: Option<SocketAddr>) -> PathBuf {
+    let dir = dirs::runtime_dir().unwrap_or_else(|| data_dir.to_path_buf());
+
+    local.map_or_else(
+        || dir.join("alacrite.sock"),
+        |local| dir.join(format!("alacrite-{}.sock", local.port())),
+    )
+}
+
+/// Answer requests on the control socket until the daemon stops
+pub async fn serve(listener: UnixListener, daemon: Arc<Daemon>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let daemon = daemon.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &daemon).await {
+                warn!("Control connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Bind the socket, replacing one left behind by a daemon that didn't shut down cleanly
+pub async fn bind(path: &Path) -> Result<UnixListener> {
+    if fs::try_exists(path).await? {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(eyre!("A daemon is already listening on {}", path.display()));
+        }
+        fs::remove_file(path).await?;
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| eyre!("Failed to bind control socket {}: {}", path.display(), e))?;
+
+    // Anyone able to connect can send and accept files as us
+    fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    info!("Control socket listening on {}", path.display());
+
+    Ok(listener)
+}
+
+async fn handle_connection(stream: UnixStream, daemon: &Daemon) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                debug!("Control request: {:?}", request);
+                handle_request(daemon, request, &mut write)
+                    .await?
+                    .unwrap_or_else(|e| ControlResponse::Error {
+                        message: e.to_string(),
+                    })
+            }
+            Err(e) => ControlResponse::Error {
+                message: format!("Invalid request: {e}"),
+            },
+        };
+
+        write_line(&mut write, &response).await?;
+    }
+
+    Ok(())
+}
+
+/// Handle a request, writing the events it streams while it runs
+async fn handle_request(
+    daemon: &Daemon,
+    request: ControlRequest,
+    write: &mut OwnedWriteHalf,
+) -> Result<Result<ControlResponse>> {
+    let (events, mut streamed) = mpsc::unbounded_channel();
+    let handling = daemon.handle(request, &events);
+    tokio::pin!(handling);
+
+    let response = loop {
+        tokio::select! {
+            response = &mut handling => break response,
+            Some(event) = streamed.recv() => write_line(write, &event).await?,
+        }
+    };
+
+    while let Ok(event) = streamed.try_recv() {
+        write_line(write, &event).await?;
+    }
+
+    Ok(response)
+}
+
+async fn write_line<T: Serialize + Sync>(write: &mut OwnedWriteHalf, message: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+    Ok(())
+}
+
+/// Connection to a running daemon's control socket
+pub struct ControlClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write: OwnedWriteHalf,
+}
+
+impl ControlClient {
+    /// Connect to the daemon, or `None` if no daemon is listening on `path`
+    pub async fn try_connect(path: &Path) -> Result<Option<Self>> {
+        match UnixStream::connect(path).await {
+            Ok(stream) => {
+                let (read, write) = stream.into_split();
+                Ok(Some(Self {
+                    lines: BufReader::new(read).lines(),
+                    write,
+                }))
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+                Ok(None)
+            }
+            Err(e) => Err(eyre!(
+                "Failed to connect to the daemon at {}: {}",
+                path.display(),
+                e
+            )),
+        }
+    }
+
+    /// Connect to the daemon, failing if it isn't running
+    pub async fn connect(path: &Path) -> Result<Self> {
+        Self::try_connect(path).await?.ok_or_else(|| {
+            eyre!(
+                "No daemon is listening on {}, start one with `alacrite daemon`",
+                path.display()
+            )
+        })
+    }
+
+    /// Send a request and wait for its response, turning error responses into errors
+    pub async fn request(&mut self, request: &ControlRequest) -> Result<ControlResponse> {
+        self.send(request).await?;
+        self.response().await
+    }
+
+    /// Send a request, leaving its responses to [`Self::response`]
+    pub async fn send(&mut self, request: &ControlRequest) -> Result<()> {
+        write_line(&mut self.write, request).await
+    }
+
+    /// Wait for the next response, turning error responses into errors
+    pub async fn response(&mut self) -> Result<ControlResponse> {
+        let line = self
+            .lines
+            .next_line()
+            .await?
+            .ok_or_else(|| eyre!("The daemon closed the connection"))?;
+
+        match serde_json::from_str(&line)? {
+            ControlResponse::Error { message } => Err(eyre!(message)),
+            response => Ok(response),
+        }
+    }
+}
@@ -0,0 +1,594 @@
+pub mod control;
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, mpsc::Sender},
+    thread,
+    time::Duration,
+};
+
+use color_eyre::{Result, eyre::eyre};
+use futures::future;
+use parking_lot::Mutex;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, oneshot,
+    },
+    time::timeout,
+};
+use tracing::{debug, info};
+
+use crate::{
+    commands::peers,
+    config::{core::CoreConfig, downloads::DownloadsConfig},
+    daemon::control::{ControlRequest, ControlResponse},
+    network_discovery::{
+        probe::BANDWIDTH_PROBE_SIZE,
+        registry::{PeerRegistry, RegistryEdit},
+        udp_broadcast::{DiscoveryCommand, PeerEvent, PeerId, PeerInfo, UdpBroadcastDiscovery},
+    },
+    rate_limit::BandwidthLimiter,
+    ssh::key_manager::KeyManager,
+    transfers::{
+        history::TransferLog,
+        offers::{Decision, OfferQueue},
+        tracker::{TransferAction, TransferEvent, TransferTracker},
+        upload,
+    },
+    websockets::{
+        event_loop,
+        handlers::client,
+        hello::Hello,
+        pairing::{self, PAIRING_TIMEOUT},
+        speed_test,
+    },
+};
+
+/// Peer events kept for watchers reading slowly, older ones are skipped
+const PEER_EVENT_CAPACITY: usize = 64;
+
+/// A code shown by `alacrite pair`, waiting for another peer to type it in
+pub struct PendingPairing {
+    pub code: String,
+    /// Told the id of the peer paired with, or why pairing with it failed
+    pub paired: oneshot::Sender<Result<PeerId>>,
+}
+
+/// State shared by the daemon's WebSocket connections and control socket
+pub struct Daemon {
+    /// Incoming files waiting for `alacrite accept` or `alacrite reject`
+    pub offers: OfferQueue,
+    pub downloads: DownloadsConfig,
+    /// How long an offer waits for a decision before it is rejected
+    pub confirmation_timeout: Duration,
+    /// Files being sent and received, and the last ones that were
+    pub transfers: TransferTracker,
+    pub upload_limiter: BandwidthLimiter,
+    pub download_limiter: BandwidthLimiter,
+    /// Whether chunks are compressed when both ends allow it
+    pub compression: bool,
+    /// Id discovery announces this peer with
+    id: PeerId,
+    /// Peers currently online, kept up to date by discovery
+    peers: Mutex<HashMap<PeerId, PeerInfo>>,
+    /// Discovery's events, passed on to `alacrite peers --watch`
+    peer_events: broadcast::Sender<PeerEvent>,
+    /// Requests for discovery, which owns the registry
+    discovery: Sender<DiscoveryCommand>,
+    /// Code of the latest `alacrite pair` still waiting for a peer
+    pairing: Mutex<Option<PendingPairing>>,
+    data_dir: PathBuf,
+    key_manager: Arc<KeyManager>,
+}
+
+impl Daemon {
+    /// What this daemon says hello with on WebSocket connections
+    #[must_use]
+    pub fn hello(&self) -> Hello {
+        Hello::new(Some(self.id.clone()), self.compression)
+    }
+
+    /// Id discovery announces this peer with
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Take the pairing waiting for a code starting with `nameplate`, so each code is only
+    /// tried once
+    pub fn take_pairing(&self, nameplate: &str) -> Option<PendingPairing> {
+        self.pairing
+            .lock()
+            .take_if(|pending| pairing::nameplate(&pending.code) == nameplate)
+    }
+
+    /// The online peer at `ip`, if any
+    #[must_use]
+    pub fn peer_at(&self, ip: IpAddr) -> Option<PeerInfo> {
+        let ip = ip.to_canonical();
+        self.peers
+            .lock()
+            .values()
+            .find(|peer| peer.ip == ip)
+            .cloned()
+    }
+
+    /// Handle a request from the control socket, passing on the events it streams to
+    /// `events`
+    pub async fn handle(
+        &self,
+        request: ControlRequest,
+        events: &mpsc::UnboundedSender<ControlResponse>,
+    ) -> Result<ControlResponse> {
+        match request {
+            ControlRequest::Peers { bandwidth } => self.list_peers(bandwidth).await,
+            ControlRequest::Discover { timeout_secs } => {
+                self.discover(Duration::from_secs(timeout_secs)).await
+            }
+            ControlRequest::WatchPeers => self.watch_peers(events).await,
+            ControlRequest::EditPeers { edit } => self.edit_peers(edit).await,
+            ControlRequest::Send {
+                to,
+                paths,
+                progress,
+                limit,
+            } => {
+                let (addr, peer) = self.transfer_address(&to)?;
+                let uploads = upload::collect(&paths)?;
+                let limit = self.upload_limiter.limit(addr.ip(), limit);
+                let hello = self.hello();
+
+                // Subscribed before the files are queued, to not miss their first events
+                let subscription = self.transfers.subscribe();
+                let sending = client::send_files(
+                    addr,
+                    peer.as_ref(),
+                    &uploads,
+                    &self.transfers,
+                    &limit,
+                    &hello,
+                    &self.key_manager,
+                );
+                let files = if progress {
+                    let ids = uploads.iter().map(|upload| upload.id.as_str()).collect();
+                    forward_events(sending, subscription, &ids, events).await?
+                } else {
+                    sending.await?
+                };
+
+                Ok(ControlResponse::Sent { files })
+            }
+            ControlRequest::Offers => Ok(ControlResponse::Offers {
+                offers: self.offers.list(),
+            }),
+            ControlRequest::Accept { offer } => {
+                let offer = self.offers.decide(offer.as_deref(), Decision::Accept)?;
+                info!("Accepted {} ({})", offer.filename, offer.id);
+                Ok(ControlResponse::Accepted { offer })
+            }
+            ControlRequest::Reject { offer, reason } => {
+                let offer = self
+                    .offers
+                    .decide(offer.as_deref(), Decision::Reject { reason })?;
+                info!("Rejected {} ({})", offer.filename, offer.id);
+                Ok(ControlResponse::Rejected { offer })
+            }
+            ControlRequest::Transfers => Ok(ControlResponse::Transfers {
+                transfers: self.transfers.list(),
+            }),
+            ControlRequest::WatchTransfers => {
+                let mut subscription = self.transfers.subscribe();
+                loop {
+                    match subscription.recv().await {
+                        Ok(event) => {
+                            if events.send(ControlResponse::Transfer { event }).is_err() {
+                                break;
+                            }
+                        }
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                Err(eyre!("Stopped watching transfers"))
+            }
+            ControlRequest::Pause { transfer } => {
+                let transfer = self
+                    .transfers
+                    .control(transfer.as_deref(), TransferAction::Pause)?;
+                Ok(ControlResponse::Paused { transfer })
+            }
+            ControlRequest::Resume { transfer } => {
+                let transfer = self
+                    .transfers
+                    .control(transfer.as_deref(), TransferAction::Resume)?;
+                Ok(ControlResponse::Resumed { transfer })
+            }
+            ControlRequest::Cancel { transfer } => {
+                let transfer = self
+                    .transfers
+                    .control(transfer.as_deref(), TransferAction::Cancel)?;
+                info!("Cancelled {} ({})", transfer.filename, transfer.id);
+                Ok(ControlResponse::Cancelled { transfer })
+            }
+            ControlRequest::AwaitPairing { code } => self.await_pairing(&code).await,
+            ControlRequest::Pair { code, with } => self.pair(&code, with.as_deref()).await,
+        }
+    }
+
+    /// Wait for a peer to type in `code`, replacing the code of an earlier `alacrite pair`
+    async fn await_pairing(&self, code: &str) -> Result<ControlResponse> {
+        let code = pairing::normalize(code)?;
+        let (paired, outcome) = oneshot::channel();
+        let pending = PendingPairing {
+            code: code.clone(),
+            paired,
+        };
+        let replaced = self.pairing.lock().replace(pending);
+        if let Some(replaced) = replaced {
+            let _ = replaced
+                .paired
+                .send(Err(eyre!("Another `alacrite pair` replaced this code")));
+        }
+        info!("Waiting for a peer to pair with");
+
+        let outcome = timeout(PAIRING_TIMEOUT, outcome).await;
+        self.pairing.lock().take_if(|pending| pending.code == code);
+        let peer_id = outcome
+            .map_err(|_| {
+                eyre!(
+                    "No peer typed the code in within {} minutes",
+                    PAIRING_TIMEOUT.as_secs() / 60
+                )
+            })?
+            .map_err(|_| eyre!("The peer left before pairing finished"))??;
+
+        Ok(self.paired(peer_id))
+    }
+
+    /// Pair with the peer waiting for `code`, trying every online peer running
+    /// `alacrite daemon` unless `with` picks one
+    async fn pair(&self, code: &str, with: Option<&str>) -> Result<ControlResponse> {
+        let code = pairing::normalize(code)?;
+        let addrs = match with {
+            Some(query) => vec![self.transfer_address(query)?.0],
+            None => self.daemon_addresses(),
+        };
+        if addrs.is_empty() {
+            return Err(eyre!(
+                "No online peer runs `alacrite daemon`, pick one with --with"
+            ));
+        }
+
+        let hello = self.hello();
+        let attempts = addrs
+            .iter()
+            .map(|addr| Box::pin(client::pair(*addr, &code, &hello, &self.key_manager)));
+        let (peer_id, _) = future::select_ok(attempts).await?;
+
+        Ok(self.paired(peer_id))
+    }
+
+    fn paired(&self, peer_id: PeerId) -> ControlResponse {
+        let hostname = self
+            .peers
+            .lock()
+            .get(&peer_id)
+            .map(|peer| peer.hostname.clone());
+        info!(
+            "Paired with {} ({})",
+            hostname.as_deref().unwrap_or("a peer"),
+            peer_id
+        );
+
+        ControlResponse::Paired { peer_id, hostname }
+    }
+
+    /// WebSocket addresses of the online peers running `alacrite daemon`
+    fn daemon_addresses(&self) -> Vec<SocketAddr> {
+        self.peers
+            .lock()
+            .values()
+            .filter_map(|peer| {
+                let mut addr = peer.socket_addr();
+                addr.set_port(peer.ws_port?);
+                Some(addr)
+            })
+            .collect()
+    }
+
+    /// Pass on the online peers as having joined, then peers joining and leaving until the
+    /// watcher goes away
+    async fn watch_peers(
+        &self,
+        events: &mpsc::UnboundedSender<ControlResponse>,
+    ) -> Result<ControlResponse> {
+        // Subscribed before listing the online peers, to not miss one joining
+        let mut subscription = self.peer_events.subscribe();
+        let online: Vec<PeerInfo> = self.peers.lock().values().cloned().collect();
+        for peer in online {
+            let event = PeerEvent::Joined(peer);
+            if events.send(ControlResponse::Peer { event }).is_err() {
+                return Err(eyre!("Stopped watching peers"));
+            }
+        }
+
+        loop {
+            match subscription.recv().await {
+                Ok(PeerEvent::Updated(_)) | Err(RecvError::Lagged(_)) => {}
+                Ok(event) => {
+                    if events.send(ControlResponse::Peer { event }).is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+        Err(eyre!("Stopped watching peers"))
+    }
+
+    /// Every online and remembered peer, measuring throughput to the online ones first
+    /// when `bandwidth`
+    async fn list_peers(&self, bandwidth: bool) -> Result<ControlResponse> {
+        let registry = PeerRegistry::load(&self.data_dir)?;
+        let mut live: Vec<PeerInfo> = self.peers.lock().values().cloned().collect();
+        if bandwidth {
+            self.measure_bandwidth(&mut live).await;
+        }
+
+        Ok(ControlResponse::Peers {
+            peers: peers::rows(&live, &registry, &self.key_manager, true),
+        })
+    }
+
+    /// Have discovery ask every peer to respond, listing the ones heard from within
+    /// `timeout`
+    async fn discover(&self, timeout: Duration) -> Result<ControlResponse> {
+        // Subscribed before asking, to not miss a quick response
+        let mut subscription = self.peer_events.subscribe();
+        self.discovery
+            .send(DiscoveryCommand::Discover)
+            .map_err(|_| eyre!("Discovery has stopped"))?;
+
+        let mut heard = HashMap::new();
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(event) = tokio::time::timeout_at(deadline, subscription.recv()).await {
+            match event {
+                Ok(PeerEvent::Joined(peer) | PeerEvent::Updated(peer)) => {
+                    heard.insert(peer.id.clone(), peer);
+                }
+                Ok(PeerEvent::Left(peer)) => {
+                    heard.remove(&peer.id);
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Err(eyre!("Discovery has stopped")),
+            }
+        }
+
+        let live: Vec<PeerInfo> = heard.into_values().collect();
+        let registry = PeerRegistry::load(&self.data_dir)?;
+        Ok(ControlResponse::Peers {
+            peers: peers::rows(&live, &registry, &self.key_manager, false),
+        })
+    }
+
+    /// Have discovery, which owns the registry, apply an edit made with `alacrite peers`
+    async fn edit_peers(&self, edit: RegistryEdit) -> Result<ControlResponse> {
+        let (done, outcome) = oneshot::channel();
+        self.discovery
+            .send(DiscoveryCommand::EditRegistry { edit, done })
+            .map_err(|_| eyre!("Discovery has stopped"))?;
+        let outcome = outcome
+            .await
+            .map_err(|_| eyre!("Discovery has stopped"))??;
+
+        Ok(ControlResponse::Edited { outcome })
+    }
+
+    /// Measure throughput to each of `peers` announcing a WebSocket server, all at once
+    async fn measure_bandwidth(&self, peers: &mut [PeerInfo]) {
+        let hello = self.hello();
+        let tests = peers.iter_mut().filter_map(|peer| {
+            let mut addr = peer.socket_addr();
+            addr.set_port(peer.ws_port?);
+            let (hello, key_manager) = (&hello, &self.key_manager);
+
+            Some(async move {
+                let measured = speed_test::measure_throughput(
+                    addr,
+                    &peer.id,
+                    BANDWIDTH_PROBE_SIZE,
+                    hello,
+                    key_manager,
+                );
+                match measured.await {
+                    Ok(bandwidth) => peer.probe.bandwidth = Some(bandwidth),
+                    Err(e) => debug!("Throughput test to {} failed: {}", peer.id, e),
+                }
+            })
+        });
+        future::join_all(tests).await;
+    }
+
+    /// WebSocket address to send files to, for a peer by id, id prefix, label or hostname,
+    /// or given directly as `ip:port`, along with the peer discovery saw announcing it, which
+    /// is the one expected to answer
+    fn transfer_address(&self, query: &str) -> Result<(SocketAddr, Option<PeerInfo>)> {
+        if let Ok(addr) = query.parse::<SocketAddr>() {
+            // Several peers may share an address in local mode, told apart by their ports
+            let ip = addr.ip().to_canonical();
+            let peer = self
+                .peers
+                .lock()
+                .values()
+                .find(|peer| peer.ip == ip && peer.ws_port == Some(addr.port()))
+                .cloned();
+            return Ok((addr, peer));
+        }
+
+        let peer = self.find_peer(query)?;
+        let ws_port = peer.ws_port.ok_or_else(|| {
+            eyre!(
+                "{} ({}) doesn't accept files, it isn't running `alacrite daemon`",
+                peer.hostname,
+                peer.id
+            )
+        })?;
+
+        let mut addr = peer.socket_addr();
+        addr.set_port(ws_port);
+        Ok((addr, Some(peer)))
+    }
+
+    /// Find an online peer by id, unique id prefix, label, or hostname
+    fn find_peer(&self, query: &str) -> Result<PeerInfo> {
+        let registry = PeerRegistry::load(&self.data_dir)?;
+        let label = |id: &str| {
+            registry
+                .peers
+                .get(id)
+                .and_then(|known| known.label.as_deref())
+        };
+
+        let peers: Vec<PeerInfo> = self.peers.lock().values().cloned().collect();
+        if let Some(peer) = peers.iter().find(|peer| peer.id == query) {
+            return Ok(peer.clone());
+        }
+
+        let matches: Vec<&PeerInfo> = peers
+            .iter()
+            .filter(|peer| {
+                peer.id.starts_with(query)
+                    || peer.hostname == query
+                    || label(&peer.id) == Some(query)
+            })
+            .collect();
+
+        match matches.as_slice() {
+            [peer] => Ok((*peer).clone()),
+            [] => Err(eyre!("No online peer matches {query}")),
+            _ => Err(eyre!(
+                "{} peers match {query}, use a longer id to pick one",
+                matches.len()
+            )),
+        }
+    }
+}
+
+/// Run `future`, passing on the events of the transfers in `ids` until it is done
+async fn forward_events<T>(
+    future: impl Future<Output = T>,
+    mut subscription: broadcast::Receiver<TransferEvent>,
+    ids: &HashSet<&str>,
+    events: &mpsc::UnboundedSender<ControlResponse>,
+) -> T {
+    tokio::pin!(future);
+
+    loop {
+        tokio::select! {
+            output = &mut future => {
+                while let Ok(event) = subscription.try_recv() {
+                    if ids.contains(event.transfer().id.as_str()) {
+                        let _ = events.send(ControlResponse::Transfer { event });
+                    }
+                }
+                return output;
+            }
+            event = subscription.recv() => match event {
+                Ok(event) if ids.contains(event.transfer().id.as_str()) => {
+                    let _ = events.send(ControlResponse::Transfer { event });
+                }
+                // Progress events are only dropped when the client reads too slowly
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return future.await,
+            },
+        }
+    }
+}
+
+/// Run discovery, the WebSocket server on the announced `ws_port` and the control socket
+/// until stopped, or until one of them fails
+pub async fn run(
+    udp_port: u16,
+    hostname: String,
+    config: &CoreConfig,
+    socket_path: &Path,
+    data_dir: PathBuf,
+    key_manager: Arc<KeyManager>,
+    registry: PeerRegistry,
+) -> Result<()> {
+    let ws_port = config
+        .discovery
+        .ws_port
+        .ok_or_else(|| eyre!("No WebSocket port to receive files on"))?;
+    let mut discovery = UdpBroadcastDiscovery::new(
+        udp_port,
+        hostname,
+        &config.discovery,
+        key_manager.clone(),
+        registry,
+    )?;
+    // Bound before discovery announces the port, so peers never find nothing listening
+    let server = event_loop::bind_server(ws_port).await?;
+    let control = control::bind(socket_path).await?;
+
+    let daemon = Arc::new(Daemon {
+        offers: OfferQueue::new(config.sharing.max_queue_length as usize),
+        downloads: config.downloads.clone(),
+        confirmation_timeout: Duration::from_secs(
+            config.sharing.confirmation_timeout_seconds.into(),
+        ),
+        transfers: TransferTracker::new(TransferLog::new(&data_dir)),
+        upload_limiter: BandwidthLimiter::new(
+            config.bandwidth.max_upload_rate,
+            config.bandwidth.max_upload_rate_per_peer,
+        ),
+        download_limiter: BandwidthLimiter::new(
+            config.bandwidth.max_download_rate,
+            config.bandwidth.max_download_rate_per_peer,
+        ),
+        compression: config.sharing.compression,
+        id: discovery.local_id().to_string(),
+        peers: Mutex::new(HashMap::new()),
+        peer_events: broadcast::channel(PEER_EVENT_CAPACITY).0,
+        discovery: discovery.commands(),
+        pairing: Mutex::new(None),
+        data_dir,
+        key_manager,
+    });
+
+    let events = discovery.subscribe();
+    let (stopped, discovery_stopped) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = stopped.send(discovery.start_listening());
+    });
+
+    let tracker = daemon.clone();
+    thread::spawn(move || {
+        for event in events {
+            match &event {
+                PeerEvent::Joined(peer) | PeerEvent::Updated(peer) => {
+                    tracker.peers.lock().insert(peer.id.clone(), peer.clone());
+                }
+                PeerEvent::Left(peer) => {
+                    tracker.peers.lock().remove(&peer.id);
+                }
+            }
+            // Nobody may be watching
+            let _ = tracker.peer_events.send(event);
+        }
+    });
+
+    // Without discovery peers can't find us, and we can't find them to send to
+    let key_manager = daemon.key_manager.clone();
+    tokio::select! {
+        result = discovery_stopped => match result {
+            Ok(Err(e)) => Err(eyre!("Discovery stopped: {e}")),
+            _ => Err(eyre!("Discovery stopped")),
+        },
+        result = event_loop::serve(server, key_manager, Some(daemon.clone())) => result,
+        result = control::serve(control, daemon) => result,
+    }
+}
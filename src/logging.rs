@@ -7,7 +7,8 @@ pub fn init_logging(log_level: &str) -> Result<()> {
 
     tracing_subscriber::registry()
         .with(env_filter)
-        .with(fmt::layer().with_target(true))
+        // Stdout is left to command output, so it can be piped into scripts
+        .with(fmt::layer().with_target(true).with_writer(std::io::stderr))
         .init();
 
     Ok(())
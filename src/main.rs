@@ -7,29 +7,40 @@
 )]
 
 pub mod cli;
+pub mod commands;
 pub mod config;
+pub mod daemon;
 pub mod logging;
 pub mod network_discovery;
+pub mod rate_limit;
 pub mod ssh;
+pub mod transfers;
 pub mod websockets;
 
+use std::{path::Path, sync::Arc, time::Duration};
+
 use clap::Parser;
 use color_eyre::{Result, eyre::eyre};
 use gethostname::gethostname;
-use tracing::{info, warn};
+use tracing::error;
 
 use crate::{
-    cli::Args,
-    config::core::CoreConfig,
+    cli::{Args, Command},
+    config::{core::CoreConfig, persistance::load_config},
+    daemon::control::{self, ControlClient, ControlRequest},
     logging::init_logging,
-    network_discovery::udp_broadcast,
+    network_discovery::{
+        registry::PeerRegistry,
+        udp_broadcast::{self, UdpBroadcastDiscovery},
+    },
+    ssh::key_manager::KeyManager,
+    websockets::event_loop::host_server,
 };
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
-
-    let _config = CoreConfig::default();
+    let mut config = configure(&args)?;
 
     init_logging(&args.log_level).map_err(|e| eyre!("Failed to initialize logging: {}", e))?;
 
@@ -38,17 +49,256 @@ async fn main() -> Result<()> {
         .into_string()
         .unwrap_or_else(|_| "i-have-no-name".to_string());
 
-    // let key_dir = dirs::data_dir()
-    //     .ok_or_else(|| eyre!("Failed to get data directory"))?
-    //     .join("alacrite");
-    // let key_manager = KeyManager::new(&key_dir)?;
-    // let public_key = key_manager.get_public_key_openssh()?;
+    let data_dir = dirs::data_dir()
+        .ok_or_else(|| eyre!("Failed to get data directory"))?
+        .join("alacrite");
+
+    let mut registry = PeerRegistry::load(&data_dir)?;
+
+    if let Some(command) = &args.command
+        && let Some(result) = run_offline(command, &data_dir, &registry)
+    {
+        return result;
+    }
+
+    let socket = args
+        .socket
+        .clone()
+        .unwrap_or_else(|| control::default_socket_path(&data_dir, config.discovery.local));
+    if let Some(command) = &args.command
+        && let Some(result) = run_client(command, &socket, &mut registry, &config).await
+    {
+        return result;
+    }
+
+    let mut key_manager = KeyManager::new(&data_dir)?;
+    key_manager.load_trusted_keys()?;
+    let key_manager = Arc::new(key_manager);
+
+    if matches!(args.command, Some(Command::Daemon)) {
+        let (port, config) = (args.udp_port, &config);
+        return daemon::run(
+            port,
+            hostname,
+            config,
+            &socket,
+            data_dir,
+            key_manager,
+            registry,
+        )
+        .await;
+    }
+
+    // Local peers announce their WebSocket server, so other local peers can probe it
+    if let Some(local) = config.discovery.local {
+        let key_manager = key_manager.clone();
+        tokio::spawn(async move {
+            if let Err(e) = host_server(local.port(), key_manager, None).await {
+                error!("WebSocket server stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(Command::Peers {
+        verbose,
+        bandwidth,
+        json,
+        watch,
+        ..
+    }) = &args.command
+    {
+        config.discovery.probe_bandwidth |= *bandwidth;
+
+        let discovery = UdpBroadcastDiscovery::new(
+            args.udp_port,
+            hostname,
+            &config.discovery,
+            key_manager.clone(),
+            registry,
+        )?;
+
+        if *watch {
+            let registry = PeerRegistry::load(&data_dir)?;
+            return commands::peers::watch(discovery, &registry, *json);
+        }
+
+        let timeout = Duration::from_secs(config.discovery.scan_timeout_secs);
+        return commands::peers::list(discovery, timeout, &key_manager, *verbose, *json);
+    }
 
-    udp_broadcast::run_udp_discovery(args.udp_port, hostname)?;
+    if let Some(Command::Discover {
+        verbose,
+        timeout,
+        json,
+    }) = &args.command
+    {
+        let timeout = Duration::from_secs(timeout.unwrap_or(config.discovery.scan_timeout_secs));
+        let discovery = UdpBroadcastDiscovery::new(
+            args.udp_port,
+            hostname,
+            &config.discovery,
+            key_manager.clone(),
+            registry,
+        )?;
+
+        return commands::discover::run(discovery, timeout, &key_manager, *verbose, *json);
+    }
+
+    udp_broadcast::run_udp_discovery(
+        args.udp_port,
+        hostname,
+        &config.discovery,
+        key_manager,
+        registry,
+    )?;
 
     Ok(())
 }
 
+/// Load the config file and apply the options overriding it
+fn configure(args: &Args) -> Result<CoreConfig> {
+    let mut config = match &args.config {
+        Some(path) => load_config(path)?,
+        None => CoreConfig::default(),
+    };
+
+    if let Some(mode) = args.discovery_mode {
+        config.discovery.mode = mode;
+    }
+    if let Some(local) = args.local {
+        config.discovery.local = Some(local);
+    }
+    if let Some(download_dir) = &args.download_dir {
+        config.downloads.primary.directory.clone_from(download_dir);
+    }
+    config.discovery.groups.extend(args.groups.iter().cloned());
+
+    match &args.command {
+        Some(Command::Relay { relays }) => {
+            config.discovery.relay = true;
+            config.discovery.relays.extend(relays.iter().cloned());
+        }
+        Some(Command::Daemon) => {
+            let ws_port = config
+                .discovery
+                .local
+                .map_or(args.ws_port, |local| local.port());
+            config.discovery.ws_port = Some(ws_port);
+        }
+        _ => {}
+    }
+
+    Ok(config)
+}
+
+/// Run the commands that only need the data directory, `None` for every other command
+fn run_offline(command: &Command, data_dir: &Path, registry: &PeerRegistry) -> Option<Result<()>> {
+    match command {
+        Command::Wake { peer } => Some(commands::wake::run(registry, peer)),
+        Command::History {
+            peer,
+            direction,
+            since,
+            until,
+            limit,
+            json,
+        } => {
+            let filter = commands::history::HistoryFilter {
+                peer: peer.as_deref(),
+                direction: *direction,
+                since: since.as_deref(),
+                until: until.as_deref(),
+                limit: *limit,
+            };
+            Some(commands::history::run(data_dir, registry, &filter, *json))
+        }
+        _ => None,
+    }
+}
+
+/// Run the commands that only talk to a running daemon, `None` for every other command
+///
+/// `peers` asks the daemon when one is running, and scans for peers itself otherwise.
+async fn run_client(
+    command: &Command,
+    socket: &Path,
+    registry: &mut PeerRegistry,
+    config: &CoreConfig,
+) -> Option<Result<()>> {
+    match command {
+        Command::Send { to, paths, limit } => {
+            Some(commands::send::run(socket, to, paths, *limit).await)
+        }
+        Command::Accept { offer } => Some(commands::offers::accept(socket, offer.as_deref()).await),
+        Command::Reject { offer, reason } => {
+            Some(commands::offers::reject(socket, offer.as_deref(), reason.clone()).await)
+        }
+        Command::Transfers { json, watch: false } => {
+            Some(commands::transfers::list(socket, *json).await)
+        }
+        Command::Transfers { json, watch: true } => {
+            Some(commands::transfers::watch(socket, *json).await)
+        }
+        Command::Pause { transfer } => {
+            let request = ControlRequest::Pause {
+                transfer: transfer.clone(),
+            };
+            Some(commands::transfers::control(socket, &request).await)
+        }
+        Command::Resume { transfer } => {
+            let request = ControlRequest::Resume {
+                transfer: transfer.clone(),
+            };
+            Some(commands::transfers::control(socket, &request).await)
+        }
+        Command::Cancel { transfer } => {
+            let request = ControlRequest::Cancel {
+                transfer: transfer.clone(),
+            };
+            Some(commands::transfers::control(socket, &request).await)
+        }
+        Command::Pair { code, with } => {
+            Some(commands::pair::run(socket, code.as_deref(), with.clone()).await)
+        }
+        // Edits go through a running daemon, so it doesn't save over them
+        Command::Peers {
+            action: Some(action),
+            ..
+        } => Some(commands::peers::manage(socket, registry, action).await),
+        // Run through the daemon when one is running, as it holds the discovery port
+        Command::Peers {
+            verbose,
+            json,
+            bandwidth,
+            watch,
+            action: None,
+        } => match ControlClient::try_connect(socket).await {
+            Ok(Some(client)) if *watch => {
+                Some(commands::peers::watch_from_daemon(client, registry, *json).await)
+            }
+            Ok(Some(client)) => {
+                Some(commands::peers::list_from_daemon(client, *bandwidth, *verbose, *json).await)
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        },
+        Command::Discover {
+            verbose,
+            timeout,
+            json,
+        } => match ControlClient::try_connect(socket).await {
+            Ok(Some(client)) => {
+                let timeout =
+                    Duration::from_secs(timeout.unwrap_or(config.discovery.scan_timeout_secs));
+                Some(commands::discover::from_daemon(client, timeout, *verbose, *json).await)
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        },
+        _ => None,
+    }
+}
+
 // if let Some(command) = args.command {
 //     match command {
 //         Command::Peers { verbose: _ } => {
@@ -59,7 +309,7 @@ async fn main() -> Result<()> {
 //         }
 //         Command::Discover { verbose: _ } => {
 //             info!("Starting UDP broadcast discovery...");
-//             udp_broadcast::run_udp_discovery(args.udp_port, hostname)?;
+//             udp_broadcast::run_udp_discovery(args.udp_port, hostname, &config.discovery)?;
 //         }
 //     }
 
@@ -3,10 +3,19 @@ use std::sync::Arc;
 use color_eyre::{Result, eyre::Context};
 use tracing::{error, info};
 
-use crate::network_discovery::mdns::{DiscoveredServices, NetworkDiscovery};
+use crate::network_discovery::{
+    groups::GroupKey,
+    mdns::{DiscoveredServices, NetworkDiscovery},
+};
 
-pub fn start_network_discovery(port: u16) -> Result<()> {
-    let service = NetworkDiscovery::new(port).context("Failed to create network discovery")?;
+/// Start mDNS discovery, only seeing services in the groups with the given shared secrets
+pub fn start_network_discovery(port: u16, group_secrets: &[String]) -> Result<()> {
+    let groups = group_secrets
+        .iter()
+        .map(|secret| GroupKey::new(secret))
+        .collect::<Result<_>>()?;
+    let service =
+        NetworkDiscovery::new(port, groups).context("Failed to create network discovery")?;
     info!("Network discovery service created on port {}", port);
 
     let discovered_services: Arc<DiscoveredServices> = Arc::default();
@@ -0,0 +1,96 @@
+use std::fmt::Write;
+
+use color_eyre::Result;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Proof of membership of a discovery group, only valid along with the salt it was made for
+pub type GroupTag = String;
+
+/// Domain separation, so group tags can't be matched against MACs used elsewhere
+const GROUP_TAG_CONTEXT: &[u8] = b"alacrite-discovery-group:";
+/// Bytes of the MAC kept for the tag, enough that tags can't be guessed
+const GROUP_TAG_LEN: usize = 16;
+
+/// Key of a discovery group, derived from the group's shared secret
+#[derive(Clone)]
+pub struct GroupKey(Hmac<Sha256>);
+
+impl GroupKey {
+    pub fn new(secret: &str) -> Result<Self> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+        mac.update(GROUP_TAG_CONTEXT);
+        Ok(Self(mac))
+    }
+
+    /// Tag proving membership of the group to peers that know its secret
+    ///
+    /// The secret never goes on the wire, and with a fresh `salt` for every message the
+    /// tags of one group can't be linked to each other, copied into another message, or
+    /// passed off as those of another group.
+    #[must_use]
+    pub fn tag(&self, salt: &[u8]) -> GroupTag {
+        let mac = self.0.clone().chain_update(salt).finalize().into_bytes();
+
+        mac[..GROUP_TAG_LEN].iter().fold(
+            String::with_capacity(GROUP_TAG_LEN * 2),
+            |mut tag, byte| {
+                let _ = write!(tag, "{byte:02x}");
+                tag
+            },
+        )
+    }
+}
+
+/// Tags for every group in `keys`, to announce along with `salt`
+#[must_use]
+pub fn tags(keys: &[GroupKey], salt: &[u8]) -> Vec<GroupTag> {
+    keys.iter().map(|key| key.tag(salt)).collect()
+}
+
+/// Whether peers in `ours` groups should see a peer that sent `theirs` tags with `salt`
+///
+/// Peers outside of any group only see each other, and grouped peers see anyone they
+/// share at least one group with.
+#[must_use]
+pub fn shares_group(ours: &[GroupKey], theirs: &[GroupTag], salt: &[u8]) -> bool {
+    if ours.is_empty() || theirs.is_empty() {
+        return ours.is_empty() && theirs.is_empty();
+    }
+
+    ours.iter().any(|key| theirs.contains(&key.tag(salt)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(secret: &str) -> GroupKey {
+        GroupKey::new(secret).unwrap()
+    }
+
+    #[test]
+    fn members_recognize_each_others_tags() {
+        let theirs = tags(&[key("office"), key("lab")], b"salt");
+
+        assert!(shares_group(&[key("lab")], &theirs, b"salt"));
+        assert!(!shares_group(&[key("home")], &theirs, b"salt"));
+    }
+
+    #[test]
+    fn tags_are_bound_to_their_salt() {
+        let theirs = tags(&[key("office")], b"salt");
+
+        assert!(!shares_group(&[key("office")], &theirs, b"other salt"));
+        assert_ne!(key("office").tag(b"salt"), key("office").tag(b"other salt"));
+    }
+
+    #[test]
+    fn ungrouped_peers_only_see_each_other() {
+        let grouped = tags(&[key("office")], b"salt");
+
+        assert!(shares_group(&[], &[], b"salt"));
+        assert!(!shares_group(&[], &grouped, b"salt"));
+        assert!(!shares_group(&[key("office")], &[], b"salt"));
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, eyre},
+};
+use if_addrs::IfAddr;
+use tracing::warn;
+
+use crate::config::discovery::DiscoveryConfig;
+
+/// An address assigned to a local network interface
+#[derive(Debug, Clone)]
+pub struct NetworkInterface {
+    pub name: String,
+    /// OS interface index, used as the scope of IPv6 link-local addresses
+    pub index: Option<u32>,
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+    /// Directed broadcast address of the interface's IPv4 subnet
+    pub broadcast: Option<Ipv4Addr>,
+}
+
+impl NetworkInterface {
+    /// Whether `ip` is on the same subnet as this interface
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        Cidr {
+            addr: self.addr,
+            prefix_len: self.prefix_len,
+        }
+        .contains(ip)
+    }
+}
+
+/// An address range in CIDR notation, such as `192.168.1.0/24` or `fd00::/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = s
+            .split_once('/')
+            .ok_or_else(|| eyre!("Missing prefix length in CIDR: {s}"))?;
+
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("Invalid address in CIDR: {s}"))?;
+        let prefix_len: u8 = prefix_len
+            .parse()
+            .with_context(|| format!("Invalid prefix length in CIDR: {s}"))?;
+
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_prefix_len {
+            return Err(eyre!("Prefix length out of range in CIDR: {s}"));
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Matches interfaces either by name or by the subnet their address is in
+#[derive(Debug, Clone)]
+pub enum InterfaceMatcher {
+    Name(String),
+    Cidr(Cidr),
+}
+
+impl InterfaceMatcher {
+    #[must_use]
+    pub fn matches(&self, interface: &NetworkInterface) -> bool {
+        match self {
+            Self::Name(name) => interface.name == *name,
+            Self::Cidr(cidr) => cidr.contains(interface.addr),
+        }
+    }
+}
+
+impl FromStr for InterfaceMatcher {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains('/') {
+            s.parse().map(Self::Cidr)
+        } else {
+            Ok(Self::Name(s.to_string()))
+        }
+    }
+}
+
+/// Which interfaces discovery is allowed to run on
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceFilter {
+    include: Vec<InterfaceMatcher>,
+    exclude: Vec<InterfaceMatcher>,
+}
+
+impl InterfaceFilter {
+    pub fn from_config(config: &DiscoveryConfig) -> Result<Self> {
+        let parse = |entries: &[String]| {
+            entries
+                .iter()
+                .map(|entry| entry.parse())
+                .collect::<Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            include: parse(&config.include_interfaces)?,
+            exclude: parse(&config.exclude_interfaces)?,
+        })
+    }
+
+    /// An interface is allowed when it isn't excluded, and is included if an include list is set
+    #[must_use]
+    pub fn allows(&self, interface: &NetworkInterface) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|m| m.matches(interface));
+        let excluded = self.exclude.iter().any(|m| m.matches(interface));
+
+        included && !excluded
+    }
+}
+
+/// List the addresses of every non-loopback interface on this machine
+#[must_use]
+pub fn list_interfaces() -> Vec<NetworkInterface> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            warn!("Failed to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    interfaces
+        .into_iter()
+        .filter(|interface| !interface.is_loopback())
+        .map(|interface| {
+            let (prefix_len, broadcast) = match &interface.addr {
+                IfAddr::V4(addr) => (addr.prefixlen, addr.broadcast),
+                IfAddr::V6(addr) => (addr.prefixlen, None),
+            };
+
+            NetworkInterface {
+                addr: interface.ip(),
+                name: interface.name,
+                index: interface.index,
+                prefix_len,
+                broadcast,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn interface(name: &str, addr: &str, prefix_len: u8) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            index: None,
+            addr: ip(addr),
+            prefix_len,
+            broadcast: None,
+        }
+    }
+
+    #[test]
+    fn parses_v4_and_v6() {
+        assert_eq!(
+            cidr("192.168.1.0/24"),
+            Cidr {
+                addr: ip("192.168.1.0"),
+                prefix_len: 24
+            }
+        );
+        assert_eq!(
+            cidr("fd00::/8"),
+            Cidr {
+                addr: ip("fd00::"),
+                prefix_len: 8
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_prefix_lengths_out_of_range() {
+        assert_eq!(cidr("10.0.0.1/32").prefix_len, 32);
+        assert_eq!(cidr("::1/128").prefix_len, 128);
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert!("10.0.0.0".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/eight".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn host_bits_are_ignored() {
+        let subnet = cidr("192.168.1.77/24");
+
+        assert!(subnet.contains(ip("192.168.1.1")));
+        assert!(subnet.contains(ip("192.168.1.255")));
+        assert!(!subnet.contains(ip("192.168.2.1")));
+    }
+
+    #[test]
+    fn contains_v4() {
+        let subnet = cidr("10.1.0.0/16");
+
+        assert!(subnet.contains(ip("10.1.200.3")));
+        assert!(!subnet.contains(ip("10.2.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(cidr("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn contains_v6() {
+        let subnet = cidr("fe80::/64");
+
+        assert!(subnet.contains(ip("fe80::1234:5678")));
+        assert!(!subnet.contains(ip("fe80:0:0:1::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::1/128").contains(ip("::1")));
+    }
+
+    #[test]
+    fn never_contains_the_other_family() {
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn matchers_by_name_or_subnet() {
+        let eth = interface("eth0", "192.168.1.20", 24);
+
+        assert!(matches!(
+            "eth0".parse::<InterfaceMatcher>().unwrap(),
+            InterfaceMatcher::Name(_)
+        ));
+        assert!("eth0".parse::<InterfaceMatcher>().unwrap().matches(&eth));
+        assert!(!"wlan0".parse::<InterfaceMatcher>().unwrap().matches(&eth));
+        assert!(
+            "192.168.0.0/16"
+                .parse::<InterfaceMatcher>()
+                .unwrap()
+                .matches(&eth)
+        );
+        assert!("10.0.0.0/8/".parse::<InterfaceMatcher>().is_err());
+    }
+
+    #[test]
+    fn filter_excludes_over_includes() {
+        let filter = InterfaceFilter {
+            include: vec!["192.168.0.0/16".parse().unwrap()],
+            exclude: vec!["docker0".parse().unwrap()],
+        };
+
+        assert!(filter.allows(&interface("eth0", "192.168.1.20", 24)));
+        assert!(!filter.allows(&interface("docker0", "192.168.5.1", 24)));
+        assert!(!filter.allows(&interface("wlan0", "10.0.0.5", 8)));
+        assert!(InterfaceFilter::default().allows(&interface("wlan0", "10.0.0.5", 8)));
+    }
+}
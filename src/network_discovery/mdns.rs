@@ -3,10 +3,16 @@ use std::{collections::HashMap, sync::Arc};
 use color_eyre::Result;
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
 use parking_lot::Mutex;
-use tracing::info;
+use tracing::{debug, info};
+
+use crate::network_discovery::groups::{self, GroupKey, GroupTag};
 
 const DOMAIN_LABEL: &str = "_alacrite._tcp.local.";
 const INSTANCE_LABEL: &str = "Alacrite";
+/// TXT record property listing the tags of a service's discovery groups
+const GROUPS_PROPERTY: &str = "groups";
+/// TXT record property holding the salt a service's group tags were made with
+const SALT_PROPERTY: &str = "salt";
 
 pub type DiscoveredServices = Mutex<HashMap<String, String>>;
 
@@ -14,20 +20,29 @@ pub struct NetworkDiscovery {
     daemon: ServiceDaemon,
     #[allow(dead_code)]
     service_info: ServiceInfo,
+    groups: Vec<GroupKey>,
 }
 
 impl NetworkDiscovery {
-    pub fn new(port: u16) -> Result<Self> {
+    pub fn new(port: u16, groups: Vec<GroupKey>) -> Result<Self> {
         let local_ip = local_ip_address::local_ip()?;
         let daemon = ServiceDaemon::new()?;
 
+        let properties = if groups.is_empty() {
+            vec![]
+        } else {
+            let salt = format!("{:016x}", rand::random::<u64>());
+            let tags = groups::tags(&groups, salt.as_bytes()).join(",");
+            vec![(SALT_PROPERTY, salt), (GROUPS_PROPERTY, tags)]
+        };
+
         let service_info = ServiceInfo::new(
             DOMAIN_LABEL,
             INSTANCE_LABEL,
             &format!("{local_ip}.local."),
             local_ip.to_string(),
             port,
-            vec![],
+            &properties[..],
         )?;
 
         daemon.register(service_info.clone())?;
@@ -35,6 +50,7 @@ impl NetworkDiscovery {
         Ok(Self {
             daemon,
             service_info,
+            groups,
         })
     }
 
@@ -67,6 +83,16 @@ impl NetworkDiscovery {
                             continue;
                         };
 
+                        let service_groups: Vec<GroupTag> = info
+                            .get_property_val_str(GROUPS_PROPERTY)
+                            .map(|groups| groups.split(',').map(str::to_string).collect())
+                            .unwrap_or_default();
+                        let salt = info.get_property_val_str(SALT_PROPERTY).unwrap_or_default();
+                        if !groups::shares_group(&self.groups, &service_groups, salt.as_bytes()) {
+                            debug!("Ignoring service {service_name}, not in any of our groups");
+                            continue;
+                        }
+
                         info!("New service discovered:");
                         info!("  Name: {service_name}");
                         info!("  Host: {service_host_ip:?}");
@@ -1,3 +1,12 @@
 pub mod discover;
+pub mod groups;
+pub mod interfaces;
 pub mod mdns;
+pub mod neighbors;
+pub mod probe;
+pub mod registry;
+pub mod relay;
+pub mod signing;
 pub mod udp_broadcast;
+pub mod wake;
+pub mod wire;
@@ -0,0 +1,124 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use color_eyre::{Report, Result, eyre::eyre};
+use serde::{Deserialize, Serialize};
+
+/// Hardware address of a network interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut bytes = [0; 6];
+        let mut parts = s.split([':', '-']);
+
+        for byte in &mut bytes {
+            let part = parts
+                .next()
+                .ok_or_else(|| eyre!("MAC address is too short: {s}"))?;
+            *byte =
+                u8::from_str_radix(part, 16).map_err(|e| eyre!("Invalid MAC address {s}: {e}"))?;
+        }
+
+        if parts.next().is_some() {
+            return Err(eyre!("MAC address is too long: {s}"));
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+impl TryFrom<String> for MacAddress {
+    type Error = Report;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<MacAddress> for String {
+    fn from(mac: MacAddress) -> Self {
+        mac.to_string()
+    }
+}
+
+/// Look up the MAC address of a host on a local subnet in the ARP or neighbor table
+///
+/// Only Linux is supported, anything else returns `None`. The entry only exists once we
+/// have exchanged packets with the host, so look it up after that.
+#[must_use]
+pub fn mac_address(ip: IpAddr) -> Option<MacAddress> {
+    match ip {
+        IpAddr::V4(ip) => arp_lookup(ip),
+        IpAddr::V6(ip) => ndp_lookup(ip),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn arp_lookup(ip: Ipv4Addr) -> Option<MacAddress> {
+    /// Set in the flags column once an entry is resolved
+    const ATF_COM: u32 = 0x2;
+
+    let table = std::fs::read_to_string("/proc/net/arp").ok()?;
+
+    // IP address, HW type, Flags, HW address, Mask, Device
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [address, _, flags, mac, ..] = fields.as_slice() else {
+            return None;
+        };
+
+        let flags = u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok()?;
+        if address.parse::<Ipv4Addr>().ok()? != ip || flags & ATF_COM == 0 {
+            return None;
+        }
+
+        mac.parse().ok()
+    })
+}
+
+/// Linux has no file listing IPv6 neighbors, so ask iproute2
+#[cfg(target_os = "linux")]
+fn ndp_lookup(ip: Ipv6Addr) -> Option<MacAddress> {
+    let output = std::process::Command::new("ip")
+        .args(["-6", "neigh", "show", &ip.to_string()])
+        .output()
+        .ok()?;
+
+    // fe80::1 dev eth0 lladdr 52:54:00:12:34:56 REACHABLE
+    let output = String::from_utf8(output.stdout).ok()?;
+    output.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        fields.find(|field| *field == "lladdr")?;
+        fields.next()?.parse().ok()
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+const fn arp_lookup(_ip: Ipv4Addr) -> Option<MacAddress> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+const fn ndp_lookup(_ip: Ipv6Addr) -> Option<MacAddress> {
+    None
+}
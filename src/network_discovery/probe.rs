@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::network_discovery::udp_broadcast::PeerId;
+
+/// How often known peers are pinged
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait for a pong before counting the ping as lost
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bytes sent to a peer when measuring throughput
+pub const BANDWIDTH_PROBE_SIZE: u64 = 4 * 1024 * 1024;
+/// Weight of a new round-trip time against the smoothed one, as in TCP's SRTT
+const RTT_SMOOTHING: f64 = 0.125;
+
+/// What we measured by probing a peer, never taken from the peer itself
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerProbe {
+    /// Smoothed round-trip time of pings, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<f64>,
+    /// Throughput of the last WebSocket test, in bytes per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<f64>,
+    /// Pings sent and left unanswered
+    #[serde(skip_serializing_if = "is_zero")]
+    pub pings_lost: u32,
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+const fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+impl PeerProbe {
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.rtt_ms.is_none() && self.bandwidth.is_none() && self.pings_lost == 0
+    }
+
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+        self.rtt_ms = Some(
+            self.rtt_ms
+                .map_or(rtt_ms, |srtt| (rtt_ms - srtt).mul_add(RTT_SMOOTHING, srtt)),
+        );
+    }
+}
+
+/// Pings waiting for a pong, by nonce
+#[derive(Debug, Default)]
+pub struct PendingPings {
+    pings: HashMap<u64, (PeerId, Instant)>,
+}
+
+impl PendingPings {
+    /// Record a ping to `peer_id`, returning the nonce to send with it
+    pub fn start(&mut self, peer_id: PeerId) -> u64 {
+        let nonce = rand::random();
+        self.pings.insert(nonce, (peer_id, Instant::now()));
+        nonce
+    }
+
+    /// Round-trip time of the ping answered by a pong from `peer_id` carrying `nonce`
+    ///
+    /// Returns `None` for pongs we didn't ask for, or that were sent by another peer.
+    pub fn finish(&mut self, peer_id: &str, nonce: u64) -> Option<Duration> {
+        match self.pings.get(&nonce) {
+            Some((pinged, sent_at)) if pinged == peer_id => {
+                let rtt = sent_at.elapsed();
+                self.pings.remove(&nonce);
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    /// Forget pings older than `PING_TIMEOUT`, returning the peers they were sent to
+    pub fn expire(&mut self) -> Vec<PeerId> {
+        let mut lost = Vec::new();
+        self.pings.retain(|_, (peer_id, sent_at)| {
+            let expired = sent_at.elapsed() >= PING_TIMEOUT;
+            if expired {
+                lost.push(peer_id.clone());
+            }
+            !expired
+        });
+        lost
+    }
+}
+
+/// Seconds since `last_seen`, a Unix timestamp
+#[must_use]
+pub fn seen_ago(last_seen: u64) -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs().saturating_sub(last_seen))
+}
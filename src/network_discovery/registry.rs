@@ -0,0 +1,432 @@
+use std::{
+    collections::{BTreeMap, btree_map::Entry},
+    fs, mem,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{Result, eyre::eyre};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::network_discovery::{
+    neighbors::MacAddress,
+    udp_broadcast::{PeerId, PeerInfo},
+};
+
+const REGISTRY_FILE: &str = "peers.json";
+/// Most peers remembered, beyond which the unlabelled and untrusted peer seen longest ago is
+/// forgotten, so peers made up by other hosts can't grow the registry without end
+const MAX_REMEMBERED_PEERS: usize = 1024;
+
+/// What we remember about a peer after it goes offline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownPeer {
+    /// Name given to the peer with `peers label`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub hostname: String,
+    /// Address the peer was last seen at
+    pub ip: IpAddr,
+    /// Discovery port the peer was last seen on
+    #[serde(default)]
+    pub port: u16,
+    /// Name of the local interface the peer was last seen on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// Hardware address, learned when the peer was on one of our subnets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<MacAddress>,
+    /// When the peer was last seen, as a Unix timestamp
+    pub last_seen: u64,
+}
+
+/// A change made with `alacrite peers`, applied by the daemon when one is running
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "edit", rename_all = "snake_case")]
+pub enum RegistryEdit {
+    AddStaticPeer {
+        address: String,
+    },
+    RemoveStaticPeer {
+        address: String,
+    },
+    /// Set or clear the label of a peer, see [`PeerRegistry::find`]
+    Label {
+        peer: String,
+        label: Option<String>,
+    },
+}
+
+/// Peers remembered across runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerRegistry {
+    /// Our own peer id, kept so other peers recognize us after a restart
+    pub local_id: Option<PeerId>,
+    /// Addresses (`host:port`) probed directly, for networks without broadcast or mDNS
+    pub static_peers: Vec<String>,
+    /// Every peer discovered so far
+    pub peers: BTreeMap<PeerId, KnownPeer>,
+    #[serde(skip)]
+    path: PathBuf,
+    /// Whether there are changes that haven't been saved yet
+    #[serde(skip)]
+    dirty: bool,
+    /// Edits applied since the registry was last saved, applied again to the saved file
+    #[serde(skip)]
+    edits: Vec<RegistryEdit>,
+}
+
+impl PeerRegistry {
+    /// Load the registry from the data directory, starting empty if it doesn't exist yet
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(REGISTRY_FILE);
+
+        let mut registry = if path.exists() {
+            read(&path)?
+        } else {
+            Self::default()
+        };
+
+        registry.path = path;
+        Ok(registry)
+    }
+
+    /// Save the registry, merging in what other processes saved since it was loaded
+    ///
+    /// Their sightings are kept where newer than ours, and our edits are applied again on
+    /// top of theirs, so neither a daemon nor `alacrite peers` undoes the other's changes.
+    pub fn save(&mut self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| eyre!("Failed to create data directory: {}", e))?;
+        }
+
+        if self.path.exists() {
+            let saved = read(&self.path)?;
+            self.merge(saved);
+        }
+
+        let registry_json = serde_json::to_string_pretty(self)
+            .map_err(|e| eyre!("Failed to serialize peer registry: {}", e))?;
+
+        // Written aside and moved over the old file, so nobody reads it half written
+        let staged = self.path.with_extension("json.tmp");
+        fs::write(&staged, registry_json)
+            .and_then(|()| fs::rename(&staged, &self.path))
+            .map_err(|e| eyre!("Failed to write peer registry: {}", e))?;
+
+        debug!("Peer registry saved to {:?}", self.path);
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Take in the registry `saved` by another process, see [`Self::save`]
+    fn merge(&mut self, saved: Self) {
+        self.local_id = self.local_id.take().or(saved.local_id);
+        self.static_peers = saved.static_peers;
+
+        for (id, theirs) in saved.peers {
+            match self.peers.entry(id) {
+                Entry::Vacant(entry) => {
+                    entry.insert(theirs);
+                }
+                Entry::Occupied(mut entry) => {
+                    let ours = entry.get_mut();
+                    let mac = ours.mac.or(theirs.mac);
+                    if theirs.last_seen > ours.last_seen {
+                        *ours = theirs;
+                    } else {
+                        ours.label = theirs.label;
+                    }
+                    ours.mac = mac;
+                }
+            }
+        }
+
+        for edit in mem::take(&mut self.edits) {
+            // The peer may have been forgotten meanwhile, there is nothing left to label
+            let _ = self.change(&edit);
+        }
+    }
+
+    /// Save the registry if anything changed since it was last saved
+    pub fn save_if_dirty(&mut self) -> Result<()> {
+        if self.dirty { self.save() } else { Ok(()) }
+    }
+
+    /// Our own peer id, generated on first use
+    pub fn local_id(&mut self) -> PeerId {
+        self.local_id
+            .get_or_insert_with(|| {
+                self.dirty = true;
+                Uuid::new_v4().to_string()
+            })
+            .clone()
+    }
+
+    /// Apply an edit made with `alacrite peers`, returning what changed
+    ///
+    /// The edit is kept until the registry is saved, to apply it again to the saved file.
+    pub fn apply(&mut self, edit: RegistryEdit) -> Result<String> {
+        // The peer is pinned down now, it could be ambiguous among the saved ones
+        let edit = match edit {
+            RegistryEdit::Label { peer, label } => RegistryEdit::Label {
+                peer: self.find(&peer)?.0.clone(),
+                label,
+            },
+            edit => edit,
+        };
+
+        let outcome = self.change(&edit)?;
+        self.edits.push(edit);
+        self.dirty = true;
+        Ok(outcome)
+    }
+
+    fn change(&mut self, edit: &RegistryEdit) -> Result<String> {
+        match edit {
+            RegistryEdit::AddStaticPeer { address } => {
+                validate_address(address)?;
+
+                if self.static_peers.contains(address) {
+                    return Ok(format!("{address} is already a static peer"));
+                }
+
+                self.static_peers.push(address.clone());
+                Ok(format!("Added static peer {address}"))
+            }
+            RegistryEdit::RemoveStaticPeer { address } => {
+                let len = self.static_peers.len();
+                self.static_peers.retain(|peer| peer != address);
+
+                if self.static_peers.len() == len {
+                    Ok(format!("{address} is not a static peer"))
+                } else {
+                    Ok(format!("Removed static peer {address}"))
+                }
+            }
+            RegistryEdit::Label { peer, label } => {
+                let id = self.set_label(peer, label.clone())?;
+
+                Ok(label.as_ref().map_or_else(
+                    || format!("Removed the label of {id}"),
+                    |label| format!("Labelled {id} as {label}"),
+                ))
+            }
+        }
+    }
+
+    /// Record a sighting of `peer`, keeping its label and the MAC address learned before
+    ///
+    /// Makes room by forgetting a peer that has no label and isn't trusted according to
+    /// `is_trusted` when the registry is full.
+    pub fn remember(&mut self, peer: &PeerInfo, is_trusted: impl Fn(&str) -> bool) {
+        let known = self.peers.get(&peer.id);
+        let label = known.and_then(|known| known.label.clone());
+        let mac = known.and_then(|known| known.mac);
+
+        self.peers.insert(
+            peer.id.clone(),
+            KnownPeer {
+                label,
+                hostname: peer.hostname.clone(),
+                ip: peer.ip,
+                port: peer.port,
+                interface: peer.interface.clone(),
+                mac,
+                last_seen: peer.last_seen,
+            },
+        );
+        self.dirty = true;
+
+        // Merging in a saved registry can leave more than one peer too many
+        while self.peers.len() > MAX_REMEMBERED_PEERS {
+            let oldest = self
+                .peers
+                .iter()
+                .filter(|(id, known)| **id != peer.id && known.label.is_none() && !is_trusted(id))
+                .min_by_key(|(_, known)| known.last_seen)
+                .map(|(id, _)| id.clone());
+
+            let Some(id) = oldest else {
+                break;
+            };
+            debug!(
+                "Forgetting {}, remembering {} peers already",
+                id, MAX_REMEMBERED_PEERS
+            );
+            self.peers.remove(&id);
+        }
+    }
+
+    /// Record the MAC address of a peer remembered before
+    pub fn learn_mac(&mut self, peer_id: &str, mac: MacAddress) {
+        if let Some(known) = self.peers.get_mut(peer_id)
+            && known.mac != Some(mac)
+        {
+            known.mac = Some(mac);
+            self.dirty = true;
+        }
+    }
+
+    /// Set or clear the label of the peer matching `query`, see [`Self::find`]
+    fn set_label(&mut self, query: &str, label: Option<String>) -> Result<PeerId> {
+        let (id, _) = self.find(query)?;
+        let id = id.clone();
+
+        if let Some(known) = self.peers.get_mut(&id) {
+            known.label = label;
+            self.dirty = true;
+        }
+
+        Ok(id)
+    }
+
+    /// Find a remembered peer by id, unique id prefix, label, or hostname
+    pub fn find(&self, query: &str) -> Result<(&PeerId, &KnownPeer)> {
+        if let Some(found) = self.peers.get_key_value(query) {
+            return Ok(found);
+        }
+
+        let matches: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(id, peer)| {
+                id.starts_with(query)
+                    || peer.label.as_deref() == Some(query)
+                    || peer.hostname == query
+            })
+            .collect();
+
+        match matches.as_slice() {
+            [found] => Ok(*found),
+            [] => Err(eyre!("No known peer matches {query}")),
+            _ => Err(eyre!(
+                "{} peers match {query}, use a longer id to pick one",
+                matches.len()
+            )),
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<PeerRegistry> {
+    let registry_json =
+        fs::read_to_string(path).map_err(|e| eyre!("Failed to read peer registry: {}", e))?;
+    serde_json::from_str(&registry_json).map_err(|e| eyre!("Failed to parse peer registry: {}", e))
+}
+
+/// Check that `address` looks like `host:port`, without resolving the host
+fn validate_address(address: &str) -> Result<()> {
+    if address.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| eyre!("Missing port in peer address: {address}"))?;
+
+    if host.is_empty() || host.contains(':') {
+        return Err(eyre!(
+            "Invalid host in peer address, IPv6 addresses need brackets: {address}"
+        ));
+    }
+
+    port.parse::<u16>()
+        .map_err(|e| eyre!("Invalid port in peer address {address}: {e}"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known(hostname: &str, last_seen: u64) -> KnownPeer {
+        KnownPeer {
+            label: None,
+            hostname: hostname.to_string(),
+            ip: IpAddr::from([192, 168, 1, 2]),
+            port: 7070,
+            interface: None,
+            mac: None,
+            last_seen,
+        }
+    }
+
+    fn registry(peers: &[(&str, KnownPeer)]) -> PeerRegistry {
+        PeerRegistry {
+            peers: peers
+                .iter()
+                .map(|(id, peer)| ((*id).to_string(), peer.clone()))
+                .collect(),
+            ..PeerRegistry::default()
+        }
+    }
+
+    #[test]
+    fn merge_keeps_edits_made_elsewhere() {
+        let mut daemon = registry(&[("a", known("laptop", 20))]);
+
+        let mut saved = registry(&[("a", known("laptop", 10))]);
+        saved
+            .apply(RegistryEdit::Label {
+                peer: "laptop".to_string(),
+                label: Some("work".to_string()),
+            })
+            .unwrap();
+        saved
+            .apply(RegistryEdit::AddStaticPeer {
+                address: "10.0.2.15:7070".to_string(),
+            })
+            .unwrap();
+
+        daemon.merge(saved);
+
+        assert_eq!(daemon.peers["a"].label.as_deref(), Some("work"));
+        assert_eq!(daemon.peers["a"].last_seen, 20);
+        assert_eq!(daemon.static_peers, ["10.0.2.15:7070"]);
+    }
+
+    #[test]
+    fn merge_applies_our_edits_again() {
+        let mut cli = registry(&[("a", known("laptop", 10))]);
+        cli.apply(RegistryEdit::Label {
+            peer: "a".to_string(),
+            label: Some("work".to_string()),
+        })
+        .unwrap();
+
+        let mut saved = registry(&[("a", known("laptop", 20)), ("b", known("desktop", 20))]);
+        saved.static_peers.push("10.0.2.15:7070".to_string());
+
+        cli.merge(saved);
+
+        assert_eq!(cli.peers["a"].label.as_deref(), Some("work"));
+        assert_eq!(cli.peers["a"].last_seen, 20);
+        assert!(cli.peers.contains_key("b"));
+        assert_eq!(cli.static_peers, ["10.0.2.15:7070"]);
+        assert!(cli.edits.is_empty());
+    }
+
+    #[test]
+    fn label_edits_are_pinned_to_one_peer() {
+        let mut cli = registry(&[("a", known("laptop", 10))]);
+        cli.apply(RegistryEdit::Label {
+            peer: "laptop".to_string(),
+            label: Some("work".to_string()),
+        })
+        .unwrap();
+
+        // Another peer with the same hostname showed up meanwhile
+        cli.merge(registry(&[
+            ("a", known("laptop", 10)),
+            ("b", known("laptop", 20)),
+        ]));
+
+        assert_eq!(cli.peers["a"].label.as_deref(), Some("work"));
+        assert_eq!(cli.peers["b"].label, None);
+    }
+}
@@ -0,0 +1,120 @@
+//! Forwarding of discovery packets between routed subnets
+//!
+//! Broadcast and link-local multicast never leave a subnet, so a relay on each subnet
+//! forwards the packets it hears to the relays on the other subnets, which broadcast
+//! them to their own subnet. Packets are forwarded unchanged inside a
+//! [`BroadcastMessage::Relayed`](super::udp_broadcast::BroadcastMessage::Relayed)
+//! envelope, so receivers still check the original sender's signature. Relayed packets
+//! are only taken from the configured relays, and only broadcast messages are relayed, so
+//! peers that aren't relays themselves list the relay of their own subnet to hear them.
+//!
+//! Every forward increases the packet's hop count, and packets are only forwarded while
+//! under `max_relay_hops`. Relays and peers also remember the ids of packets they have
+//! seen recently, so a packet arriving again over another path is dropped.
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::config::discovery::DiscoveryConfig;
+
+/// Identifies a packet, no matter how many relays it went through
+pub type MessageId = u64;
+
+/// How long packet ids are remembered
+///
+/// Longer than signed packets stay valid for, after which replays are rejected anyway.
+const SEEN_TTL: Duration = Duration::from_secs(90);
+/// Most packet ids remembered at once
+const MAX_SEEN: usize = 16 * 1024;
+
+/// Id of a packet, derived from its contents
+///
+/// Signed packets contain a random nonce, so identical messages still get distinct ids.
+#[must_use]
+pub fn message_id(frame: &[u8]) -> MessageId {
+    let hash = Sha256::digest(frame);
+    let mut id = [0; 8];
+    id.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(id)
+}
+
+/// Ids of recently seen packets
+#[derive(Debug, Default)]
+pub struct SeenMessages {
+    seen: HashMap<MessageId, Instant>,
+}
+
+impl SeenMessages {
+    /// Record `id`, returning `false` if it was already seen recently
+    pub fn insert(&mut self, id: MessageId) -> bool {
+        let now = Instant::now();
+
+        if self.seen.len() >= MAX_SEEN {
+            self.seen
+                .retain(|_, seen_at| now.duration_since(*seen_at) < SEEN_TTL);
+        }
+
+        if self.seen.len() >= MAX_SEEN {
+            // Under a flood, forgetting ids early only risks handling a packet twice
+            self.seen.clear();
+        }
+
+        self.seen
+            .insert(id, now)
+            .is_none_or(|seen_at| now.duration_since(seen_at) >= SEEN_TTL)
+    }
+}
+
+/// Settings of a node running in relay mode
+#[derive(Debug, Clone)]
+pub struct Relay {
+    max_hops: u8,
+}
+
+impl Relay {
+    /// Relay settings, or `None` if relaying isn't enabled
+    #[must_use]
+    pub fn from_config(config: &DiscoveryConfig) -> Option<Self> {
+        config.relay.then_some(Self {
+            max_hops: config.max_relay_hops,
+        })
+    }
+
+    /// Hop count for forwarding a packet that has taken `hops` hops so far, or `None`
+    /// if it has gone far enough
+    #[must_use]
+    pub fn next_hop(&self, hops: u8) -> Option<u8> {
+        (hops < self.max_hops).then(|| hops + 1)
+    }
+}
+
+/// Addresses of the relays given as `host:port`
+///
+/// Called again from time to time, so relays with changing addresses are found.
+#[must_use]
+pub fn resolve(relays: &[String]) -> Vec<SocketAddr> {
+    relays
+        .iter()
+        .filter_map(|relay| {
+            relay
+                .to_socket_addrs()
+                .inspect_err(|e| warn!("Failed to resolve relay {}: {}", relay, e))
+                .ok()
+        })
+        .flatten()
+        .collect()
+}
+
+/// Whether a packet from `from` was sent by one of the relays at `relay_addrs`
+#[must_use]
+pub fn is_relay(relay_addrs: &[SocketAddr], from: SocketAddr) -> bool {
+    relay_addrs.iter().any(|addr| {
+        addr.ip().to_canonical() == from.ip().to_canonical() && addr.port() == from.port()
+    })
+}
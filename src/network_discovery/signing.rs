@@ -0,0 +1,178 @@
+//! Signatures over discovery packets
+//!
+//! Signed packets carry a trailer after their payload, binding the packet to the
+//! sender's Ed25519 key and to a moment in time:
+//!
+//! ```text
+//! +-----------+-------+------------+-----------+
+//! | timestamp | nonce | public key | signature |
+//! | 8         | 8     | 32         | 64        |
+//! +-----------+-------+------------+-----------+
+//! ```
+//!
+//! The signature covers every byte of the packet before it, header included.
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{Result, eyre::eyre};
+use signature::{Signer, Verifier};
+use ssh_key::{Algorithm, HashAlg, PublicKey, Signature, public::Ed25519PublicKey};
+
+use crate::ssh::key_manager::KeyManager;
+
+const TIMESTAMP_LEN: usize = 8;
+const NONCE_LEN: usize = 8;
+const PUBLIC_KEY_LEN: usize = Ed25519PublicKey::BYTE_SIZE;
+const SIGNATURE_LEN: usize = 64;
+/// Size of the trailer appended to signed packets
+pub const TRAILER_LEN: usize = TIMESTAMP_LEN + NONCE_LEN + PUBLIC_KEY_LEN + SIGNATURE_LEN;
+/// How far a signed packet's timestamp may be from our clock before it is rejected
+pub const MAX_CLOCK_SKEW_SECS: u64 = 30;
+
+/// When a packet is signed and the nonce telling it apart, picked before its payload is
+/// written so the payload can refer to them
+#[derive(Debug, Clone, Copy)]
+pub struct Stamp {
+    /// When the packet was signed, as a Unix timestamp
+    pub timestamp: u64,
+    pub nonce: u64,
+}
+
+impl Stamp {
+    #[must_use]
+    pub fn now() -> Self {
+        Self {
+            timestamp: unix_timestamp(),
+            nonce: rand::random(),
+        }
+    }
+
+    /// Bytes unique to the packet signed by `public_key` with this stamp, to tie proofs
+    /// in its payload to that one packet
+    #[must_use]
+    pub fn binding(&self, public_key: &PublicKey) -> Vec<u8> {
+        let mut binding = public_key
+            .fingerprint(HashAlg::Sha256)
+            .to_string()
+            .into_bytes();
+        binding.extend_from_slice(&self.timestamp.to_be_bytes());
+        binding.extend_from_slice(&self.nonce.to_be_bytes());
+        binding
+    }
+}
+
+/// Details of a packet's valid signature
+#[derive(Debug, Clone)]
+pub struct SignedBy {
+    pub public_key: PublicKey,
+    pub stamp: Stamp,
+}
+
+impl SignedBy {
+    #[must_use]
+    pub fn fingerprint(&self) -> String {
+        self.public_key.fingerprint(HashAlg::Sha256).to_string()
+    }
+
+    /// See [`Stamp::binding`]
+    #[must_use]
+    pub fn binding(&self) -> Vec<u8> {
+        self.stamp.binding(&self.public_key)
+    }
+}
+
+/// Append a signature trailer to `packet`, signing it with our private key
+pub fn sign(packet: &mut Vec<u8>, stamp: Stamp, key_manager: &KeyManager) -> Result<()> {
+    let public_key = key_manager
+        .get_public_key()
+        .key_data()
+        .ed25519()
+        .ok_or_else(|| eyre!("Discovery packets can only be signed with Ed25519 keys"))?;
+
+    packet.extend_from_slice(&stamp.timestamp.to_be_bytes());
+    packet.extend_from_slice(&stamp.nonce.to_be_bytes());
+    packet.extend_from_slice(public_key.as_ref());
+
+    let signature: Signature = key_manager.get_private_key().try_sign(packet)?;
+    packet.extend_from_slice(signature.as_bytes());
+
+    Ok(())
+}
+
+/// Check the signature trailer at the end of `packet`
+///
+/// Returns `None` if the trailer is missing or the signature doesn't match.
+#[must_use]
+pub fn verify(packet: &[u8]) -> Option<SignedBy> {
+    let signed_len = packet.len().checked_sub(SIGNATURE_LEN)?;
+    let (signed, signature) = packet.split_at(signed_len);
+    let trailer = signed.get(signed.len().checked_sub(TRAILER_LEN - SIGNATURE_LEN)?..)?;
+
+    let (timestamp, trailer) = trailer.split_at(TIMESTAMP_LEN);
+    let (nonce, public_key) = trailer.split_at(NONCE_LEN);
+
+    let public_key = PublicKey::from(Ed25519PublicKey::try_from(public_key).ok()?);
+    let signature = Signature::new(Algorithm::Ed25519, signature).ok()?;
+    Verifier::verify(&public_key, signed, &signature).ok()?;
+
+    Some(SignedBy {
+        public_key,
+        stamp: Stamp {
+            timestamp: u64::from_be_bytes(timestamp.try_into().ok()?),
+            nonce: u64::from_be_bytes(nonce.try_into().ok()?),
+        },
+    })
+}
+
+/// Whether a signed packet could have been sent just now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    /// Already seen, so a copy sent again by someone else
+    Replayed,
+    /// Signed at a time this many seconds from our clock, too far to tell a replay apart
+    /// from a peer whose clock is off
+    Skewed(i64),
+}
+
+/// Tells signed packets that are stale or have already been seen
+#[derive(Default)]
+pub struct ReplayGuard {
+    /// Nonces seen within the clock skew window, with the timestamp they were signed at
+    seen: HashMap<(PublicKey, u64), u64>,
+}
+
+impl ReplayGuard {
+    /// Whether the packet is fresh, remembering it so a second copy is rejected
+    pub fn check(&mut self, signed_by: &SignedBy) -> Freshness {
+        let now = unix_timestamp();
+        let Stamp { timestamp, nonce } = signed_by.stamp;
+        if timestamp.abs_diff(now) > MAX_CLOCK_SKEW_SECS {
+            let skew = i64::try_from(timestamp).unwrap_or(i64::MAX)
+                - i64::try_from(now).unwrap_or(i64::MAX);
+            return Freshness::Skewed(skew);
+        }
+
+        // Anything older than the window is rejected by the timestamp check instead
+        self.seen
+            .retain(|_, timestamp| timestamp.abs_diff(now) <= MAX_CLOCK_SKEW_SECS);
+
+        let seen = self
+            .seen
+            .insert((signed_by.public_key.clone(), nonce), timestamp);
+        if seen.is_some() {
+            Freshness::Replayed
+        } else {
+            Freshness::Fresh
+        }
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
@@ -1,27 +1,129 @@
 use std::{
     collections::HashMap,
-    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use color_eyre::Result;
+use color_eyre::{Result, eyre::eyre};
 use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::{runtime::Handle, sync::oneshot};
 use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+use crate::{
+    config::discovery::{DiscoveryConfig, DiscoveryMode},
+    network_discovery::{
+        groups::{self, GroupKey, GroupTag},
+        interfaces::{InterfaceFilter, NetworkInterface, list_interfaces},
+        neighbors,
+        probe::{BANDWIDTH_PROBE_SIZE, PING_INTERVAL, PeerProbe, PendingPings},
+        registry::{PeerRegistry, RegistryEdit},
+        relay::{self, Relay, SeenMessages},
+        signing::{Freshness, ReplayGuard, SignedBy},
+        wire::{self, Packet, WireError},
+    },
+    rate_limit::KeyedRateLimiter,
+    ssh::key_manager::KeyManager,
+    websockets::{hello::Hello, speed_test},
+};
+
 pub type PeerId = String;
 
+/// Link-local multicast group used for discovery over IPv6, which has no broadcast
+pub const IPV6_MULTICAST_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xa1ac);
+/// Most sources rate limited at once
+const MAX_RATE_LIMITED_SOURCES: usize = 4096;
+/// Responses a single address can get in a burst, before `max_responses_per_second` applies
+const RESPONSE_BURST: f64 = 2.0;
+/// How often static peers are probed, in case they weren't reachable yet
+const STATIC_PEER_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a peer may go unseen before it is considered gone
+///
+/// Long enough for a few missed announcements and pings.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often changes to the peer registry are saved
+const REGISTRY_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the traffic counters are logged, if anything was dropped
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// Packets waiting to be handled, more are dropped until discovery catches up
+const INCOMING_CAPACITY: usize = 1024;
+/// Longest a listener thread waits before reading again after a socket error
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub id: PeerId,
     pub hostname: String,
     pub ip: IpAddr,
+    /// Interface index needed to reach `ip` when it is an IPv6 link-local address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope_id: Option<u32>,
     pub port: u16,
     /// When the peer was last seen, as a Unix timestamp
     pub last_seen: u64,
+    /// Name of the local interface the peer was seen on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// SHA256 fingerprint of the key the peer signs its messages with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Whether the peer's latest message was freshly signed by its trusted key, peers that
+    /// aren't trusted can't be told apart from anyone else claiming their id
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub verified: bool,
+    /// How many seconds the peer's clock is ahead of ours, when it is too far off to tell
+    /// whether its messages are fresh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_skew: Option<i64>,
+    /// Tags of the discovery groups the peer is in, only valid in the message they were
+    /// sent in, see [`groups::GroupKey::tag`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<GroupTag>,
+    /// Port of the peer's WebSocket server, if it announces one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ws_port: Option<u16>,
+    /// What we measured probing the peer, anything the peer sends here is discarded
+    #[serde(default, skip_serializing_if = "PeerProbe::is_empty")]
+    pub probe: PeerProbe,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl PeerInfo {
+    /// Address the peer can be reached at, scoped to an interface for link-local IPv6
+    #[must_use]
+    pub fn socket_addr(&self) -> SocketAddr {
+        match self.ip {
+            IpAddr::V4(ip) => SocketAddr::new(ip.into(), self.port),
+            IpAddr::V6(ip) => {
+                SocketAddrV6::new(ip, self.port, 0, self.scope_id.unwrap_or_default()).into()
+            }
+        }
+    }
+
+    /// Replace the self-reported address with the one the message actually came from
+    ///
+    /// Multi-homed peers can only guess which of their addresses we are able to reach,
+    /// and only know their own link-local addresses without a scope, so the packet's
+    /// source is the only address known to work.
+    fn resolve_address(&mut self, from_addr: SocketAddr, interface: Option<&NetworkInterface>) {
+        self.ip = from_addr.ip();
+        self.scope_id = match from_addr {
+            SocketAddr::V6(addr) if addr.ip().is_unicast_link_local() => Some(addr.scope_id()),
+            _ => None,
+        };
+        self.interface = interface.map(|interface| interface.name.clone());
+    }
+}
+
+/// Messages exchanged during discovery, see [`wire`] for how they are encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BroadcastMessage {
     /// Announce presence on the network
     Announce { peer: PeerInfo },
@@ -29,106 +131,617 @@ pub enum BroadcastMessage {
     DiscoveryRequest { from: PeerInfo },
     /// Response to discovery request
     DiscoveryResponse { peer: PeerInfo },
+    /// Request for a `Pong` carrying the same nonce, to measure round-trip time
+    Ping { peer: PeerInfo, nonce: u64 },
+    /// Response to a ping
+    Pong { peer: PeerInfo, nonce: u64 },
+    /// A packet forwarded from another subnet by a relay, see [`relay`]
+    Relayed {
+        /// Relays the packet has passed through
+        hops: u8,
+        /// The packet as sent by its peer
+        #[serde(with = "serde_bytes")]
+        frame: Vec<u8>,
+    },
+}
+
+impl BroadcastMessage {
+    /// The peer the message is from, `None` for relayed packets
+    const fn peer(&self) -> Option<&PeerInfo> {
+        match self {
+            Self::Announce { peer }
+            | Self::DiscoveryResponse { peer }
+            | Self::Ping { peer, .. }
+            | Self::Pong { peer, .. } => Some(peer),
+            Self::DiscoveryRequest { from } => Some(from),
+            Self::Relayed { .. } => None,
+        }
+    }
+
+    /// The peer the message is from, `None` for relayed packets
+    pub const fn peer_mut(&mut self) -> Option<&mut PeerInfo> {
+        match self {
+            Self::Announce { peer }
+            | Self::DiscoveryResponse { peer }
+            | Self::Ping { peer, .. }
+            | Self::Pong { peer, .. } => Some(peer),
+            Self::DiscoveryRequest { from } => Some(from),
+            Self::Relayed { .. } => None,
+        }
+    }
+
+    /// Whether the message is meant for every peer, rather than a single one
+    const fn is_broadcast(&self) -> bool {
+        matches!(self, Self::Announce { .. } | Self::DiscoveryRequest { .. })
+    }
+}
+
+/// How a message reached us
+#[derive(Debug, Clone, Copy)]
+enum Route {
+    /// Straight from its peer, which can be reached at the address it was sent from
+    Direct(SocketAddr),
+    /// Through the relay at this address, so the address the peer reports is only its
+    /// word and nothing is sent there in reply
+    Relayed(SocketAddr),
+}
+
+/// Who a packet is counted against when rate limiting
+///
+/// Relays forward the packets of every peer on their subnet, so packets they relay are
+/// counted against the peer that sent them rather than the relay's address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RateKey {
+    Address(IpAddr),
+    Peer(PeerId),
+}
+
+/// Changes to the peers currently online
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", content = "peer", rename_all = "lowercase")]
+pub enum PeerEvent {
+    Joined(PeerInfo),
+    /// An online peer was seen again or probed, and may have changed its address or ports
+    Updated(PeerInfo),
+    /// The peer hasn't been seen for a while
+    Left(PeerInfo),
+}
+
+/// Counters of discovery traffic, for spotting floods and misbehaving peers
+#[derive(Debug, Default, Clone)]
+pub struct DiscoveryStats {
+    pub packets_received: u64,
+    /// Packets from addresses, or peers behind relays, sending faster than
+    /// `max_packets_per_second`
+    pub dropped_rate_limited: u64,
+    /// Packets that aren't well-formed discovery packets
+    pub dropped_invalid: u64,
+    /// Replays, messages signed by the wrong key, and relayed packets from hosts that
+    /// aren't configured relays
+    pub dropped_unauthenticated: u64,
+    /// Messages from new peers while `max_peers` were already tracked
+    pub dropped_peer_limit: u64,
+    /// Discovery requests left unanswered to avoid amplifying traffic
+    pub responses_suppressed: u64,
+    /// Packets that arrived while `INCOMING_CAPACITY` others were waiting to be handled
+    pub dropped_backlog: u64,
+    /// Packets forwarded to other relays, in relay mode
+    pub packets_relayed: u64,
+}
+
+impl DiscoveryStats {
+    #[must_use]
+    pub const fn dropped(&self) -> u64 {
+        self.dropped_rate_limited
+            + self.dropped_invalid
+            + self.dropped_unauthenticated
+            + self.dropped_peer_limit
+            + self.dropped_backlog
+    }
+}
+
+/// Requests from other threads, handled by discovery between packets
+pub enum DiscoveryCommand {
+    /// Ask every peer to respond right away, broadcasting a discovery request and probing
+    /// the static peers
+    Discover,
+    /// Apply an edit to the registry and save it, answering with what changed
+    EditRegistry {
+        edit: RegistryEdit,
+        done: oneshot::Sender<Result<String>>,
+    },
+}
+
+/// A packet read by one of the socket listener threads
+struct Datagram {
+    data: Vec<u8>,
+    from: SocketAddr,
 }
 
 pub struct UdpBroadcastDiscovery {
-    socket: UdpSocket,
+    socket_v4: Option<UdpSocket>,
+    socket_v6: Option<UdpSocket>,
+    incoming: Receiver<Datagram>,
+    /// Packets the listener threads dropped because `incoming` was full
+    backlog_drops: Arc<AtomicU64>,
     broadcast_port: u16,
+    config: DiscoveryConfig,
+    interface_filter: InterfaceFilter,
+    /// Local interfaces, listed again on every announcement so interfaces that come up
+    /// later (VPNs, docks) are picked up without listing them for every packet
+    interfaces: Vec<NetworkInterface>,
+    key_manager: Arc<KeyManager>,
+    /// Keys of the discovery groups we are in, tagging every message we send
+    groups: Vec<GroupKey>,
+    replay_guard: ReplayGuard,
+    /// Packets already handled, so copies arriving through relays are ignored
+    seen_messages: SeenMessages,
+    /// How far to forward packets, in relay mode
+    relay: Option<Relay>,
+    /// Addresses of the configured relays, packets are forwarded to them in relay mode and
+    /// relayed packets only taken from them, resolved again on every announcement
+    relay_addrs: Vec<SocketAddr>,
+    packet_limiter: KeyedRateLimiter<RateKey>,
+    response_limiter: KeyedRateLimiter<RateKey>,
+    stats: DiscoveryStats,
+    pending_pings: PendingPings,
+    /// Runtime throughput tests are run on, if discovery was started from within one
+    runtime: Option<Handle>,
+    bandwidth_sender: Sender<(PeerId, Result<f64>)>,
+    bandwidth_results: Receiver<(PeerId, Result<f64>)>,
+    command_sender: Sender<DiscoveryCommand>,
+    commands: Receiver<DiscoveryCommand>,
     local_info: PeerInfo,
     known_peers: HashMap<PeerId, PeerInfo>,
+    /// Every peer seen, kept across runs
+    registry: PeerRegistry,
+    /// Listeners for peers joining and leaving
+    subscribers: Vec<Sender<PeerEvent>>,
 }
 
 impl UdpBroadcastDiscovery {
-    pub fn new(port: u16, hostname: String) -> Result<Self> {
-        // Bind to the specific broadcast port to listen for incoming messages
-        let socket = UdpSocket::bind(format!("0.0.0.0:{port}"))?;
-        socket.set_broadcast(true)?;
+    pub fn new(
+        port: u16,
+        hostname: String,
+        config: &DiscoveryConfig,
+        key_manager: Arc<KeyManager>,
+        mut registry: PeerRegistry,
+    ) -> Result<Self> {
+        let interface_filter = InterfaceFilter::from_config(config)?;
+        let groups = config
+            .groups
+            .iter()
+            .map(|secret| GroupKey::new(secret))
+            .collect::<Result<_>>()?;
+        let (sender, incoming) = mpsc::sync_channel(INCOMING_CAPACITY);
+        let backlog_drops = Arc::new(AtomicU64::new(0));
+
+        let (socket_v4, socket_v6) = if let Some(local) = config.local {
+            let (socket, group_socket) = bind_local(port, config, local)?;
+            spawn_receiver(group_socket, sender.clone(), backlog_drops.clone());
+
+            info!(
+                "Local mode, only discovering peers on this host at {}",
+                socket.local_addr()?
+            );
+
+            (Some(socket), None)
+        } else {
+            bind_lan(port, config, &interface_filter)?
+        };
 
-        info!("Bound UDP socket to port {}", port);
+        for socket in socket_v4.iter().chain(socket_v6.iter()) {
+            spawn_receiver(socket.try_clone()?, sender.clone(), backlog_drops.clone());
+        }
 
-        let id = Uuid::new_v4().to_string();
-        let local_ip = local_ip_address::local_ip()?;
+        // Local peers usually share a data directory, so they can't share its id
+        let id = if config.local.is_some() {
+            Uuid::new_v4().to_string()
+        } else {
+            let id = registry.local_id();
+            registry.save_if_dirty()?;
+            id
+        };
         let last_seen = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
+        // Local peers share the discovery port, so each is reached on its own socket instead
+        let (local_ip, local_port) = match (config.local, &socket_v4) {
+            (Some(local), Some(socket)) => (local.ip(), socket.local_addr()?.port()),
+            _ => (
+                local_ip_address::local_ip().or_else(|_| local_ip_address::local_ipv6())?,
+                port,
+            ),
+        };
+
         let local_info = PeerInfo {
             id,
-            hostname,
+            hostname: wire::truncate_hostname(hostname),
             ip: local_ip,
-            port,
+            scope_id: None,
+            port: local_port,
             last_seen,
+            interface: None,
+            fingerprint: None,
+            verified: false,
+            clock_skew: None,
+            groups: Vec::new(),
+            ws_port: config
+                .ws_port
+                .or_else(|| config.local.map(|local| local.port())),
+            probe: PeerProbe::default(),
         };
 
+        let (bandwidth_sender, bandwidth_results) = mpsc::channel();
+        let (command_sender, commands) = mpsc::channel();
+
         Ok(Self {
-            socket,
+            socket_v4,
+            socket_v6,
+            incoming,
+            backlog_drops,
             broadcast_port: port,
+            config: config.clone(),
+            interface_filter,
+            interfaces: list_interfaces(),
+            key_manager,
+            groups,
+            replay_guard: ReplayGuard::default(),
+            seen_messages: SeenMessages::default(),
+            relay: Relay::from_config(config),
+            relay_addrs: relay::resolve(&config.relays),
+            packet_limiter: KeyedRateLimiter::new(
+                config.max_packets_per_second,
+                config.packet_burst,
+                MAX_RATE_LIMITED_SOURCES,
+            ),
+            response_limiter: KeyedRateLimiter::new(
+                config.max_responses_per_second,
+                RESPONSE_BURST,
+                MAX_RATE_LIMITED_SOURCES,
+            ),
+            stats: DiscoveryStats::default(),
+            pending_pings: PendingPings::default(),
+            runtime: Handle::try_current().ok(),
+            bandwidth_sender,
+            bandwidth_results,
+            command_sender,
+            commands,
             local_info,
             known_peers: HashMap::new(),
+            registry,
+            subscribers: Vec::new(),
         })
     }
 
     pub fn start_listening(&mut self) -> Result<()> {
+        self.run_until(None)
+    }
+
+    /// Discover peers for `duration`, returning every peer found
+    pub fn scan(&mut self, duration: Duration) -> Result<Vec<PeerInfo>> {
+        self.run_until(Some(Instant::now() + duration))?;
+        self.registry.save_if_dirty()?;
+        Ok(self.get_known_peers())
+    }
+
+    /// Get notified of peers joining and leaving from now on
+    pub fn subscribe(&mut self) -> Receiver<PeerEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Send requests to discovery while it runs in another thread
+    #[must_use]
+    pub fn commands(&self) -> Sender<DiscoveryCommand> {
+        self.command_sender.clone()
+    }
+
+    /// Every peer seen so far, including ones that are offline now
+    #[must_use]
+    pub const fn registry(&self) -> &PeerRegistry {
+        &self.registry
+    }
+
+    /// Run discovery until `deadline`, or forever without one
+    fn run_until(&mut self, deadline: Option<Instant>) -> Result<()> {
         info!("Starting UDP broadcast discovery...");
         info!(
             "Local peer: {} ({})",
             self.local_info.hostname, self.local_info.id
         );
+        if !self.groups.is_empty() {
+            info!("Discovering peers in {} groups", self.groups.len());
+        }
+        if self.relay.is_some() {
+            info!("Relaying to {}", self.config.relays.join(", "));
+        }
 
-        self.send_discovery_request()?;
-        self.announce_presence()?;
+        // Failures to send are logged, and only last while the network is down
+        let _ = self.send_discovery_request();
+        let _ = self.announce_presence();
+        self.probe_static_peers();
 
-        let mut buffer = [0; 1024];
-        let mut last_announcement = std::time::Instant::now();
+        let mut last_announcement = Instant::now();
+        let mut last_static_probe = Instant::now();
+        let mut last_ping = Instant::now();
+        let mut last_registry_save = Instant::now();
+        let mut last_stats_log = Instant::now();
+        let mut logged_drops = 0;
 
         loop {
-            // Set a timeout for receiving
-            self.socket.set_read_timeout(Some(Duration::from_secs(1)))?;
-
-            match self.socket.recv_from(&mut buffer) {
-                Ok((len, addr)) => {
-                    let data = &buffer[..len];
-                    debug!("Received {} bytes from {}", len, addr);
-
-                    if let Ok(message) = serde_json::from_slice::<BroadcastMessage>(data) {
-                        debug!("Parsed message: {:?}", message);
-                        self.handle_broadcast_message(message, addr)?;
-                    } else {
-                        warn!("Failed to parse message from {}", addr);
-                        debug!("Raw data: {:?}", &data[..std::cmp::min(len, 100)]);
+            let timeout = deadline.map_or(Duration::from_secs(1), |deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(Duration::from_secs(1))
+            });
+
+            match self.incoming.recv_timeout(timeout) {
+                Ok(datagram) => {
+                    if let Err(e) = self.handle_datagram(&datagram) {
+                        warn!("Failed to handle packet from {}: {}", datagram.from, e);
                     }
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // Timeout - send periodic announcements every 5 seconds
-                    if last_announcement.elapsed() >= Duration::from_secs(5) {
-                        self.announce_presence()?;
-                        last_announcement = std::time::Instant::now();
-                    }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(eyre!("All UDP discovery sockets have closed"));
                 }
-                Err(e) => {
-                    warn!("Error receiving UDP broadcast: {}", e);
+            }
+
+            // Send periodic announcements every 5 seconds
+            if last_announcement.elapsed() >= Duration::from_secs(5) {
+                self.interfaces = list_interfaces();
+                self.relay_addrs = relay::resolve(&self.config.relays);
+                let _ = self.announce_presence();
+                last_announcement = Instant::now();
+            }
+
+            if last_static_probe.elapsed() >= STATIC_PEER_PROBE_INTERVAL {
+                self.probe_static_peers();
+                last_static_probe = Instant::now();
+            }
+
+            if last_ping.elapsed() >= PING_INTERVAL {
+                for peer in self.get_known_peers() {
+                    self.ping(&peer);
+                }
+                last_ping = Instant::now();
+            }
+            self.collect_probe_results();
+            self.handle_commands();
+            self.expire_peers()?;
+
+            if last_registry_save.elapsed() >= REGISTRY_SAVE_INTERVAL {
+                if let Err(e) = self.registry.save_if_dirty() {
+                    warn!("{}", e);
                 }
+                last_registry_save = Instant::now();
+            }
+
+            if last_stats_log.elapsed() >= STATS_LOG_INTERVAL {
+                self.stats.dropped_backlog = self.backlog_drops.load(Ordering::Relaxed);
+                if self.stats.dropped() > logged_drops {
+                    info!("Discovery traffic: {:?}", self.stats);
+                    logged_drops = self.stats.dropped();
+                }
+                last_stats_log = Instant::now();
+            }
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(());
             }
         }
     }
 
+    /// Decode a received packet and handle it, unless its sender is being rate limited
+    ///
+    /// Configured relays are only limited for the packets they send themselves, those they
+    /// relay are limited by the peer they came from.
+    fn handle_datagram(&mut self, datagram: &Datagram) -> Result<()> {
+        let Datagram { data, from } = datagram;
+        debug!("Received {} bytes from {}", data.len(), from);
+        self.stats.packets_received += 1;
+
+        let from_relay = relay::is_relay(&self.relay_addrs, *from);
+        if !from_relay && self.rate_limited(&RateKey::Address(from.ip()), *from) {
+            return Ok(());
+        }
+
+        match wire::decode(data) {
+            Ok(Packet {
+                message: BroadcastMessage::Relayed { hops, frame },
+                ..
+            }) => {
+                self.handle_relayed(hops, &frame, *from)?;
+            }
+            Ok(packet) => {
+                debug!("Parsed message: {:?}", packet.message);
+
+                if from_relay && self.rate_limited(&RateKey::Address(from.ip()), *from) {
+                    return Ok(());
+                }
+
+                if !self.seen_messages.insert(relay::message_id(data)) {
+                    debug!("Ignoring message from {}, already seen", from);
+                    return Ok(());
+                }
+
+                // Only packets heard on our own subnets need to reach the other relays
+                if packet.message.is_broadcast()
+                    && !self.is_own(&packet.message)
+                    && self.interface_for(*from).is_some()
+                {
+                    self.forward(data, 0);
+                }
+
+                self.handle_broadcast_message(packet, Route::Direct(*from))?;
+            }
+            Err(WireError::Foreign) => {
+                debug!("Ignoring foreign packet from {}", from);
+                self.stats.dropped_invalid += 1;
+            }
+            // Newer peers may send messages this one doesn't know yet, with every packet
+            Err(e @ WireError::UnknownMessage(_)) => {
+                debug!("Ignoring packet from {}: {}", from, e);
+                self.stats.dropped_invalid += 1;
+            }
+            Err(e) => {
+                warn!("Rejected packet from {}: {}", from, e);
+                debug!("Raw data: {:?}", &data[..std::cmp::min(data.len(), 100)]);
+                self.stats.dropped_invalid += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unwrap a packet forwarded by the relay at `from` and handle it, if `from` is one of
+    /// the configured relays
+    fn handle_relayed(&mut self, hops: u8, frame: &[u8], from: SocketAddr) -> Result<()> {
+        if !relay::is_relay(&self.relay_addrs, from) {
+            debug!(
+                "Ignoring relayed packet from {}, not a configured relay",
+                from
+            );
+            self.stats.dropped_unauthenticated += 1;
+            return Ok(());
+        }
+
+        if !self.seen_messages.insert(relay::message_id(frame)) {
+            debug!("Ignoring message relayed by {}, already seen", from);
+            return Ok(());
+        }
+
+        let packet = match wire::decode(frame) {
+            Ok(Packet {
+                message: BroadcastMessage::Relayed { .. },
+                ..
+            }) => {
+                warn!("Rejected nested relayed packet from {}", from);
+                self.stats.dropped_invalid += 1;
+                return Ok(());
+            }
+            Ok(packet) if !packet.message.is_broadcast() => {
+                warn!(
+                    "Rejected relayed packet meant for a single peer from {}",
+                    from
+                );
+                self.stats.dropped_invalid += 1;
+                return Ok(());
+            }
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Rejected packet relayed by {}: {}", from, e);
+                self.stats.dropped_invalid += 1;
+                return Ok(());
+            }
+        };
+
+        if let Some(peer) = packet.message.peer()
+            && self.rate_limited(&RateKey::Peer(peer.id.clone()), from)
+        {
+            return Ok(());
+        }
+
+        debug!(
+            "Parsed message relayed by {} after {} hops: {:?}",
+            from, hops, packet.message
+        );
+
+        if !self.is_own(&packet.message) {
+            self.forward(frame, hops);
+        }
+
+        self.handle_broadcast_message(packet, Route::Relayed(from))
+    }
+
+    /// Take a token from the bucket of `key`, counting the packet from `from` as dropped if
+    /// there was none left
+    fn rate_limited(&mut self, key: &RateKey, from: SocketAddr) -> bool {
+        if self.packet_limiter.check(key.clone()) {
+            return false;
+        }
+
+        debug!("Rate limited packet from {} ({:?})", from, key);
+        self.stats.dropped_rate_limited += 1;
+        true
+    }
+
+    /// Whether `message` was sent by us
+    fn is_own(&self, message: &BroadcastMessage) -> bool {
+        message
+            .peer()
+            .is_some_and(|peer| peer.id == self.local_info.id)
+    }
+
+    /// Forward a packet that has passed through `hops` relays to the other relays, and
+    /// broadcast it on our own subnets if it came from another relay
+    ///
+    /// Does nothing unless running in relay mode.
+    fn forward(&mut self, frame: &[u8], hops: u8) {
+        let Some(relay) = &self.relay else {
+            return;
+        };
+
+        let Some(next_hop) = relay.next_hop(hops) else {
+            debug!(
+                "Not relaying packet, it already passed through {} relays",
+                hops
+            );
+            return;
+        };
+
+        let envelope = BroadcastMessage::Relayed {
+            hops: next_hop,
+            frame: frame.to_vec(),
+        };
+
+        let data = match wire::encode(&envelope, &self.groups, &self.key_manager) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to relay packet: {}", e);
+                return;
+            }
+        };
+
+        for &addr in &self.relay_addrs {
+            let result = self
+                .socket_for(addr)
+                .and_then(|socket| Ok(socket.send_to(&data, addr)?));
+
+            match result {
+                Ok(_) => debug!("Relayed {} bytes to {}", data.len(), addr),
+                Err(e) => warn!("Failed to relay packet to {}: {}", addr, e),
+            }
+        }
+
+        if hops > 0
+            && let Err(e) = self.broadcast(&data)
+        {
+            warn!("Failed to broadcast relayed packet: {}", e);
+        }
+
+        self.stats.packets_relayed += 1;
+    }
+
     /// Send a discovery request to find other peers
     fn send_discovery_request(&self) -> Result<()> {
         let message = BroadcastMessage::DiscoveryRequest {
             from: self.local_info.clone(),
         };
 
-        let data = serde_json::to_vec(&message)?;
-        let broadcast_addr = SocketAddr::new(Ipv4Addr::BROADCAST.into(), self.broadcast_port);
+        let data = wire::encode(&message, &self.groups, &self.key_manager)?;
 
-        match self.socket.send_to(&data, broadcast_addr) {
+        match self.broadcast(&data) {
             Ok(bytes_sent) => {
                 info!(
-                    "Sent discovery request to broadcast address ({} bytes)",
+                    "Sent discovery request to broadcast addresses ({} bytes)",
                     bytes_sent
                 );
             }
             Err(e) => {
                 warn!("Failed to send discovery request: {}", e);
-                return Err(e.into());
+                return Err(e);
             }
         }
 
@@ -142,66 +755,534 @@ impl UdpBroadcastDiscovery {
 
         let message = BroadcastMessage::Announce { peer: updated_info };
 
-        let data = serde_json::to_vec(&message)?;
-        let broadcast_addr = SocketAddr::new(Ipv4Addr::BROADCAST.into(), self.broadcast_port);
+        let data = wire::encode(&message, &self.groups, &self.key_manager)?;
 
-        match self.socket.send_to(&data, broadcast_addr) {
+        match self.broadcast(&data) {
             Ok(bytes_sent) => {
                 debug!(
-                    "Announced presence to broadcast address ({} bytes)",
+                    "Announced presence to broadcast addresses ({} bytes)",
                     bytes_sent
                 );
             }
             Err(e) => {
                 warn!("Failed to announce presence: {}", e);
-                return Err(e.into());
+                return Err(e);
             }
         }
 
         Ok(())
     }
 
+    /// Interfaces discovery is currently allowed to run on
+    fn allowed_interfaces(&self) -> Vec<NetworkInterface> {
+        self.interfaces
+            .iter()
+            .filter(|interface| self.interface_filter.allows(interface))
+            .cloned()
+            .collect()
+    }
+
+    /// Send a unicast discovery request to every static peer, configured or added with
+    /// `alacrite peers add`
+    ///
+    /// Hostnames are resolved on every probe, so peers with changing addresses are found.
+    fn probe_static_peers(&self) {
+        let message = BroadcastMessage::DiscoveryRequest {
+            from: self.local_info.clone(),
+        };
+
+        let mut addresses: Vec<&String> = self
+            .config
+            .static_peers
+            .iter()
+            .chain(&self.registry.static_peers)
+            .collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+
+        for address in addresses {
+            let addrs = match address.to_socket_addrs() {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    warn!("Failed to resolve static peer {}: {}", address, e);
+                    continue;
+                }
+            };
+
+            for addr in addrs {
+                match self.send_to(addr, &message) {
+                    Ok(()) => debug!("Sent discovery request to static peer {}", addr),
+                    Err(e) => warn!("Failed to probe static peer {}: {}", addr, e),
+                }
+            }
+        }
+    }
+
+    /// Send data to the directed broadcast address (or multicast group, in multicast mode)
+    /// of every IPv4 subnet and to the IPv6 multicast group on every IPv6 interface
+    ///
+    /// Succeeds as long as one of the sends did, since hosts commonly have interfaces
+    /// that are down or only one address family configured.
+    fn broadcast(&self, data: &[u8]) -> Result<usize> {
+        if let (Some(_), Some(socket)) = (self.config.local, &self.socket_v4) {
+            let addr = SocketAddr::new(self.config.multicast_group.into(), self.broadcast_port);
+            return Ok(socket.send_to(data, addr)?);
+        }
+
+        let interfaces = self.allowed_interfaces();
+
+        let mut bytes_sent = 0;
+        let mut last_error = None;
+        let mut record = |result: std::io::Result<usize>, target: &str| match result {
+            Ok(sent) => bytes_sent += sent,
+            Err(e) => {
+                debug!("Failed to send to {}: {}", target, e);
+                last_error = Some(e);
+            }
+        };
+
+        if let Some(socket) = &self.socket_v4
+            && self.config.mode == DiscoveryMode::Multicast
+        {
+            let mut interface_addrs = ipv4_interface_addrs(&interfaces);
+
+            // Unspecified lets the OS pick its default multicast interface
+            if interface_addrs.is_empty() {
+                interface_addrs.push(Ipv4Addr::UNSPECIFIED);
+            }
+
+            let addr = SocketAddr::new(self.config.multicast_group.into(), self.broadcast_port);
+            for interface_addr in interface_addrs {
+                let result = SockRef::from(socket)
+                    .set_multicast_if_v4(&interface_addr)
+                    .and_then(|()| socket.send_to(data, addr));
+                record(result, &format!("{addr} via {interface_addr}"));
+            }
+        } else if let Some(socket) = &self.socket_v4 {
+            let mut broadcast_addrs: Vec<Ipv4Addr> = interfaces
+                .iter()
+                .filter_map(|interface| interface.broadcast)
+                .collect();
+            broadcast_addrs.sort_unstable();
+            broadcast_addrs.dedup();
+
+            // Without any known subnets, fall back to the limited broadcast address
+            if broadcast_addrs.is_empty() {
+                broadcast_addrs.push(Ipv4Addr::BROADCAST);
+            }
+
+            for broadcast_addr in broadcast_addrs {
+                let addr = SocketAddr::new(broadcast_addr.into(), self.broadcast_port);
+                record(socket.send_to(data, addr), &addr.to_string());
+            }
+        }
+
+        if let Some(socket) = &self.socket_v6 {
+            let mut indexes = ipv6_interface_indexes(&interfaces);
+
+            // Index 0 lets the OS pick its default multicast interface
+            if indexes.is_empty() {
+                indexes.push(0);
+            }
+
+            let addr = SocketAddr::new(IPV6_MULTICAST_GROUP.into(), self.broadcast_port);
+            for index in indexes {
+                let result = SockRef::from(socket)
+                    .set_multicast_if_v6(index)
+                    .and_then(|()| socket.send_to(data, addr));
+                record(result, &format!("{addr}%{index}"));
+            }
+        }
+
+        match last_error {
+            Some(e) if bytes_sent == 0 => Err(e.into()),
+            _ => Ok(bytes_sent),
+        }
+    }
+
+    /// Find the local interface a packet from `addr` arrived on
+    ///
+    /// Returns `None` for packets routed in from outside every local subnet.
+    fn interface_for(&self, addr: SocketAddr) -> Option<&NetworkInterface> {
+        self.interfaces.iter().find(|interface| match addr {
+            SocketAddr::V6(addr) if addr.ip().is_unicast_link_local() => {
+                interface.addr.is_ipv6() && interface.index == Some(addr.scope_id())
+            }
+            _ => interface.contains(addr.ip()),
+        })
+    }
+
+    /// Pick the socket matching the address family of `addr`
+    fn socket_for(&self, addr: SocketAddr) -> Result<&UdpSocket> {
+        let socket = match addr {
+            SocketAddr::V4(_) => self.socket_v4.as_ref(),
+            SocketAddr::V6(_) => self.socket_v6.as_ref(),
+        };
+
+        socket.ok_or_else(|| eyre!("No UDP socket available to reach {addr}"))
+    }
+
     /// Handle incoming broadcast messages
-    fn handle_broadcast_message(
-        &mut self,
-        message: BroadcastMessage,
-        from_addr: SocketAddr,
-    ) -> Result<()> {
+    fn handle_broadcast_message(&mut self, packet: Packet, route: Route) -> Result<()> {
+        let interface = match route {
+            Route::Direct(from_addr) => self.interface_for(from_addr).cloned(),
+            Route::Relayed(_) => None,
+        };
+        if let Some(interface) = &interface
+            && !self.interface_filter.allows(interface)
+        {
+            debug!(
+                "Ignoring message from {:?} on excluded interface {}",
+                route, interface.name
+            );
+            return Ok(());
+        }
+
+        let Packet {
+            mut message,
+            signed_by,
+        } = packet;
+
+        let Some(peer) = message.peer_mut() else {
+            return Ok(());
+        };
+        if peer.id == self.local_info.id {
+            return Ok(());
+        }
+
+        if !groups::shares_group(&self.groups, &peer.groups, &signed_by.binding()) {
+            debug!(
+                "Ignoring {} ({}), not in any of our groups",
+                peer.hostname, peer.id
+            );
+            return Ok(());
+        }
+
+        if !self.known_peers.contains_key(&peer.id)
+            && self.known_peers.len() >= self.config.max_peers
+        {
+            debug!(
+                "Ignoring {} ({}), already tracking {} peers",
+                peer.hostname, peer.id, self.config.max_peers
+            );
+            self.stats.dropped_peer_limit += 1;
+            return Ok(());
+        }
+
+        if !self.authenticate(peer, &signed_by) {
+            self.stats.dropped_unauthenticated += 1;
+            return Ok(());
+        }
+        if let Route::Direct(from_addr) = route {
+            peer.resolve_address(from_addr, interface.as_ref());
+        }
+        peer.last_seen = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        peer.probe = self
+            .known_peers
+            .get(&peer.id)
+            .map(|known| known.probe.clone())
+            .unwrap_or_default();
+
+        self.handle_peer_message(message, route);
+        Ok(())
+    }
+
+    /// Act on a message that passed every check, failing to answer it only being logged
+    fn handle_peer_message(&mut self, message: BroadcastMessage, route: Route) {
         match message {
             BroadcastMessage::DiscoveryRequest { from } => {
                 info!("Received discovery request from {}", from.hostname);
 
-                // Respond with our info
-                let response = BroadcastMessage::DiscoveryResponse {
-                    peer: self.local_info.clone(),
+                // Unicast requests come from static peers, which never announce to us
+                self.upsert_peer(from.clone());
+
+                // Answering every request would let a single host amplify its traffic
+                let (key, source) = match route {
+                    Route::Direct(source) => (RateKey::Address(source.ip()), source),
+                    Route::Relayed(relay) => (RateKey::Peer(from.id.clone()), relay),
                 };
+                if !self.response_limiter.check(key) {
+                    debug!(
+                        "Not responding to {} through {}, rate limited",
+                        from.hostname, source
+                    );
+                    self.stats.responses_suppressed += 1;
+                    return;
+                }
 
-                let data = serde_json::to_vec(&response)?;
-                self.socket.send_to(&data, from_addr)?;
-                info!("Sent discovery response to {}", from.hostname);
+                match route {
+                    Route::Direct(reply_addr) => {
+                        let response = BroadcastMessage::DiscoveryResponse {
+                            peer: self.local_info.clone(),
+                        };
+                        match self.send_to(reply_addr, &response) {
+                            Ok(()) => info!("Sent discovery response to {}", from.hostname),
+                            Err(e) => {
+                                warn!("Failed to send discovery response to {}: {}", reply_addr, e);
+                            }
+                        }
+                    }
+                    // Its address is only its word, so answer on our subnets where the
+                    // relays pick the announcement up and carry it back
+                    Route::Relayed(_) => {
+                        if self.announce_presence().is_ok() {
+                            info!("Announced ourselves to {} through relays", from.hostname);
+                        }
+                    }
+                }
             }
             BroadcastMessage::DiscoveryResponse { peer } => {
                 info!(
-                    "Received discovery response from {} at {}",
-                    peer.hostname, peer.ip
+                    "Received discovery response from {} at {} ({}{})",
+                    peer.hostname,
+                    peer.socket_addr(),
+                    peer.interface.as_deref().unwrap_or("routed"),
+                    if peer.verified { "" } else { ", unverified" }
                 );
-                self.known_peers.insert(peer.id.clone(), peer);
+                self.upsert_peer(peer);
             }
             BroadcastMessage::Announce { peer } => {
-                if peer.id != self.local_info.id {
-                    if self.known_peers.contains_key(&peer.id) {
-                        debug!("Already know peer: {} at {}", peer.hostname, peer.ip);
-                    } else {
-                        info!("Discovered peer: {} at {}", peer.hostname, peer.ip);
-                        self.known_peers.insert(peer.id.clone(), peer);
+                if self.known_peers.contains_key(&peer.id) {
+                    debug!(
+                        "Already know peer: {} at {}",
+                        peer.hostname,
+                        peer.socket_addr()
+                    );
+                } else {
+                    info!(
+                        "Discovered peer: {} at {} ({}{})",
+                        peer.hostname,
+                        peer.socket_addr(),
+                        peer.interface.as_deref().unwrap_or("routed"),
+                        if peer.verified { "" } else { ", unverified" }
+                    );
+                }
+                self.upsert_peer(peer);
+            }
+            BroadcastMessage::Ping { peer, nonce } => {
+                // Pings are never relayed
+                if let Route::Direct(reply_addr) = route {
+                    let pong = BroadcastMessage::Pong {
+                        peer: self.local_info.clone(),
+                        nonce,
+                    };
+                    match self.send_to(reply_addr, &pong) {
+                        Ok(()) => debug!("Answered ping from {}", peer.hostname),
+                        Err(e) => warn!("Failed to answer ping from {}: {}", reply_addr, e),
                     }
                 }
+                self.upsert_peer(peer);
+            }
+            BroadcastMessage::Pong { mut peer, nonce } => {
+                if let Some(rtt) = self.pending_pings.finish(&peer.id, nonce) {
+                    debug!("Round trip to {} took {:?}", peer.hostname, rtt);
+                    peer.probe.record_rtt(rtt);
+                }
+
+                // Answering our ping means the peer's hardware address was just resolved,
+                // only worth looking up if we don't know it for this address yet
+                let unknown_mac = self.registry.peers.get(&peer.id).is_none_or(|known| {
+                    known.mac.is_none() || known.ip.to_canonical() != peer.ip.to_canonical()
+                });
+                let mac = (unknown_mac && peer.interface.is_some())
+                    .then(|| neighbors::mac_address(peer.ip))
+                    .flatten();
+
+                let peer_id = peer.id.clone();
+                self.upsert_peer(peer);
+                if let Some(mac) = mac {
+                    self.registry.learn_mac(&peer_id, mac);
+                }
+            }
+            BroadcastMessage::Relayed { .. } => {}
+        }
+    }
+
+    /// Add a peer or refresh what we know about it, probing it when it is new
+    fn upsert_peer(&mut self, peer: PeerInfo) {
+        if self.known_peers.contains_key(&peer.id) {
+            self.notify(&PeerEvent::Updated(peer.clone()));
+        } else {
+            self.probe_peer(&peer);
+            self.notify(&PeerEvent::Joined(peer.clone()));
+        }
+        let key_manager = &self.key_manager;
+        self.registry
+            .remember(&peer, |id| key_manager.is_peer_trusted(id));
+        self.known_peers.insert(peer.id.clone(), peer);
+    }
+
+    /// Forget peers that haven't been seen for `PEER_TIMEOUT`
+    fn expire_peers(&mut self) -> Result<()> {
+        let cutoff = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs()
+            .saturating_sub(PEER_TIMEOUT.as_secs());
+
+        let expired: Vec<PeerId> = self
+            .known_peers
+            .values()
+            .filter(|peer| peer.last_seen < cutoff)
+            .map(|peer| peer.id.clone())
+            .collect();
+
+        for peer_id in expired {
+            if let Some(peer) = self.known_peers.remove(&peer_id) {
+                info!("Lost peer: {} ({})", peer.hostname, peer.id);
+                self.notify(&PeerEvent::Left(peer));
             }
         }
 
         Ok(())
     }
 
+    /// Send an event to every subscriber, dropping the ones that went away
+    fn notify(&mut self, event: &PeerEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Measure round-trip time to a peer, and its throughput if enabled and the peer
+    /// announces a WebSocket server
+    fn probe_peer(&mut self, peer: &PeerInfo) {
+        self.ping(peer);
+
+        if !self.config.probe_bandwidth {
+            return;
+        }
+        let (Some(runtime), Some(ws_port)) = (&self.runtime, peer.ws_port) else {
+            return;
+        };
+
+        let mut addr = peer.socket_addr();
+        addr.set_port(ws_port);
+        let peer_id = peer.id.clone();
+        let sender = self.bandwidth_sender.clone();
+        let hello = Hello::new(Some(self.local_info.id.clone()), false);
+        let key_manager = self.key_manager.clone();
+
+        runtime.spawn(async move {
+            let result = speed_test::measure_throughput(
+                addr,
+                &peer_id,
+                BANDWIDTH_PROBE_SIZE,
+                &hello,
+                &key_manager,
+            )
+            .await;
+            let _ = sender.send((peer_id, result));
+        });
+    }
+
+    fn ping(&mut self, peer: &PeerInfo) {
+        let message = BroadcastMessage::Ping {
+            peer: self.local_info.clone(),
+            nonce: self.pending_pings.start(peer.id.clone()),
+        };
+
+        if let Err(e) = self.send_to_peer(peer, &message) {
+            debug!("Failed to ping {}: {}", peer.hostname, e);
+        }
+    }
+
+    /// Record finished throughput tests and pings that went unanswered
+    fn handle_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                DiscoveryCommand::Discover => {
+                    let _ = self.send_discovery_request();
+                    self.probe_static_peers();
+                }
+                DiscoveryCommand::EditRegistry { edit, done } => {
+                    let adds_static_peer = matches!(edit, RegistryEdit::AddStaticPeer { .. });
+                    let outcome = self
+                        .registry
+                        .apply(edit)
+                        .and_then(|outcome| self.registry.save().map(|()| outcome));
+
+                    if adds_static_peer && outcome.is_ok() {
+                        self.probe_static_peers();
+                    }
+                    // The client may have given up waiting
+                    let _ = done.send(outcome);
+                }
+            }
+        }
+    }
+
+    fn collect_probe_results(&mut self) {
+        while let Ok((peer_id, result)) = self.bandwidth_results.try_recv() {
+            match result {
+                Ok(bandwidth) => {
+                    if let Some(peer) = self.known_peers.get_mut(&peer_id) {
+                        peer.probe.bandwidth = Some(bandwidth);
+                        let event = PeerEvent::Updated(peer.clone());
+                        self.notify(&event);
+                    }
+                }
+                Err(e) => debug!("Throughput test to {} failed: {}", peer_id, e),
+            }
+        }
+
+        for peer_id in self.pending_pings.expire() {
+            if let Some(peer) = self.known_peers.get_mut(&peer_id) {
+                peer.probe.pings_lost += 1;
+            }
+        }
+    }
+
+    /// Check whether a message was signed by the trusted key of its peer id, recording the
+    /// outcome on the peer
+    ///
+    /// Only trusted keys are enforced, as pinning whichever key signs first for an id would
+    /// let anyone quick enough lock the real peer out. Messages signed too far from our
+    /// clock are let through as unverified. Returns `false` for messages that must be
+    /// dropped: replays, and messages claiming a trusted id signed by a different key.
+    fn authenticate(&mut self, peer: &mut PeerInfo, signed_by: &SignedBy) -> bool {
+        let trusted_key = self.key_manager.get_trusted_key(&peer.id);
+        if trusted_key
+            .as_ref()
+            .is_some_and(|key| *key != signed_by.public_key)
+        {
+            warn!(
+                "Dropping message claiming to be {} ({}) signed by a different key",
+                peer.hostname, peer.id
+            );
+            return false;
+        }
+
+        peer.fingerprint = Some(signed_by.fingerprint());
+        peer.clock_skew = None;
+        match self.replay_guard.check(signed_by) {
+            Freshness::Fresh => {
+                peer.verified = trusted_key.is_some();
+                true
+            }
+            Freshness::Replayed => {
+                warn!(
+                    "Dropping replayed message from {} ({})",
+                    peer.hostname, peer.id
+                );
+                false
+            }
+            Freshness::Skewed(skew) => {
+                debug!(
+                    "The clock of {} ({}) is {}s off, listing it as unverified",
+                    peer.hostname, peer.id, skew
+                );
+                peer.verified = false;
+                peer.clock_skew = Some(skew);
+                true
+            }
+        }
+    }
+
+    /// Id this peer is announced with
+    #[must_use]
+    pub fn local_id(&self) -> &str {
+        &self.local_info.id
+    }
+
     /// Get list of discovered peers
     #[must_use]
     pub fn get_known_peers(&self) -> Vec<PeerInfo> {
@@ -210,15 +1291,243 @@ impl UdpBroadcastDiscovery {
 
     /// Send a message to a specific peer
     pub fn send_to_peer(&self, peer: &PeerInfo, message: &BroadcastMessage) -> Result<()> {
-        let data = serde_json::to_vec(message)?;
-        let peer_addr = SocketAddr::new(peer.ip, peer.port);
-        self.socket.send_to(&data, peer_addr)?;
+        self.send_to(peer.socket_addr(), message)
+    }
+
+    /// Send a message to an address that may not belong to a known peer yet
+    fn send_to(&self, addr: SocketAddr, message: &BroadcastMessage) -> Result<()> {
+        let data = wire::encode(message, &self.groups, &self.key_manager)?;
+        self.socket_for(addr)?.send_to(&data, addr)?;
         Ok(())
     }
 }
 
-pub fn run_udp_discovery(port: u16, hostname: String) -> Result<()> {
-    let mut discovery = UdpBroadcastDiscovery::new(port, hostname)?;
+/// Bind the discovery port on every address family that has an allowed interface
+fn bind_lan(
+    port: u16,
+    config: &DiscoveryConfig,
+    interface_filter: &InterfaceFilter,
+) -> Result<(Option<UdpSocket>, Option<UdpSocket>)> {
+    let interfaces: Vec<_> = list_interfaces()
+        .into_iter()
+        .filter(|interface| interface_filter.allows(interface))
+        .collect();
+
+    for interface in &interfaces {
+        info!(
+            "Discovery enabled on {} ({}/{})",
+            interface.name, interface.addr, interface.prefix_len
+        );
+    }
+
+    // Bind to the specific broadcast port on both address families to listen for incoming messages
+    let socket_v4 = bind_ipv4(port, config, &interfaces)
+        .inspect_err(|e| warn!("IPv4 discovery unavailable: {}", e))
+        .ok();
+    let socket_v6 = bind_ipv6(port, config, &interfaces)
+        .inspect_err(|e| warn!("IPv6 discovery unavailable: {}", e))
+        .ok();
+
+    if socket_v4.is_none() && socket_v6.is_none() {
+        return Err(eyre!("Failed to bind UDP discovery port {port}"));
+    }
+
+    info!(
+        "Bound UDP sockets to port {} ({:?} mode)",
+        port, config.mode
+    );
+
+    Ok((socket_v4, socket_v6))
+}
+
+fn bind_ipv4(
+    port: u16,
+    config: &DiscoveryConfig,
+    interfaces: &[NetworkInterface],
+) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    socket.set_broadcast(true)?;
+
+    if config.mode == DiscoveryMode::Multicast {
+        let group = config.multicast_group;
+        if !group.is_multicast() {
+            return Err(eyre!("{group} is not a multicast address"));
+        }
+
+        socket.set_multicast_ttl_v4(config.multicast_ttl)?;
+        // Our own messages would otherwise be delivered straight back to us
+        socket.set_multicast_loop_v4(false)?;
+
+        let interface_addrs = ipv4_interface_addrs(interfaces);
+        if interface_addrs.is_empty() {
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        }
+        for interface_addr in interface_addrs {
+            if let Err(e) = socket.join_multicast_v4(&group, &interface_addr) {
+                warn!(
+                    "Failed to join multicast group {} on {}: {}",
+                    group, interface_addr, e
+                );
+            }
+        }
+    }
+
+    Ok(socket)
+}
+
+fn bind_ipv6(
+    port: u16,
+    config: &DiscoveryConfig,
+    interfaces: &[NetworkInterface],
+) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    // The IPv4 socket already owns this port for IPv4 traffic
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+
+    if config.mode == DiscoveryMode::Multicast {
+        socket.set_multicast_hops_v6(config.multicast_ttl)?;
+        socket.set_multicast_loop_v6(false)?;
+    }
+
+    let indexes = ipv6_interface_indexes(interfaces);
+    if indexes.is_empty() {
+        socket.join_multicast_v6(&IPV6_MULTICAST_GROUP, 0)?;
+    }
+    for index in indexes {
+        if let Err(e) = socket.join_multicast_v6(&IPV6_MULTICAST_GROUP, index) {
+            warn!(
+                "Failed to join IPv6 multicast group on interface {}: {}",
+                index, e
+            );
+        }
+    }
+
+    Ok(socket.into())
+}
+
+/// Bind the sockets used in local mode
+///
+/// Returns a unicast socket on `local`'s address that only this peer uses, which
+/// messages are sent from and replies arrive on, and a socket joined to the multicast
+/// group on the discovery port, which is shared by every local peer on this host.
+fn bind_local(
+    port: u16,
+    config: &DiscoveryConfig,
+    local: SocketAddr,
+) -> Result<(UdpSocket, UdpSocket)> {
+    let IpAddr::V4(local_ip) = local.ip() else {
+        return Err(eyre!(
+            "Local mode needs an IPv4 address, got {}",
+            local.ip()
+        ));
+    };
+
+    let group = config.multicast_group;
+    if !group.is_multicast() {
+        return Err(eyre!("{group} is not a multicast address"));
+    }
+
+    let socket = UdpSocket::bind((local_ip, 0))?;
+    SockRef::from(&socket).set_multicast_if_v4(&local_ip)?;
+    // A TTL of 0 keeps messages on this host, where they still loop back to the other peers
+    socket.set_multicast_ttl_v4(0)?;
+    socket.set_multicast_loop_v4(true)?;
+
+    let group_socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    group_socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    group_socket.set_reuse_port(true)?;
+    group_socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port).into())?;
+    group_socket.join_multicast_v4(&group, &local_ip)?;
+
+    Ok((socket, group_socket.into()))
+}
+
+/// Addresses of the interfaces that have an IPv4 address
+fn ipv4_interface_addrs(interfaces: &[NetworkInterface]) -> Vec<Ipv4Addr> {
+    interfaces
+        .iter()
+        .filter_map(|interface| match interface.addr {
+            IpAddr::V4(addr) => Some(addr),
+            IpAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Indexes of the interfaces that have an IPv6 address, without duplicates
+fn ipv6_interface_indexes(interfaces: &[NetworkInterface]) -> Vec<u32> {
+    let mut indexes: Vec<u32> = interfaces
+        .iter()
+        .filter(|interface| interface.addr.is_ipv6())
+        .filter_map(|interface| interface.index)
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+}
+
+/// Forward every packet received on `socket` to the discovery loop, dropping packets while
+/// it is behind and counting them in `dropped`
+fn spawn_receiver(socket: UdpSocket, sender: SyncSender<Datagram>, dropped: Arc<AtomicU64>) {
+    thread::spawn(move || {
+        // Large enough for any UDP payload, so oversized packets are rejected rather than truncated
+        let mut buffer = vec![0; usize::from(u16::MAX)];
+        let mut backoff = Duration::ZERO;
+
+        loop {
+            match socket.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    backoff = Duration::ZERO;
+                    let datagram = Datagram {
+                        data: buffer[..len].to_vec(),
+                        from,
+                    };
+
+                    match sender.try_send(datagram) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(TrySendError::Disconnected(_)) => break,
+                    }
+                }
+                // Interrupted reads, and ICMP errors some systems report for earlier sends
+                Err(e) if is_transient(&e) => {}
+                Err(e) => {
+                    backoff = (backoff * 2)
+                        .max(Duration::from_millis(100))
+                        .min(MAX_RECEIVE_BACKOFF);
+                    warn!(
+                        "Error receiving UDP broadcast, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    thread::sleep(backoff);
+                }
+            }
+        }
+    });
+}
+
+fn is_transient(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+    )
+}
+
+pub fn run_udp_discovery(
+    port: u16,
+    hostname: String,
+    config: &DiscoveryConfig,
+    key_manager: Arc<KeyManager>,
+    registry: PeerRegistry,
+) -> Result<()> {
+    let mut discovery = UdpBroadcastDiscovery::new(port, hostname, config, key_manager, registry)?;
 
     discovery.start_listening()?;
 
@@ -0,0 +1,60 @@
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+use color_eyre::{Result, eyre::eyre};
+use tracing::{debug, warn};
+
+use crate::network_discovery::{interfaces::list_interfaces, neighbors::MacAddress};
+
+/// Port magic packets are conventionally sent to
+const WAKE_ON_LAN_PORT: u16 = 9;
+
+/// Build a Wake-on-LAN magic packet: six `0xff` bytes followed by the MAC sixteen times
+#[must_use]
+pub fn magic_packet(mac: MacAddress) -> Vec<u8> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac.0);
+    }
+    packet
+}
+
+/// Broadcast a magic packet for `mac`, last seen at `ip` on the interface named `interface`
+///
+/// Sleeping hosts have no ARP entry to unicast to, so the packet is sent to the IPv4
+/// broadcast address of that interface and of the local subnet containing `ip`, and to
+/// the limited broadcast address in case neither exists anymore. Every address is tried,
+/// failing only if the packet couldn't be sent to any. Returns the addresses sent to.
+pub fn wake(mac: MacAddress, ip: IpAddr, interface: Option<&str>) -> Result<Vec<Ipv4Addr>> {
+    let mut targets: Vec<Ipv4Addr> = list_interfaces()
+        .into_iter()
+        .filter(|candidate| interface == Some(candidate.name.as_str()) || candidate.contains(ip))
+        .filter_map(|candidate| candidate.broadcast)
+        .collect();
+    targets.sort_unstable();
+    targets.push(Ipv4Addr::BROADCAST);
+    targets.dedup();
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+
+    let packet = magic_packet(mac);
+    let mut sent = Vec::with_capacity(targets.len());
+    let mut last_error = None;
+    for target in targets {
+        match socket.send_to(&packet, (target, WAKE_ON_LAN_PORT)) {
+            Ok(_) => {
+                debug!("Sent magic packet for {} to {}", mac, target);
+                sent.push(target);
+            }
+            Err(e) => {
+                warn!("Failed to send magic packet to {}: {}", target, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if sent.is_empty() => Err(eyre!("Failed to send magic packet: {e}")),
+        _ => Ok(sent),
+    }
+}
@@ -0,0 +1,347 @@
+//! Wire format of discovery packets
+//!
+//! Every packet starts with a fixed header followed by a `MessagePack` payload:
+//!
+//! ```text
+//! +-------+---------+-------+----------------+---------+
+//! | magic | version | flags | payload length | payload |
+//! | 4     | 1       | 1     | 2 (big endian) | ...     |
+//! +-------+---------+-------+----------------+---------+
+//! ```
+//!
+//! Payload fields are encoded by name, so fields added by newer peers are ignored by
+//! older ones. The version is only bumped for changes older peers cannot ignore.
+//!
+//! Every packet has [`FLAG_SIGNED`] set and is followed by a signature trailer, see
+//! [`signing`]. Unsigned packets are rejected.
+
+use std::fmt;
+
+use color_eyre::{Result, eyre::eyre};
+
+use crate::{
+    network_discovery::{
+        groups::{self, GroupKey},
+        signing::{self, SignedBy, Stamp},
+        udp_broadcast::BroadcastMessage,
+    },
+    ssh::key_manager::KeyManager,
+};
+
+/// Identifies a packet as an Alacrite discovery packet
+pub const MAGIC: [u8; 4] = *b"ALCR";
+/// Current version of the wire format
+pub const VERSION: u8 = 1;
+/// Size of the header preceding the payload
+pub const HEADER_LEN: usize = MAGIC.len() + 4;
+/// Set when the payload is followed by a signature trailer
+pub const FLAG_SIGNED: u8 = 0b0000_0001;
+/// Largest packet we send or accept
+///
+/// Stays under the IPv6 minimum MTU so packets are never fragmented.
+pub const MAX_PACKET_SIZE: usize = 1200;
+/// Longest hostname announced to other peers, matching the DNS limit
+pub const MAX_HOSTNAME_LEN: usize = 253;
+
+/// Reasons a received packet was rejected
+#[derive(Debug)]
+pub enum WireError {
+    /// The packet doesn't start with our magic bytes, so it isn't meant for us
+    Foreign,
+    /// The packet is shorter than its header says it is
+    Truncated,
+    /// The packet is larger than `MAX_PACKET_SIZE`
+    Oversized(usize),
+    /// The packet was sent by a newer, incompatible peer
+    UnsupportedVersion(u8),
+    /// The packet isn't signed
+    Unsigned,
+    /// The packet is signed, but the signature doesn't match its contents
+    BadSignature,
+    /// The payload is a message this peer doesn't know, likely from a newer peer
+    UnknownMessage(String),
+    /// The payload could not be decoded
+    Malformed(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Foreign => write!(f, "not an alacrite discovery packet"),
+            Self::Truncated => write!(f, "packet is truncated"),
+            Self::Oversized(len) => {
+                write!(f, "packet of {len} bytes exceeds {MAX_PACKET_SIZE} bytes")
+            }
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported wire format version {version}")
+            }
+            Self::Unsigned => write!(f, "packet is not signed"),
+            Self::BadSignature => write!(f, "invalid signature"),
+            Self::UnknownMessage(e) => write!(f, "unknown message: {e}"),
+            Self::Malformed(e) => write!(f, "malformed payload: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// A decoded packet
+#[derive(Debug)]
+pub struct Packet {
+    pub message: BroadcastMessage,
+    /// Who signed the packet
+    pub signed_by: SignedBy,
+}
+
+/// Encode a message into a single packet signed with our key
+///
+/// The peer the message carries is tagged with the groups in `groups`, for this packet only.
+pub fn encode(
+    message: &BroadcastMessage,
+    groups: &[GroupKey],
+    key_manager: &KeyManager,
+) -> Result<Vec<u8>> {
+    let stamp = Stamp::now();
+    let mut message = message.clone();
+    if let Some(peer) = message.peer_mut() {
+        peer.groups = groups::tags(groups, &stamp.binding(key_manager.get_public_key()));
+    }
+    let payload = rmp_serde::to_vec_named(&message)?;
+
+    let packet_len = HEADER_LEN + payload.len() + signing::TRAILER_LEN;
+    if packet_len > MAX_PACKET_SIZE {
+        return Err(eyre!(
+            "Discovery packet of {packet_len} bytes exceeds {MAX_PACKET_SIZE} bytes"
+        ));
+    }
+
+    let payload_len = u16::try_from(payload.len())?;
+
+    let mut packet = Vec::with_capacity(packet_len);
+    packet.extend_from_slice(&MAGIC);
+    packet.push(VERSION);
+    packet.push(FLAG_SIGNED);
+    packet.extend_from_slice(&payload_len.to_be_bytes());
+    packet.extend_from_slice(&payload);
+    signing::sign(&mut packet, stamp, key_manager)?;
+
+    Ok(packet)
+}
+
+/// Decode a packet, rejecting anything that isn't a well-formed, signed packet we
+/// understand
+///
+/// The signature only shows who sent the packet, it is up to the caller to decide whether
+/// to trust them.
+pub fn decode(packet: &[u8]) -> Result<Packet, WireError> {
+    // Checked first, so other traffic on the port is told apart whatever its size
+    if !packet.starts_with(&MAGIC) {
+        return Err(WireError::Foreign);
+    }
+
+    if packet.len() > MAX_PACKET_SIZE {
+        return Err(WireError::Oversized(packet.len()));
+    }
+
+    let Some(&[version, flags, len_high, len_low]) = packet.get(MAGIC.len()..HEADER_LEN) else {
+        return Err(WireError::Truncated);
+    };
+
+    if version != VERSION {
+        return Err(WireError::UnsupportedVersion(version));
+    }
+
+    let payload_len = usize::from(u16::from_be_bytes([len_high, len_low]));
+    let payload = packet
+        .get(HEADER_LEN..HEADER_LEN + payload_len)
+        .ok_or(WireError::Truncated)?;
+
+    if flags & FLAG_SIGNED == 0 {
+        return Err(WireError::Unsigned);
+    }
+    let signed_packet = packet
+        .get(..HEADER_LEN + payload_len + signing::TRAILER_LEN)
+        .ok_or(WireError::Truncated)?;
+    let signed_by = signing::verify(signed_packet).ok_or(WireError::BadSignature)?;
+
+    let message = rmp_serde::from_slice(payload).map_err(|e| match e {
+        rmp_serde::decode::Error::Syntax(e) if e.starts_with("unknown variant") => {
+            WireError::UnknownMessage(e)
+        }
+        e => WireError::Malformed(e.to_string()),
+    })?;
+
+    Ok(Packet { message, signed_by })
+}
+
+/// Shorten a hostname to `MAX_HOSTNAME_LEN` bytes without splitting a character
+#[must_use]
+pub fn truncate_hostname(mut hostname: String) -> String {
+    if hostname.len() > MAX_HOSTNAME_LEN {
+        let mut end = MAX_HOSTNAME_LEN;
+        while !hostname.is_char_boundary(end) {
+            end -= 1;
+        }
+        hostname.truncate(end);
+    }
+
+    hostname
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+    use ssh_key::HashAlg;
+
+    use super::*;
+
+    fn message() -> BroadcastMessage {
+        BroadcastMessage::Relayed {
+            hops: 1,
+            frame: vec![1, 2, 3],
+        }
+    }
+
+    /// A packet with `payload`, signed by `key_manager`
+    fn packet(payload: &[u8], key_manager: &KeyManager) -> Vec<u8> {
+        let mut packet = MAGIC.to_vec();
+        packet.push(VERSION);
+        packet.push(FLAG_SIGNED);
+        packet.extend_from_slice(&u16::try_from(payload.len()).unwrap().to_be_bytes());
+        packet.extend_from_slice(payload);
+        signing::sign(&mut packet, Stamp::now(), key_manager).unwrap();
+        packet
+    }
+
+    #[test]
+    fn round_trip() {
+        let key_manager = KeyManager::ephemeral();
+        let packet = encode(&message(), &[], &key_manager).unwrap();
+        let decoded = decode(&packet).unwrap();
+
+        assert!(matches!(
+            decoded.message,
+            BroadcastMessage::Relayed { hops: 1, ref frame } if frame == &[1, 2, 3]
+        ));
+        assert_eq!(
+            decoded.signed_by.fingerprint(),
+            key_manager
+                .get_public_key()
+                .fingerprint(HashAlg::Sha256)
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn header_layout() {
+        let packet = encode(&message(), &[], &KeyManager::ephemeral()).unwrap();
+        let payload_len = packet.len() - HEADER_LEN - signing::TRAILER_LEN;
+
+        assert_eq!(packet[..4], MAGIC);
+        assert_eq!(packet[4], VERSION);
+        assert_eq!(packet[5], FLAG_SIGNED);
+        assert_eq!(
+            usize::from(u16::from_be_bytes([packet[6], packet[7]])),
+            payload_len
+        );
+    }
+
+    #[test]
+    fn rejects_foreign_packets_whatever_their_size() {
+        assert!(matches!(decode(b"GET / HTTP/1.1"), Err(WireError::Foreign)));
+        assert!(matches!(decode(&[]), Err(WireError::Foreign)));
+        assert!(matches!(
+            decode(&vec![0; MAX_PACKET_SIZE * 2]),
+            Err(WireError::Foreign)
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized() {
+        let mut packet = MAGIC.to_vec();
+        packet.resize(MAX_PACKET_SIZE + 1, 0);
+
+        assert!(matches!(
+            decode(&packet),
+            Err(WireError::Oversized(len)) if len == MAX_PACKET_SIZE + 1
+        ));
+
+        let message = BroadcastMessage::Relayed {
+            hops: 0,
+            frame: vec![0; MAX_PACKET_SIZE],
+        };
+        assert!(encode(&message, &[], &KeyManager::ephemeral()).is_err());
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut packet = encode(&message(), &[], &KeyManager::ephemeral()).unwrap();
+        packet[4] = VERSION + 1;
+
+        assert!(matches!(
+            decode(&packet),
+            Err(WireError::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let packet = encode(&message(), &[], &KeyManager::ephemeral()).unwrap();
+
+        assert!(matches!(
+            decode(&packet[..HEADER_LEN - 1]),
+            Err(WireError::Truncated)
+        ));
+        // The header promises more payload than there is
+        assert!(matches!(
+            decode(&packet[..HEADER_LEN + 2]),
+            Err(WireError::Truncated)
+        ));
+        // The payload is there, but not the whole signature trailer
+        assert!(matches!(
+            decode(&packet[..packet.len() - 1]),
+            Err(WireError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn rejects_unsigned() {
+        let mut packet = encode(&message(), &[], &KeyManager::ephemeral()).unwrap();
+        packet[5] &= !FLAG_SIGNED;
+
+        assert!(matches!(decode(&packet), Err(WireError::Unsigned)));
+    }
+
+    #[test]
+    fn rejects_tampered_payload_and_length() {
+        let packet = encode(&message(), &[], &KeyManager::ephemeral()).unwrap();
+
+        let mut tampered = packet.clone();
+        tampered[HEADER_LEN] ^= 1;
+        assert!(matches!(decode(&tampered), Err(WireError::BadSignature)));
+
+        let mut tampered = packet;
+        tampered[7] -= 1;
+        assert!(matches!(decode(&tampered), Err(WireError::BadSignature)));
+    }
+
+    #[test]
+    fn tells_unknown_messages_from_malformed_ones() {
+        #[derive(Serialize)]
+        enum Newer {
+            Gossip { rumour: u8 },
+        }
+        let key_manager = KeyManager::ephemeral();
+
+        let unknown = rmp_serde::to_vec_named(&Newer::Gossip { rumour: 1 }).unwrap();
+        assert!(matches!(
+            decode(&packet(&unknown, &key_manager)),
+            Err(WireError::UnknownMessage(_))
+        ));
+
+        assert!(matches!(
+            decode(&packet(&[0xc1], &key_manager)),
+            Err(WireError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// Allows bursts of up to `capacity` tokens, refilling at `rate` tokens per second
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a bucket that starts out full
+    #[must_use]
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = elapsed.mul_add(self.rate, self.tokens).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Take `amount` tokens if that many are available
+    pub fn try_take(&mut self, amount: f64) -> bool {
+        self.refill();
+
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take `amount` tokens even if they aren't available yet, returning how long to wait
+    /// until they would have been
+    ///
+    /// Amounts larger than the capacity are allowed, the bucket just goes into debt.
+    pub fn reserve(&mut self, amount: f64) -> Duration {
+        self.refill();
+        self.tokens -= amount;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Whether the bucket has refilled completely, meaning it hasn't been used in a while
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// A separate token bucket for every key, such as every source address
+#[derive(Debug)]
+pub struct KeyedRateLimiter<K> {
+    buckets: HashMap<K, TokenBucket>,
+    rate: f64,
+    capacity: f64,
+    max_keys: usize,
+}
+
+impl<K: Eq + Hash + Clone> KeyedRateLimiter<K> {
+    /// Track at most `max_keys` keys, so a flood of distinct keys can't exhaust memory
+    #[must_use]
+    pub fn new(rate: f64, capacity: f64, max_keys: usize) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            rate,
+            capacity,
+            max_keys,
+        }
+    }
+
+    /// Take a token from the bucket of `key`
+    ///
+    /// When `max_keys` are tracked already, the keys that refilled completely are forgotten
+    /// to make room, or else the one closest to refilling, so new keys are never turned
+    /// away.
+    pub fn check(&mut self, key: K) -> bool {
+        if !self.buckets.contains_key(&key) && self.buckets.len() >= self.max_keys {
+            // Idle keys are indistinguishable from new ones, so they can be forgotten
+            self.buckets.retain(|_, bucket| !bucket.is_full());
+
+            if self.buckets.len() >= self.max_keys {
+                // Every bucket was just refilled, and they all refill at the same rate
+                let least_limited = self
+                    .buckets
+                    .iter()
+                    .max_by(|(_, a), (_, b)| a.tokens.total_cmp(&b.tokens))
+                    .map(|(key, _)| key.clone());
+                if let Some(least_limited) = least_limited {
+                    self.buckets.remove(&least_limited);
+                }
+            }
+        }
+
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(self.rate, self.capacity))
+            .try_take(1.0)
+    }
+}
+
+/// Byte rate limits shared by every transfer, overall and for each peer
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    global: Option<Arc<Mutex<TokenBucket>>>,
+    per_peer_rate: Option<u64>,
+    peers: Mutex<HashMap<IpAddr, Arc<Mutex<TokenBucket>>>>,
+}
+
+impl BandwidthLimiter {
+    /// Limit every transfer together to `rate`, and those with one peer to `per_peer_rate`
+    /// bytes per second
+    #[must_use]
+    pub fn new(rate: Option<u64>, per_peer_rate: Option<u64>) -> Self {
+        Self {
+            global: rate.map(|rate| Arc::new(Mutex::new(byte_bucket(rate)))),
+            per_peer_rate,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Limits for transferring with `peer`, at `rate` instead of the per-peer limit if given
+    #[must_use]
+    pub fn limit(&self, peer: IpAddr, rate: Option<u64>) -> RateLimit {
+        // A limit given for one transfer isn't shared with the peer's other transfers
+        let peer_bucket = rate.map_or_else(
+            || self.peer_bucket(peer),
+            |rate| Some(Arc::new(Mutex::new(byte_bucket(rate)))),
+        );
+
+        RateLimit {
+            buckets: self.global.iter().cloned().chain(peer_bucket).collect(),
+        }
+    }
+
+    fn peer_bucket(&self, peer: IpAddr) -> Option<Arc<Mutex<TokenBucket>>> {
+        let rate = self.per_peer_rate?;
+        let mut peers = self.peers.lock();
+
+        // Buckets nobody uses that refilled completely can be recreated later
+        peers.retain(|_, bucket| Arc::strong_count(bucket) > 1 || !bucket.lock().is_full());
+        let bucket = peers
+            .entry(peer.to_canonical())
+            .or_insert_with(|| Arc::new(Mutex::new(byte_bucket(rate))))
+            .clone();
+        drop(peers);
+
+        Some(bucket)
+    }
+}
+
+/// A bucket holding a second's worth of bytes at `rate` bytes per second
+#[allow(clippy::cast_precision_loss)]
+fn byte_bucket(rate: u64) -> TokenBucket {
+    let rate = rate.max(1) as f64;
+    TokenBucket::new(rate, rate)
+}
+
+/// The limits one transfer is subject to, see [`BandwidthLimiter::limit`]
+#[derive(Debug, Clone, Default)]
+pub struct RateLimit {
+    buckets: Vec<Arc<Mutex<TokenBucket>>>,
+}
+
+impl RateLimit {
+    /// Wait until `bytes` may be sent or received
+    pub async fn acquire(&self, bytes: usize) {
+        #[allow(clippy::cast_precision_loss)]
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.lock().reserve(bytes as f64))
+            .max()
+            .unwrap_or_default();
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Pretend `elapsed` passed since the bucket was last used
+    fn wait(bucket: &mut TokenBucket, elapsed: Duration) {
+        bucket.last_refill -= elapsed;
+    }
+
+    #[test]
+    fn bursts_up_to_capacity() {
+        let mut bucket = TokenBucket::new(1.0, 3.0);
+
+        assert!((0..3).all(|_| bucket.try_take(1.0)));
+        assert!(!bucket.try_take(1.0));
+    }
+
+    #[test]
+    fn refills_at_rate_up_to_capacity() {
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+        assert!(bucket.try_take(3.0));
+
+        wait(&mut bucket, Duration::from_secs(1));
+        assert!(bucket.try_take(2.0));
+        assert!(!bucket.try_take(1.0));
+
+        wait(&mut bucket, Duration::from_secs(60));
+        assert!(bucket.is_full());
+        assert!(!bucket.try_take(4.0));
+    }
+
+    #[test]
+    fn reserving_goes_into_debt() {
+        let mut bucket = TokenBucket::new(100.0, 100.0);
+
+        assert_eq!(bucket.reserve(100.0), Duration::ZERO);
+        let delay = bucket.reserve(200.0);
+        assert!(delay > Duration::from_millis(1900) && delay <= Duration::from_secs(2));
+
+        // The debt is paid off before anything else is taken
+        wait(&mut bucket, Duration::from_secs(1));
+        assert!(!bucket.try_take(1.0));
+    }
+
+    #[test]
+    fn limits_each_key_separately() {
+        let mut limiter = KeyedRateLimiter::new(1.0, 1.0, 16);
+
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+        assert!(limiter.check("b"));
+    }
+
+    #[test]
+    fn makes_room_for_new_keys_when_full() {
+        let mut limiter = KeyedRateLimiter::new(1.0, 1.0, 2);
+        assert!(limiter.check("a"));
+        assert!(limiter.check("b"));
+        assert!(!limiter.check("b"));
+
+        // Every key is still being limited, so the one closest to refilling goes
+        assert!(limiter.check("c"));
+        assert_eq!(limiter.buckets.len(), 2);
+        assert!(!limiter.buckets.contains_key("a"));
+        assert!(!limiter.check("b"));
+    }
+
+    #[test]
+    fn forgets_idle_keys_first_when_full() {
+        let mut limiter = KeyedRateLimiter::new(1.0, 1.0, 2);
+        assert!(limiter.check("a"));
+        assert!(limiter.check("b"));
+        wait(
+            limiter.buckets.get_mut("b").unwrap(),
+            Duration::from_secs(1),
+        );
+
+        assert!(limiter.check("c"));
+        assert!(limiter.buckets.contains_key("a"));
+        assert!(!limiter.buckets.contains_key("b"));
+    }
+
+    #[test]
+    fn shares_peer_limits_between_transfers() {
+        let limiter = BandwidthLimiter::new(Some(1000), Some(100));
+        let peer = IpAddr::from(Ipv4Addr::new(192, 168, 1, 2));
+
+        let first = limiter.limit(peer, None);
+        let second = limiter.limit(peer, None);
+        let own_rate = limiter.limit(peer, Some(10));
+
+        assert_eq!(first.buckets.len(), 2);
+        assert!(Arc::ptr_eq(&first.buckets[0], &own_rate.buckets[0]));
+        assert!(Arc::ptr_eq(&first.buckets[1], &second.buckets[1]));
+        assert!(!Arc::ptr_eq(&first.buckets[1], &own_rate.buckets[1]));
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_once_the_burst_is_spent() {
+        let limiter = BandwidthLimiter::new(Some(1000), None);
+        let limit = limiter.limit(IpAddr::from(Ipv4Addr::LOCALHOST), None);
+
+        let start = Instant::now();
+        limit.acquire(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        limit.acquire(200).await;
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
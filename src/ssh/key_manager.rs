@@ -5,14 +5,15 @@ use std::{
 };
 
 use color_eyre::{Result, eyre::eyre};
+use parking_lot::RwLock;
 use ssh_key::{Algorithm, PrivateKey, PublicKey};
 use tracing::info;
 
 pub struct KeyManager {
     private_key: PrivateKey,
     public_key: PublicKey,
-    /// Map of trusted peer IDs to their public keys
-    trusted_keys: HashMap<String, PublicKey>,
+    /// Map of trusted peer IDs to their public keys, added to while running by pairing
+    trusted_keys: RwLock<HashMap<String, PublicKey>>,
     key_dir: PathBuf,
 }
 
@@ -32,6 +33,13 @@ impl KeyManager {
         }
     }
 
+    /// Fresh keys that are never saved, for tests
+    #[cfg(test)]
+    #[must_use]
+    pub fn ephemeral() -> Self {
+        Self::generate_new_keys(Path::new("")).unwrap()
+    }
+
     fn generate_new_keys(key_dir: &Path) -> Result<Self> {
         info!("Generating new SSH key pair...");
 
@@ -42,7 +50,7 @@ impl KeyManager {
         Ok(Self {
             private_key,
             public_key,
-            trusted_keys: HashMap::new(),
+            trusted_keys: RwLock::new(HashMap::new()),
             key_dir: key_dir.to_path_buf(),
         })
     }
@@ -76,7 +84,7 @@ impl KeyManager {
         let key_manager = Self {
             private_key,
             public_key,
-            trusted_keys: HashMap::new(),
+            trusted_keys: RwLock::new(HashMap::new()),
             key_dir: key_dir.to_path_buf(),
         };
 
@@ -134,21 +142,21 @@ impl KeyManager {
     }
 
     /// Add a trusted peer's public key
-    pub fn add_trusted_key(&mut self, peer_id: String, public_key: PublicKey) {
-        self.trusted_keys.insert(peer_id, public_key);
+    pub fn add_trusted_key(&self, peer_id: String, public_key: PublicKey) {
+        self.trusted_keys.write().insert(peer_id, public_key);
         info!("Added trusted key for peer");
     }
 
     /// Get a trusted peer's public key
     #[must_use]
-    pub fn get_trusted_key(&self, peer_id: &str) -> Option<&PublicKey> {
-        self.trusted_keys.get(peer_id)
+    pub fn get_trusted_key(&self, peer_id: &str) -> Option<PublicKey> {
+        self.trusted_keys.read().get(peer_id).cloned()
     }
 
     /// Check if a peer is trusted
     #[must_use]
     pub fn is_peer_trusted(&self, peer_id: &str) -> bool {
-        self.trusted_keys.contains_key(peer_id)
+        self.trusted_keys.read().contains_key(peer_id)
     }
 
     /// Get the key directory path
@@ -180,6 +188,7 @@ impl KeyManager {
         // Convert PublicKey to serializable format
         let trusted_keys_serializable: HashMap<String, String> = self
             .trusted_keys
+            .read()
             .iter()
             .map(|(peer_id, public_key)| {
                 (peer_id.clone(), public_key.to_openssh().unwrap_or_default())
@@ -209,7 +218,7 @@ impl KeyManager {
                     .map_err(|e| eyre!("Failed to parse trusted keys: {}", e))?;
 
             // Convert back to PublicKey objects
-            self.trusted_keys = trusted_keys_serializable
+            *self.trusted_keys.get_mut() = trusted_keys_serializable
                 .into_iter()
                 .filter_map(|(peer_id, key_str)| {
                     PublicKey::from_openssh(&key_str)
@@ -220,7 +229,7 @@ impl KeyManager {
 
             info!(
                 "Loaded {} trusted keys from {:?}",
-                self.trusted_keys.len(),
+                self.trusted_keys.get_mut().len(),
                 trusted_keys_path
             );
         }
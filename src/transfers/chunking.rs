@@ -0,0 +1,169 @@
+use std::time::{Duration, Instant};
+
+/// Chunk size used before throughput was measured
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Smallest chunk sent, below which per-message overhead dominates
+const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Largest chunk sent whatever the receiver allows, so pausing and cancelling take effect
+/// quickly
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// How long sending one chunk should take at the measured throughput
+const TARGET_CHUNK_TIME: Duration = Duration::from_millis(100);
+
+/// Weight of the latest chunk in the smoothed throughput, higher follows changes faster
+const SMOOTHING: f64 = 0.3;
+
+/// Bytes one byte of chunk data takes in a JSON message at most, up to three digits and a
+/// comma. Only `TransferChunk` messages are encoded this way, binary chunks take a byte per
+/// byte, but the limit is kept for peers that send JSON ones
+const ENCODED_BYTE_SIZE: usize = 4;
+
+/// Room left in a frame for the rest of a chunk message
+const MESSAGE_OVERHEAD: usize = 1024;
+
+/// Largest chunk whose message fits in a WebSocket frame of `max_frame_size` bytes, even when
+/// sent as a JSON `TransferChunk`
+#[must_use]
+pub fn max_chunk_size(max_frame_size: Option<usize>) -> usize {
+    max_frame_size.map_or(MAX_CHUNK_SIZE, |size| {
+        (size.saturating_sub(MESSAGE_OVERHEAD) / ENCODED_BYTE_SIZE).min(MAX_CHUNK_SIZE)
+    })
+}
+
+/// Picks the size of the next chunk to send, growing it on fast links and shrinking it on
+/// slow ones while staying within what the receiver takes
+#[derive(Debug, Clone)]
+pub struct ChunkSizer {
+    size: usize,
+    min: usize,
+    max: usize,
+    last_chunk: Instant,
+    bytes_per_second: Option<f64>,
+}
+
+impl ChunkSizer {
+    /// Start at the default size, never exceeding the receiver's largest chunk or its buffer
+    #[must_use]
+    pub fn new(max_chunk_size: usize, buffer_size: usize) -> Self {
+        let max = max_chunk_size.min(buffer_size).clamp(1, MAX_CHUNK_SIZE);
+
+        Self {
+            size: DEFAULT_CHUNK_SIZE.min(max),
+            min: MIN_CHUNK_SIZE.min(max),
+            max,
+            last_chunk: Instant::now(),
+            bytes_per_second: None,
+        }
+    }
+
+    /// Size of the next chunk
+    #[must_use]
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Largest chunk that will be sent
+    #[must_use]
+    pub const fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Record that a chunk of `bytes` was sent, resizing the next ones by how long it took
+    /// since the previous one
+    pub fn record(&mut self, bytes: usize) {
+        let elapsed = self.last_chunk.elapsed();
+        self.last_chunk = Instant::now();
+        if elapsed.is_zero() {
+            return;
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let rate = bytes as f64 / elapsed.as_secs_f64();
+        let rate = self.bytes_per_second.map_or(rate, |smoothed| {
+            SMOOTHING.mul_add(rate, (1.0 - SMOOTHING) * smoothed)
+        });
+        self.bytes_per_second = Some(rate);
+
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            clippy::cast_precision_loss
+        )]
+        let target = (rate * TARGET_CHUNK_TIME.as_secs_f64()) as usize;
+
+        // At most doubling or halving at once, so one slow chunk doesn't swing the size
+        self.size = target
+            .clamp(self.size / 2, self.size.saturating_mul(2))
+            .clamp(self.min, self.max);
+    }
+
+    /// Start timing again after a pause, so the time spent paused doesn't count
+    pub fn resume(&mut self) {
+        self.last_chunk = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Record a chunk of the current size as if sending it took `elapsed`
+    fn sent(sizer: &mut ChunkSizer, elapsed: Duration) {
+        sizer.last_chunk = Instant::now().checked_sub(elapsed).unwrap();
+        sizer.record(sizer.size());
+    }
+
+    #[test]
+    fn stays_within_the_receivers_chunk_and_buffer() {
+        let sizer = ChunkSizer::new(1024 * 1024, 256 * 1024);
+        assert_eq!(sizer.max(), 256 * 1024);
+
+        let sizer = ChunkSizer::new(256 * 1024, 1024 * 1024);
+        assert_eq!(sizer.max(), 256 * 1024);
+
+        let sizer = ChunkSizer::new(usize::MAX, usize::MAX);
+        assert_eq!(sizer.max(), MAX_CHUNK_SIZE);
+
+        let sizer = ChunkSizer::new(8 * 1024, 1024 * 1024);
+        assert_eq!(sizer.size(), 8 * 1024);
+        assert_eq!(sizer.min, 8 * 1024);
+    }
+
+    #[test]
+    fn grows_on_fast_links() {
+        let mut sizer = ChunkSizer::new(MAX_CHUNK_SIZE, usize::MAX);
+
+        sent(&mut sizer, Duration::from_millis(1));
+        assert_eq!(sizer.size(), DEFAULT_CHUNK_SIZE * 2);
+
+        for _ in 0..10 {
+            sent(&mut sizer, Duration::from_millis(1));
+        }
+        assert_eq!(sizer.size(), MAX_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn shrinks_on_slow_links() {
+        let mut sizer = ChunkSizer::new(MAX_CHUNK_SIZE, usize::MAX);
+
+        sent(&mut sizer, Duration::from_secs(10));
+        assert_eq!(sizer.size(), DEFAULT_CHUNK_SIZE / 2);
+
+        for _ in 0..10 {
+            sent(&mut sizer, Duration::from_secs(10));
+        }
+        assert_eq!(sizer.size(), MIN_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn fits_json_chunks_in_a_frame() {
+        let frame = 64 * 1024;
+        let size = max_chunk_size(Some(frame));
+
+        assert!(size * ENCODED_BYTE_SIZE + MESSAGE_OVERHEAD <= frame);
+        assert_eq!(max_chunk_size(None), MAX_CHUNK_SIZE);
+    }
+}
@@ -0,0 +1,131 @@
+use std::path::Path;
+
+use color_eyre::{Result, eyre::eyre};
+use serde::{Deserialize, Serialize};
+
+/// zstd level chunks are compressed at, fast enough to keep up with a gigabit link
+const ZSTD_LEVEL: i32 = 3;
+
+/// Extensions of files whose contents are compressed already
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz4",
+    "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus", "png", "rar", "tgz", "webm", "webp", "xlsx",
+    "xz", "zip", "zst",
+];
+
+/// How chunks can be compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+}
+
+/// Compressions this end can decompress, in order of preference
+pub const SUPPORTED: &[Compression] = &[Compression::Zstd];
+
+impl Compression {
+    /// Compress a chunk, `None` if that doesn't make it smaller
+    pub fn compress(self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let compressed = match self {
+            Self::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)?,
+        };
+
+        Ok((compressed.len() < data.len()).then_some(compressed))
+    }
+
+    /// Decompress a chunk, failing if it is larger than `max_size` once decompressed
+    pub fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::bulk::decompress(data, max_size)
+                .map_err(|e| eyre!("Failed to decompress a chunk: {}", e)),
+        }
+    }
+}
+
+/// The first of the compressions the receiver offers that this end supports, unless
+/// `mime_type` or the extension of `path` says the file is compressed already
+#[must_use]
+pub fn choose(offered: &[Compression], path: &Path, mime_type: &str) -> Option<Compression> {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let is_compressed = COMPRESSED_EXTENSIONS.contains(&extension.as_str())
+        || ["image/", "audio/", "video/"]
+            .iter()
+            .any(|prefix| mime_type.starts_with(prefix))
+        || matches!(mime_type, "application/zip" | "application/gzip");
+
+    if is_compressed {
+        return None;
+    }
+    SUPPORTED
+        .iter()
+        .copied()
+        .find(|compression| offered.contains(compression))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chunk that compresses well
+    fn text(len: usize) -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(len)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = text(64 * 1024);
+        let compressed = Compression::Zstd.compress(&data).unwrap().unwrap();
+
+        assert!(compressed.len() < data.len());
+        assert_eq!(
+            Compression::Zstd
+                .decompress(&compressed, data.len())
+                .unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn decompress_rejects_chunks_over_max_size() {
+        let data = text(64 * 1024);
+        let compressed = Compression::Zstd.compress(&data).unwrap().unwrap();
+
+        assert!(
+            Compression::Zstd
+                .decompress(&compressed, data.len() - 1)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn sends_chunks_that_dont_shrink_as_they_are() {
+        let data: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+
+        assert!(Compression::Zstd.compress(&data).unwrap().is_none());
+        assert!(Compression::Zstd.compress(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn skips_files_compressed_already() {
+        let choose = |path: &str, mime_type: &str| choose(SUPPORTED, Path::new(path), mime_type);
+
+        assert_eq!(choose("notes.txt", "text/plain"), Some(Compression::Zstd));
+        assert_eq!(choose("backup.TAR.GZ", "application/octet-stream"), None);
+        assert_eq!(choose("photo", "image/heic"), None);
+        assert_eq!(choose("clip", "video/mp4"), None);
+        assert_eq!(choose("archive", "application/zip"), None);
+    }
+
+    #[test]
+    fn only_uses_compressions_the_receiver_offers() {
+        assert_eq!(choose(&[], Path::new("notes.txt"), "text/plain"), None);
+    }
+}
@@ -0,0 +1,254 @@
+use std::path::{Component, Path, PathBuf};
+
+use color_eyre::{Result, eyre::eyre};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::downloads::DownloadsConfig,
+    transfers::{TransferId, offers::Offer},
+};
+
+/// Suffix of files still being downloaded
+const PART_SUFFIX: &str = ".part";
+
+/// Download settings for one file, from its extension's config or the primary one
+struct FileSettings<'a> {
+    directory: &'a Path,
+    data_limit: Option<u64>,
+    auto_download: bool,
+    partial_downloads: bool,
+    hash_checking: bool,
+}
+
+fn settings<'a>(config: &'a DownloadsConfig, filename: &Path) -> FileSettings<'a> {
+    let extension_config = extension(filename).and_then(|ext| config.extension_configs.get(&ext));
+
+    extension_config.map_or(
+        FileSettings {
+            directory: &config.primary.directory,
+            data_limit: config.primary.data_limit,
+            auto_download: config.primary.auto_download,
+            partial_downloads: config.primary.partial_downloads,
+            hash_checking: config.primary.hash_checking,
+        },
+        |ext| FileSettings {
+            directory: &ext.directory,
+            data_limit: ext.file_data_limit,
+            auto_download: ext.auto_download,
+            partial_downloads: ext.partial_downloads,
+            hash_checking: ext.hash_checking,
+        },
+    )
+}
+
+fn extension(filename: &Path) -> Option<String> {
+    filename
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+}
+
+/// Turn an offered filename into a relative path, refusing anything that would escape
+/// the download directory
+pub fn relative_path(filename: &str) -> Result<PathBuf> {
+    let path: PathBuf = filename.split(['/', '\\']).collect();
+
+    let is_safe = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !is_safe || path.as_os_str().is_empty() {
+        return Err(eyre!("Unsafe filename offered: {filename}"));
+    }
+
+    Ok(path)
+}
+
+/// Check an offer against the allowed and blocked extensions and the size limits
+pub fn check_offer(config: &DownloadsConfig, filename: &str, size: u64) -> Result<()> {
+    let path = relative_path(filename)?;
+    let extension = extension(&path).unwrap_or_default();
+
+    if config.blocked_extensions.contains(&extension)
+        || (!config.allowed_extensions.is_empty()
+            && !config.allowed_extensions.contains(&extension))
+    {
+        return Err(eyre!("Files with this extension aren't accepted"));
+    }
+
+    if let Some(limit) = settings(config, &path).data_limit
+        && size > limit
+    {
+        return Err(eyre!("File is larger than the {limit} byte limit"));
+    }
+
+    Ok(())
+}
+
+/// Whether an offer may be downloaded without asking, see [`check_offer`] for whether it
+/// may be downloaded at all
+#[must_use]
+pub fn auto_accepts(config: &DownloadsConfig, filename: &str) -> bool {
+    relative_path(filename).is_ok_and(|path| settings(config, &path).auto_download)
+}
+
+/// A file being received, written to a `.part` file until it is complete
+pub struct Download {
+    pub id: TransferId,
+    pub size: u64,
+    pub received: u64,
+    expected_hash: String,
+    hash_checking: bool,
+    /// Whether to keep what was received of a cancelled download
+    keep_partial: bool,
+    path: PathBuf,
+    part_path: PathBuf,
+    file: File,
+    hasher: Sha256,
+}
+
+impl Download {
+    /// Create the file an accepted offer is saved to, next to any file of the same name
+    pub async fn create(config: &DownloadsConfig, offer: &Offer) -> Result<Self> {
+        let relative = relative_path(&offer.filename)?;
+        let settings = settings(config, &relative);
+
+        let path = unused_path(&settings.directory.join(relative)).await;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| eyre!("Failed to create download directory: {}", e))?;
+        }
+
+        let part_path = part_path(&path);
+        let file = File::create(&part_path)
+            .await
+            .map_err(|e| eyre!("Failed to create {}: {}", part_path.display(), e))?;
+
+        Ok(Self {
+            id: offer.id.clone(),
+            size: offer.size,
+            received: 0,
+            expected_hash: offer.hash.clone(),
+            hash_checking: settings.hash_checking,
+            keep_partial: settings.partial_downloads,
+            path,
+            part_path,
+            file,
+            hasher: Sha256::new(),
+        })
+    }
+
+    /// Append a chunk, which has to start where the previous one ended
+    pub async fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if offset != self.received {
+            return Err(eyre!(
+                "Expected a chunk at offset {}, got one at {}",
+                self.received,
+                offset
+            ));
+        }
+        if self.received + data.len() as u64 > self.size {
+            return Err(eyre!("Received more than the {} bytes offered", self.size));
+        }
+
+        self.file.write_all(data).await?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Check the file is complete and move it into place, returning where it was saved and
+    /// the hash of what was received
+    pub async fn finish(mut self, hash: &str) -> Result<(PathBuf, String)> {
+        self.file.flush().await?;
+
+        if self.received != self.size {
+            let error = eyre!(
+                "Transfer ended after {} of {} bytes",
+                self.received,
+                self.size
+            );
+            self.abort().await;
+            return Err(error);
+        }
+
+        let actual_hash = format!("{:x}", self.hasher.clone().finalize());
+        if actual_hash != hash || actual_hash != self.expected_hash {
+            if self.hash_checking {
+                self.abort().await;
+                return Err(eyre!("Hash mismatch, the download was discarded"));
+            }
+            warn!("Hash mismatch for {}", self.path.display());
+        }
+
+        fs::rename(&self.part_path, &self.path)
+            .await
+            .map_err(|e| eyre!("Failed to move download into place: {}", e))?;
+
+        info!("Saved {}", self.path.display());
+        Ok((self.path, actual_hash))
+    }
+
+    /// Stop downloading, keeping the partial file if partial downloads are enabled
+    pub async fn cancel(mut self) {
+        if !self.keep_partial {
+            self.abort().await;
+            return;
+        }
+
+        if let Err(e) = self.file.flush().await {
+            warn!("Failed to write {}: {}", self.part_path.display(), e);
+        }
+        info!(
+            "Kept {} of {} bytes in {}",
+            self.received,
+            self.size,
+            self.part_path.display()
+        );
+    }
+
+    /// Delete the partial file
+    pub async fn abort(self) {
+        drop(self.file);
+        if let Err(e) = fs::remove_file(&self.part_path).await {
+            warn!(
+                "Failed to remove partial download {}: {}",
+                self.part_path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(PART_SUFFIX);
+    PathBuf::from(part_path)
+}
+
+/// `path`, or `name (n).ext` if a file or partial download already exists there
+async fn unused_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut candidate = path.to_path_buf();
+    let mut n = 1;
+    while fs::try_exists(&candidate).await.unwrap_or(false)
+        || fs::try_exists(part_path(&candidate)).await.unwrap_or(false)
+    {
+        candidate = path.with_file_name(format!("{stem} ({n}){extension}"));
+        n += 1;
+    }
+
+    candidate
+}
@@ -0,0 +1,71 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{Result, eyre::eyre};
+use tracing::warn;
+
+use crate::transfers::tracker::Transfer;
+
+const LOG_FILE: &str = "transfers.jsonl";
+
+/// Every finished transfer, one JSON object per line in the data directory
+pub struct TransferLog {
+    path: PathBuf,
+}
+
+impl TransferLog {
+    #[must_use]
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            path: data_dir.join(LOG_FILE),
+        }
+    }
+
+    pub fn append(&self, transfer: &Transfer) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| eyre!("Failed to create data directory: {}", e))?;
+        }
+
+        let mut line = serde_json::to_vec(transfer)?;
+        line.push(b'\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .map_err(|e| eyre!("Failed to write transfer history: {}", e))
+    }
+
+    /// Read every transfer logged so far, oldest first, skipping lines that can't be parsed
+    pub fn load(&self) -> Result<Vec<Transfer>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let log = fs::read_to_string(&self.path)
+            .map_err(|e| eyre!("Failed to read transfer history: {}", e))?;
+
+        Ok(log
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .filter_map(|(number, line)| {
+                serde_json::from_str(line)
+                    .inspect_err(|e| {
+                        warn!(
+                            "Skipping line {} of {}: {}",
+                            number + 1,
+                            self.path.display(),
+                            e
+                        );
+                    })
+                    .ok()
+            })
+            .collect())
+    }
+}
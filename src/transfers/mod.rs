@@ -0,0 +1,11 @@
+pub mod chunking;
+pub mod compression;
+pub mod download;
+pub mod history;
+pub mod offers;
+pub mod progress;
+pub mod tracker;
+pub mod upload;
+
+/// Identifies a transfer on both ends, a UUID chosen by the sender
+pub type TransferId = String;
@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+
+use color_eyre::{Result, eyre::eyre};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::{network_discovery::udp_broadcast::PeerId, transfers::TransferId};
+
+/// A file a peer wants to send us, waiting to be accepted or rejected
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Offer {
+    pub id: TransferId,
+    pub filename: String,
+    pub size: u64,
+    pub hash: String,
+    pub mime_type: String,
+    /// Address the offer came from
+    pub from: SocketAddr,
+    /// The discovered peer at that address, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// When the offer was received, as a Unix timestamp
+    pub received_at: u64,
+}
+
+/// What the user decided to do with an offer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    Reject { reason: Option<String> },
+}
+
+/// Offers waiting for `alacrite accept` or `alacrite reject`
+pub struct OfferQueue {
+    pending: Mutex<Vec<(Offer, oneshot::Sender<Decision>)>>,
+    max_length: usize,
+}
+
+impl OfferQueue {
+    #[must_use]
+    pub const fn new(max_length: usize) -> Self {
+        Self {
+            pending: Mutex::new(Vec::new()),
+            max_length,
+        }
+    }
+
+    /// Queue an offer, returning where the decision will be sent
+    ///
+    /// Fails when `max_length` offers are already waiting.
+    pub fn push(&self, offer: Offer) -> Result<oneshot::Receiver<Decision>> {
+        let mut pending = self.pending.lock();
+        if pending.len() >= self.max_length {
+            return Err(eyre!(
+                "Too many offers waiting to be accepted ({})",
+                pending.len()
+            ));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        pending.push((offer, sender));
+        drop(pending);
+        Ok(receiver)
+    }
+
+    /// Every offer waiting for a decision, oldest first
+    #[must_use]
+    pub fn list(&self) -> Vec<Offer> {
+        self.pending
+            .lock()
+            .iter()
+            .map(|(offer, _)| offer.clone())
+            .collect()
+    }
+
+    /// Decide on the offer whose id starts with `query`, or the only offer waiting when
+    /// there is no query
+    pub fn decide(&self, query: Option<&str>, decision: Decision) -> Result<Offer> {
+        let mut pending = self.pending.lock();
+
+        let matches: Vec<usize> = pending
+            .iter()
+            .enumerate()
+            .filter(|(_, (offer, _))| query.is_none_or(|query| offer.id.starts_with(query)))
+            .map(|(index, _)| index)
+            .collect();
+
+        let index = match (matches.as_slice(), query) {
+            ([index], _) => *index,
+            ([], Some(query)) => return Err(eyre!("No offer matches {query}")),
+            ([], None) => return Err(eyre!("No offers are waiting")),
+            (_, Some(query)) => {
+                return Err(eyre!(
+                    "{} offers match {query}, use a longer id to pick one",
+                    matches.len()
+                ));
+            }
+            (_, None) => {
+                return Err(eyre!(
+                    "{} offers are waiting, pick one by id",
+                    matches.len()
+                ));
+            }
+        };
+
+        let (offer, sender) = pending.remove(index);
+        drop(pending);
+        sender
+            .send(decision)
+            .map_err(|_| eyre!("{} was withdrawn by the sender", offer.filename))?;
+        Ok(offer)
+    }
+
+    /// Drop an offer that timed out or whose sender went away
+    pub fn remove(&self, id: &str) {
+        self.pending.lock().retain(|(offer, _)| offer.id != id);
+    }
+}
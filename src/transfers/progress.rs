@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+/// How often throughput is sampled, which is also how often progress is published
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Weight of the latest sample in the smoothed throughput, higher follows changes faster
+const SMOOTHING: f64 = 0.3;
+
+/// Smoothed throughput of a running transfer, fed the bytes done after every chunk
+#[derive(Debug, Clone)]
+pub struct ProgressMeter {
+    last_sample: Instant,
+    last_transferred: u64,
+    bytes_per_second: Option<f64>,
+}
+
+impl ProgressMeter {
+    #[must_use]
+    pub fn new(transferred: u64) -> Self {
+        Self {
+            last_sample: Instant::now(),
+            last_transferred: transferred,
+            bytes_per_second: None,
+        }
+    }
+
+    /// Record the bytes done so far, returning whether a new sample was taken
+    pub fn record(&mut self, transferred: u64) -> bool {
+        let elapsed = self.last_sample.elapsed();
+        if elapsed < SAMPLE_INTERVAL {
+            return false;
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let rate = transferred.saturating_sub(self.last_transferred) as f64 / elapsed.as_secs_f64();
+        self.bytes_per_second = Some(self.bytes_per_second.map_or(rate, |smoothed| {
+            SMOOTHING.mul_add(rate, (1.0 - SMOOTHING) * smoothed)
+        }));
+        self.last_sample = Instant::now();
+        self.last_transferred = transferred;
+        true
+    }
+
+    /// Start sampling again after a pause, so the time spent paused doesn't count
+    pub fn resume(&mut self, transferred: u64) {
+        self.last_sample = Instant::now();
+        self.last_transferred = transferred;
+    }
+
+    #[must_use]
+    pub const fn bytes_per_second(&self) -> Option<f64> {
+        self.bytes_per_second
+    }
+
+    /// Seconds until `remaining` bytes are done at the current throughput
+    #[must_use]
+    pub fn eta_secs(&self, remaining: u64) -> Option<u64> {
+        let bytes_per_second = self.bytes_per_second.filter(|rate| *rate > 0.0)?;
+
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            clippy::cast_precision_loss
+        )]
+        Some((remaining as f64 / bytes_per_second).ceil() as u64)
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use color_eyre::{Result, eyre::eyre};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use tracing::warn;
+
+use crate::{
+    network_discovery::udp_broadcast::{PeerId, PeerInfo},
+    transfers::{
+        TransferId, history::TransferLog, offers::Offer, progress::ProgressMeter, upload::Upload,
+    },
+};
+
+/// Finished transfers kept in memory for `alacrite transfers`, older ones are only in
+/// the history
+const MAX_FINISHED_TRANSFERS: usize = 100;
+
+/// Events kept for subscribers that fall behind, older ones are dropped
+const EVENT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Files we send
+    #[value(alias = "out", alias = "sent")]
+    Outgoing,
+    /// Files we receive
+    #[value(alias = "in", alias = "received")]
+    Incoming,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferState {
+    /// Offered and waiting to be accepted, or waiting for earlier files to be sent
+    Queued,
+    Active,
+    /// Paused by either end, until either end resumes it
+    Paused,
+    Completed,
+    Rejected,
+    Cancelled,
+    Failed,
+}
+
+impl TransferState {
+    #[must_use]
+    pub const fn is_finished(self) -> bool {
+        matches!(
+            self,
+            Self::Completed | Self::Rejected | Self::Cancelled | Self::Failed
+        )
+    }
+}
+
+/// What the user asked of a transfer, carried out by the connection it runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferAction {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// Whether the file arrived with the hash it was offered with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashStatus {
+    #[default]
+    Pending,
+    Verified,
+    Mismatch,
+}
+
+/// A file being sent or received, or one that was
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transfer {
+    pub id: TransferId,
+    pub direction: Direction,
+    pub filename: String,
+    pub size: u64,
+    /// Bytes sent or received so far
+    pub transferred: u64,
+    pub state: TransferState,
+    /// Address of the other end
+    pub address: SocketAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default)]
+    pub hash_status: HashStatus,
+    /// Why the transfer was rejected or failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Where a received file was saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// When the transfer was queued, as a Unix timestamp
+    pub queued_at: u64,
+    /// When the transfer finished, as a Unix timestamp
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    /// Smoothed bytes per second while running, the average once finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_per_second: Option<f64>,
+    /// Seconds left at the current throughput, while active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<u64>,
+    #[serde(skip)]
+    started: Option<Instant>,
+    #[serde(skip)]
+    meter: Option<ProgressMeter>,
+    #[serde(skip)]
+    actions: Option<mpsc::UnboundedSender<TransferAction>>,
+}
+
+impl Transfer {
+    /// A file we are about to offer to `peer` at `address`
+    #[must_use]
+    pub fn outgoing(upload: &Upload, address: SocketAddr, peer: Option<&PeerInfo>) -> Self {
+        Self {
+            id: upload.id.clone(),
+            direction: Direction::Outgoing,
+            filename: upload.name.clone(),
+            size: upload.size,
+            address,
+            peer: peer.map(|peer| peer.id.clone()),
+            hostname: peer.map(|peer| peer.hostname.clone()),
+            ..Self::queued()
+        }
+    }
+
+    /// A file offered to us
+    #[must_use]
+    pub fn incoming(offer: &Offer) -> Self {
+        Self {
+            id: offer.id.clone(),
+            direction: Direction::Incoming,
+            filename: offer.filename.clone(),
+            size: offer.size,
+            address: offer.from,
+            peer: offer.peer.clone(),
+            hostname: offer.hostname.clone(),
+            hash: Some(offer.hash.clone()),
+            ..Self::queued()
+        }
+    }
+
+    fn queued() -> Self {
+        Self {
+            id: TransferId::new(),
+            direction: Direction::Outgoing,
+            filename: String::new(),
+            size: 0,
+            transferred: 0,
+            state: TransferState::Queued,
+            address: SocketAddr::from(([0, 0, 0, 0], 0)),
+            peer: None,
+            hostname: None,
+            hash: None,
+            hash_status: HashStatus::Pending,
+            error: None,
+            path: None,
+            queued_at: now(),
+            finished_at: None,
+            bytes_per_second: None,
+            eta_secs: None,
+            started: None,
+            meter: None,
+            actions: None,
+        }
+    }
+
+    /// Name of the other end, its hostname if it was discovered
+    #[must_use]
+    pub fn peer_name(&self) -> String {
+        self.hostname
+            .clone()
+            .unwrap_or_else(|| self.address.ip().to_canonical().to_string())
+    }
+
+    /// Bytes per second since the first byte, including any time spent paused
+    fn average_speed(&self) -> Option<f64> {
+        let elapsed = self.started?.elapsed().as_secs_f64();
+
+        #[allow(clippy::cast_precision_loss)]
+        (elapsed > 0.0).then(|| self.transferred as f64 / elapsed)
+    }
+}
+
+/// Changes to transfers, for following them through the control socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", content = "transfer", rename_all = "lowercase")]
+pub enum TransferEvent {
+    Queued(Transfer),
+    /// Started, paused or resumed
+    Updated(Transfer),
+    /// Bytes done, throughput and ETA, published a few times a second at most
+    Progress(Transfer),
+    /// Completed, rejected, cancelled or failed
+    Finished(Transfer),
+}
+
+impl TransferEvent {
+    #[must_use]
+    pub const fn transfer(&self) -> &Transfer {
+        match self {
+            Self::Queued(transfer)
+            | Self::Updated(transfer)
+            | Self::Progress(transfer)
+            | Self::Finished(transfer) => transfer,
+        }
+    }
+}
+
+/// Transfers of this session, every finished one also written to the [`TransferLog`]
+pub struct TransferTracker {
+    transfers: Mutex<Vec<Transfer>>,
+    log: TransferLog,
+    events: broadcast::Sender<TransferEvent>,
+}
+
+impl TransferTracker {
+    #[must_use]
+    pub fn new(log: TransferLog) -> Self {
+        Self {
+            transfers: Mutex::new(Vec::new()),
+            log,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Get notified of transfers being queued, progressing and finishing from now on
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<TransferEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, event: TransferEvent) {
+        // Nobody may be listening
+        let _ = self.events.send(event);
+    }
+
+    /// Add a transfer, returning the actions the user asks of it through [`Self::control`]
+    pub fn insert(&self, mut transfer: Transfer) -> mpsc::UnboundedReceiver<TransferAction> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        transfer.actions = Some(sender);
+        self.transfers.lock().push(transfer.clone());
+        self.publish(TransferEvent::Queued(transfer));
+        receiver
+    }
+
+    /// Change a transfer that hasn't finished yet, returning it as changed
+    pub fn update(&self, id: &str, update: impl FnOnce(&mut Transfer)) -> Option<Transfer> {
+        let mut transfers = self.transfers.lock();
+        let transfer = transfers
+            .iter_mut()
+            .find(|transfer| transfer.id == id && !transfer.state.is_finished())?;
+
+        update(transfer);
+        let updated = transfer.clone();
+        drop(transfers);
+        Some(updated)
+    }
+
+    /// Mark a transfer as accepted, with its first byte about to be sent
+    pub fn start(&self, id: &str) {
+        let started = self.update(id, |transfer| {
+            transfer.state = TransferState::Active;
+            transfer.started = Some(Instant::now());
+            transfer.meter = Some(ProgressMeter::new(transfer.transferred));
+        });
+
+        if let Some(transfer) = started {
+            self.publish(TransferEvent::Updated(transfer));
+        }
+    }
+
+    /// Mark a transfer as paused or running again, by either end
+    pub fn pause(&self, id: &str, paused: bool) {
+        let changed = self.update(id, |transfer| {
+            if paused {
+                transfer.state = TransferState::Paused;
+                transfer.eta_secs = None;
+            } else {
+                transfer.state = TransferState::Active;
+                if let Some(meter) = &mut transfer.meter {
+                    meter.resume(transfer.transferred);
+                }
+            }
+        });
+
+        if let Some(transfer) = changed {
+            self.publish(TransferEvent::Updated(transfer));
+        }
+    }
+
+    /// Ask the connection running a transfer to pause, resume or cancel it, by id prefix
+    /// or the only unfinished transfer
+    ///
+    /// Cancelled transfers are finished right away, the connection only has to tell the
+    /// other end and clean up.
+    pub fn control(&self, query: Option<&str>, action: TransferAction) -> Result<Transfer> {
+        let transfer = self.find_unfinished(query)?;
+
+        match (action, transfer.state) {
+            (TransferAction::Pause, TransferState::Active)
+            | (TransferAction::Resume, TransferState::Paused)
+            | (TransferAction::Cancel, _) => {}
+            (TransferAction::Pause, TransferState::Paused) => {
+                return Err(eyre!("{} is already paused", transfer.filename));
+            }
+            (TransferAction::Pause, _) => {
+                return Err(eyre!("{} isn't being transferred", transfer.filename));
+            }
+            (TransferAction::Resume, _) => {
+                return Err(eyre!("{} isn't paused", transfer.filename));
+            }
+        }
+
+        if let Some(actions) = &transfer.actions {
+            // The connection may have just finished the transfer
+            let _ = actions.send(action);
+        }
+
+        if action == TransferAction::Cancel {
+            self.finish(&transfer.id, TransferState::Cancelled, None);
+        }
+
+        Ok(transfer)
+    }
+
+    fn find_unfinished(&self, query: Option<&str>) -> Result<Transfer> {
+        let matches: Vec<Transfer> = self
+            .transfers
+            .lock()
+            .iter()
+            .filter(|transfer| {
+                !transfer.state.is_finished()
+                    && query.is_none_or(|query| transfer.id.starts_with(query))
+            })
+            .cloned()
+            .collect();
+
+        match (matches.as_slice(), query) {
+            ([transfer], _) => Ok(transfer.clone()),
+            ([], Some(query)) => Err(eyre!("No unfinished transfer matches {query}")),
+            ([], None) => Err(eyre!("No transfers are queued or running")),
+            (_, Some(query)) => Err(eyre!(
+                "{} transfers match {query}, use a longer id to pick one",
+                matches.len()
+            )),
+            (_, None) => Err(eyre!(
+                "{} transfers are queued or running, pick one by id",
+                matches.len()
+            )),
+        }
+    }
+
+    /// Record how many bytes were sent or received so far, publishing the progress every
+    /// time throughput is sampled
+    pub fn progress(&self, id: &str, transferred: u64) {
+        let mut sampled = false;
+        let transfer = self.update(id, |transfer| {
+            transfer.transferred = transferred;
+
+            let remaining = transfer.size.saturating_sub(transferred);
+            if let Some(meter) = &mut transfer.meter
+                && meter.record(transferred)
+            {
+                sampled = true;
+                transfer.bytes_per_second = meter.bytes_per_second();
+                transfer.eta_secs = meter.eta_secs(remaining);
+            }
+        });
+
+        if sampled && let Some(transfer) = transfer {
+            self.publish(TransferEvent::Progress(transfer));
+        }
+    }
+
+    /// Finish a transfer and add it to the history, unless it already finished
+    pub fn finish(&self, id: &str, state: TransferState, error: Option<String>) {
+        let mut transfers = self.transfers.lock();
+        let Some(transfer) = transfers
+            .iter_mut()
+            .find(|transfer| transfer.id == id && !transfer.state.is_finished())
+        else {
+            return;
+        };
+
+        transfer.bytes_per_second = transfer.average_speed();
+        transfer.state = state;
+        transfer.error = error;
+        transfer.eta_secs = None;
+        transfer.finished_at = Some(now());
+        transfer.meter = None;
+        transfer.actions = None;
+        let finished = transfer.clone();
+
+        let finished_count = transfers
+            .iter()
+            .filter(|transfer| transfer.state.is_finished())
+            .count();
+        if finished_count > MAX_FINISHED_TRANSFERS
+            && let Some(oldest) = transfers
+                .iter()
+                .position(|transfer| transfer.state.is_finished())
+        {
+            transfers.remove(oldest);
+        }
+        drop(transfers);
+
+        if let Err(e) = self.log.append(&finished) {
+            warn!("Failed to record {} in the transfer history: {}", id, e);
+        }
+        self.publish(TransferEvent::Finished(finished));
+    }
+
+    /// Every transfer of this session, in the order they were queued
+    #[must_use]
+    pub fn list(&self) -> Vec<Transfer> {
+        self.transfers.lock().clone()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
@@ -0,0 +1,133 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{Result, eyre::eyre};
+use sha2::{Digest, Sha256};
+use tokio::{fs::File, io::AsyncReadExt};
+use uuid::Uuid;
+
+use crate::transfers::TransferId;
+
+/// Size of the reads files are hashed in
+const HASH_BUFFER_SIZE: usize = 256 * 1024;
+
+/// A file to offer to a peer
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub id: TransferId,
+    pub path: PathBuf,
+    /// Name the file is offered under, relative to the directory being sent
+    pub name: String,
+    pub size: u64,
+}
+
+/// List the files to send for `paths`, walking into directories
+///
+/// Files inside a directory are named by their path from the directory's parent, so the
+/// receiver recreates the directory.
+pub fn collect(paths: &[PathBuf]) -> Result<Vec<Upload>> {
+    let mut uploads = Vec::new();
+
+    for path in paths {
+        let metadata =
+            fs::metadata(path).map_err(|e| eyre!("Can't send {}: {}", path.display(), e))?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+        if metadata.is_dir() {
+            collect_dir(path, base, &mut uploads)?;
+        } else {
+            uploads.push(Upload {
+                id: Uuid::new_v4().to_string(),
+                path: path.clone(),
+                name: offered_name(path, base)?,
+                size: metadata.len(),
+            });
+        }
+    }
+
+    Ok(uploads)
+}
+
+fn collect_dir(dir: &Path, base: &Path, uploads: &mut Vec<Upload>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .map_err(|e| eyre!("Can't read {}: {}", dir.display(), e))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(fs::DirEntry::path);
+
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            collect_dir(&path, base, uploads)?;
+        } else if file_type.is_file() {
+            uploads.push(Upload {
+                id: Uuid::new_v4().to_string(),
+                name: offered_name(&path, base)?,
+                size: entry.metadata()?.len(),
+                path,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn offered_name(path: &Path, base: &Path) -> Result<String> {
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    let parts: Vec<String> = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+
+    if parts.is_empty() {
+        return Err(eyre!("Can't send {}", path.display()));
+    }
+    Ok(parts.join("/"))
+}
+
+/// SHA-256 of a file's contents, as lowercase hex
+pub async fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .await
+        .map_err(|e| eyre!("Failed to open {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Guess a MIME type from a file's extension, for the receiver to decide on the offer by
+#[must_use]
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "txt" | "log" | "md" => "text/plain",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,67 @@
+//! Challenge-response authentication of connecting peers
+//!
+//! After the hellos, the receiving end sends a random nonce. The connecting end signs it
+//! with its Ed25519 key along with the receiving end's id and both hellos, so a receiver
+//! can't pass the challenge of another peer on and get in there as the connecting end. The
+//! signature has to match the trusted key of the peer id the connecting end said hello with.
+
+use color_eyre::{Result, eyre::eyre};
+use ssh_key::{HashAlg, LineEnding, SshSig};
+
+use crate::{ssh::key_manager::KeyManager, websockets::hello::Hello};
+
+/// Namespace of the signatures, so they can't be passed off as signatures of anything else
+const NAMESPACE: &str = "alacrite-auth";
+const NONCE_LEN: usize = 32;
+
+/// A fresh nonce for the connecting peer to sign
+#[must_use]
+pub fn challenge() -> Vec<u8> {
+    rand::random::<[u8; NONCE_LEN]>().to_vec()
+}
+
+/// What the connecting end signs: the nonce, the id of the end it reached and both hellos,
+/// each prefixed with its length
+pub fn transcript(nonce: &[u8], connecting: &Hello, receiving: &Hello) -> Result<Vec<u8>> {
+    let receiver = receiving.peer_id.as_deref().unwrap_or_default();
+    let parts = [
+        nonce.to_vec(),
+        receiver.as_bytes().to_vec(),
+        serde_json::to_vec(connecting)?,
+        serde_json::to_vec(receiving)?,
+    ];
+
+    let mut transcript = Vec::new();
+    for part in parts {
+        transcript.extend_from_slice(&(part.len() as u64).to_be_bytes());
+        transcript.extend_from_slice(&part);
+    }
+    Ok(transcript)
+}
+
+/// Sign a transcript with our private key
+pub fn sign(key_manager: &KeyManager, transcript: &[u8]) -> Result<String> {
+    let signature = key_manager
+        .get_private_key()
+        .sign(NAMESPACE, HashAlg::Sha512, transcript)?;
+    Ok(signature.to_pem(LineEnding::LF)?)
+}
+
+/// Check that `signature` over `transcript` was made with the trusted key of `peer_id`
+pub fn verify(
+    key_manager: &KeyManager,
+    peer_id: Option<&str>,
+    transcript: &[u8],
+    signature: &str,
+) -> Result<()> {
+    let peer_id = peer_id.ok_or_else(|| eyre!("The peer didn't say hello with its id"))?;
+    let public_key = key_manager
+        .get_trusted_key(peer_id)
+        .ok_or_else(|| eyre!("{peer_id} isn't a trusted peer"))?;
+
+    let signature =
+        SshSig::from_pem(signature).map_err(|e| eyre!("Invalid signature from {peer_id}: {e}"))?;
+    public_key
+        .verify(NAMESPACE, transcript, &signature)
+        .map_err(|_| eyre!("{peer_id} didn't sign with its trusted key"))
+}
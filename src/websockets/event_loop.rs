@@ -1,75 +1,100 @@
-use std::time::Duration;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use color_eyre::Result;
-use tokio::{net::TcpListener, time::sleep};
-use tokio_tungstenite::{accept_async, connect_async};
-use tracing::{error, info};
+use color_eyre::{Result, eyre::eyre};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+use tokio_tungstenite::accept_async;
+use tracing::{error, info, warn};
 
-use crate::websockets::handlers::{
-    client::handle_client_connection, server::handle_server_connection,
+use crate::{
+    daemon::Daemon, ssh::key_manager::KeyManager,
+    websockets::handlers::server::handle_server_connection,
 };
 
-pub async fn run_event_loop(websocket_client_url: Option<String>, ws_port: u16) -> Result<()> {
-    if let Some(websocket_client_url) = websocket_client_url {
-        let url = format!("ws://{websocket_client_url}");
-
-        // Try to connect with retries (useful after UDP discovery)
-        let mut retries = 5;
-        let mut connected = false;
+/// How long to wait after failing to accept a connection, such as when out of file
+/// descriptors, before accepting the next one
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
-        while retries > 0 && !connected {
-            match connect_async(url.clone()).await {
-                Ok((ws_stream, _)) => {
-                    info!("Connected as client to {}", url);
-                    handle_client_connection(ws_stream).await?;
-                    connected = true;
-                }
-                Err(e) => {
-                    retries -= 1;
-                    if retries > 0 {
-                        info!(
-                            "Failed to connect as client: {}. Retrying in 2s... ({retries} retries left)",
-                            e
-                        );
-                        sleep(Duration::from_secs(2)).await;
-                    } else {
-                        info!(
-                            "Failed to connect as client after all retries: {}. Becoming server...",
-                            e
-                        );
-                    }
-                }
-            }
-        }
+/// Accept WebSocket connections on `ws_port` from peers whose keys `key_manager` trusts,
+/// taking file offers only with a `daemon`
+pub async fn host_server(
+    ws_port: u16,
+    key_manager: Arc<KeyManager>,
+    daemon: Option<Arc<Daemon>>,
+) -> Result<()> {
+    let listener = bind_server(ws_port).await?;
+    serve(listener, key_manager, daemon).await
+}
 
-        // If we connected successfully, we're done
-        if connected {
-            return Ok(());
+/// Listen on `ws_port`, over IPv6 as well as IPv4 where available
+pub async fn bind_server(ws_port: u16) -> Result<TcpListener> {
+    let listener = match bind_dual_stack(ws_port) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!(
+                "IPv6 listener unavailable, falling back to IPv4 only: {}",
+                e
+            );
+            TcpListener::bind((Ipv4Addr::UNSPECIFIED, ws_port))
+                .await
+                .map_err(|e| eyre!("Failed to listen on port {ws_port}: {e}"))?
         }
-    }
+    };
+    info!("Server listening on {}", listener.local_addr()?);
 
-    // Start server (either because no client URL provided, or connection failed)
-    host_server(ws_port).await?;
-    Ok(())
+    Ok(listener)
 }
 
-async fn host_server(ws_port: u16) -> Result<()> {
-    let listener = TcpListener::bind(format!("0.0.0.0:{ws_port}")).await?;
-    info!("Server listening on port {}", ws_port);
-
-    while let Ok((stream, addr)) = listener.accept().await {
+/// Accept WebSocket connections on a listener bound with [`bind_server`], see
+/// [`host_server`]
+pub async fn serve(
+    listener: TcpListener,
+    key_manager: Arc<KeyManager>,
+    daemon: Option<Arc<Daemon>>,
+) -> Result<()> {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
         info!("Incoming connection from {}", addr);
+        let daemon = daemon.clone();
+        let key_manager = key_manager.clone();
 
-        match accept_async(stream).await {
-            Ok(ws_stream) => {
-                info!("WebSocket connection established with {}", addr);
-                handle_server_connection(ws_stream).await?;
-            }
-            Err(e) => {
-                error!("Failed to accept WebSocket connection: {}", e);
+        tokio::spawn(async move {
+            match accept_async(stream).await {
+                Ok(ws_stream) => {
+                    info!("WebSocket connection established with {}", addr);
+                    if let Err(e) =
+                        handle_server_connection(ws_stream, addr, key_manager, daemon).await
+                    {
+                        error!("Connection with {} failed: {}", addr, e);
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to accept WebSocket connection: {}", e);
+                }
             }
-        }
+        });
     }
+}
+
+/// Listen on `[::]` accepting both IPv6 and IPv4-mapped connections
+fn bind_dual_stack(ws_port: u16) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), ws_port).into())?;
+    socket.listen(1024)?;
 
-    Ok(())
+    Ok(TcpListener::from_std(socket.into())?)
 }
@@ -1,95 +1,623 @@
-use color_eyre::Result;
+use std::{net::SocketAddr, time::Duration};
+
+use color_eyre::{Result, eyre::eyre};
 use futures::{SinkExt, StreamExt};
-use tokio_tungstenite::tungstenite::{Bytes, Message};
-use tracing::{error, info};
+use serde::{Deserialize, Serialize};
+use ssh_key::PublicKey;
+use tokio::{fs::File, io::AsyncReadExt, sync::mpsc::UnboundedReceiver, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{info, warn};
+
+use crate::{
+    network_discovery::udp_broadcast::PeerInfo,
+    rate_limit::RateLimit,
+    ssh::key_manager::KeyManager,
+    transfers::{
+        chunking::ChunkSizer,
+        compression,
+        tracker::{HashStatus, Transfer, TransferAction, TransferState, TransferTracker},
+        upload::{self, Upload},
+    },
+    websockets::{
+        ReadStream, WriteSink, auth,
+        hello::{Hello, Session},
+        messages::{BinaryChunk, WebSocketMessage},
+        pairing::{self, PAIRING_TIMEOUT, Role, Spake},
+    },
+};
+
+/// How long the receiver has to answer the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What happened to a file offered to a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentFile {
+    pub name: String,
+    pub size: u64,
+    #[serde(flatten)]
+    pub outcome: SendOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "lowercase")]
+pub enum SendOutcome {
+    Sent,
+    Rejected { reason: Option<String> },
+    Cancelled { by_receiver: bool },
+}
+
+/// Offer each file in turn to the WebSocket server at `addr`, sending the ones accepted
+///
+/// Every file is added to `tracker`, through which it can be paused, resumed or cancelled,
+/// and sent no faster than `limit` allows, using what both `hello` and the receiver's hello
+/// support. The receiver has to trust the key of `key_manager`.
+pub async fn send_files(
+    addr: SocketAddr,
+    peer: Option<&PeerInfo>,
+    uploads: &[Upload],
+    tracker: &TransferTracker,
+    limit: &RateLimit,
+    hello: &Hello,
+    key_manager: &KeyManager,
+) -> Result<Vec<SentFile>> {
+    let result = send_all(addr, peer, uploads, tracker, limit, hello, key_manager).await;
+
+    if let Err(e) = &result {
+        // Only the files that weren't sent, rejected or cancelled yet are still unfinished
+        for upload in uploads {
+            tracker.finish(&upload.id, TransferState::Failed, Some(e.to_string()));
+        }
+    }
+
+    result
+}
+
+async fn send_all(
+    addr: SocketAddr,
+    peer: Option<&PeerInfo>,
+    uploads: &[Upload],
+    tracker: &TransferTracker,
+    limit: &RateLimit,
+    hello: &Hello,
+    key_manager: &KeyManager,
+) -> Result<Vec<SentFile>> {
+    let actions: Vec<_> = uploads
+        .iter()
+        .map(|upload| tracker.insert(Transfer::outgoing(upload, addr, peer)))
+        .collect();
 
-use crate::websockets::messages::WebSocketMessage;
+    // Hashed before connecting, as the receiver drops connections that stay silent for long
+    let mut hashes = Vec::with_capacity(uploads.len());
+    for upload in uploads {
+        let hash = upload::hash_file(&upload.path).await?;
+        tracker.update(&upload.id, |transfer| transfer.hash = Some(hash.clone()));
+        hashes.push(hash);
+    }
 
-pub async fn handle_client_connection(
-    ws_stream: tokio_tungstenite::WebSocketStream<
-        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-    >,
-) -> Result<()> {
+    let (ws_stream, _) = connect_async(format!("ws://{addr}"))
+        .await
+        .map_err(|e| eyre!("Failed to connect to {}: {}", addr, e))?;
     let (mut write, mut read) = ws_stream.split();
+    let peer_id = peer.map(|peer| peer.id.as_str());
+    let session = handshake(&mut write, &mut read, hello, key_manager, peer_id).await?;
+    info!("Connected to {} to send {} files", addr, uploads.len());
+
+    let mut files = Vec::new();
+    for ((upload, hash), actions) in uploads.iter().zip(hashes).zip(actions) {
+        let mut sending = Sending {
+            upload,
+            hash,
+            tracker,
+            limit,
+            session: &session,
+            actions,
+            paused: false,
+            chunks: ChunkSizer::new(session.limits.max_chunk_size, session.limits.buffer_size),
+            sent: 0,
+            acked: 0,
+        };
+        let outcome = sending.send(&mut write, &mut read).await?;
+        files.push(SentFile {
+            name: upload.name.clone(),
+            size: upload.size,
+            outcome,
+        });
+    }
+
+    write.close().await?;
+    Ok(files)
+}
+
+/// A file being sent, along with what the user and the receiver ask of it
+struct Sending<'a> {
+    upload: &'a Upload,
+    /// SHA-256 of the file, taken before connecting
+    hash: String,
+    tracker: &'a TransferTracker,
+    limit: &'a RateLimit,
+    session: &'a Session,
+    actions: UnboundedReceiver<TransferAction>,
+    paused: bool,
+    chunks: ChunkSizer,
+    /// Bytes sent so far, and how many of them the receiver wrote
+    sent: u64,
+    acked: u64,
+}
 
-    write
-        .send(Message::Ping(Bytes::from_static(b"ping")))
+impl Sending<'_> {
+    async fn send(&mut self, write: &mut WriteSink, read: &mut ReadStream) -> Result<SendOutcome> {
+        let upload = self.upload;
+
+        // Cancelled while earlier files were being sent
+        if self.actions.try_recv() == Ok(TransferAction::Cancel) {
+            return Ok(SendOutcome::Cancelled { by_receiver: false });
+        }
+
+        let hash = self.hash.clone();
+        let mime_type = upload::mime_type(&upload.path);
+        let offer = WebSocketMessage::FileOffer {
+            transfer_id: upload.id.clone(),
+            filename: upload.name.clone(),
+            size: upload.size,
+            hash: hash.clone(),
+            mime_type: mime_type.to_string(),
+        };
+        send_message(write, &offer).await?;
+
+        if let Some(outcome) = self.wait_for_accept(write, read).await? {
+            return Ok(outcome);
+        }
+
+        info!("Sending {} ({} bytes)", upload.name, upload.size);
+        self.tracker.start(&upload.id);
+        let compression = compression::choose(&self.session.compression, &upload.path, mime_type);
+        send_message(
+            write,
+            &WebSocketMessage::TransferStart {
+                chunk_size: self.chunks.max(),
+                compression,
+            },
+        )
         .await?;
-    info!("Sent initial ping to outgoing connection");
 
-    while let Some(msg) = read.next().await {
-        match msg {
-            Ok(Message::Text(text)) => {
-                info!("Received text message: {text}");
+        let mut file = File::open(&upload.path)
+            .await
+            .map_err(|e| eyre!("Failed to open {}: {}", upload.path.display(), e))?;
+        let mut buffer = vec![0; self.chunks.max()];
+        self.chunks.resume();
+        loop {
+            if let Some(outcome) = self.check(write, read).await? {
+                return Ok(outcome);
+            }
+
+            let read = file.read(&mut buffer[..self.chunks.size()]).await?;
+            if read == 0 {
+                break;
+            }
+
+            let compressed = match compression {
+                Some(compression) => compression.compress(&buffer[..read])?,
+                None => None,
+            };
+            let is_compressed = compressed.is_some();
+            let data = compressed.unwrap_or_else(|| buffer[..read].to_vec());
+
+            // Limited by what goes over the network, not the size of the file
+            self.limit.acquire(data.len()).await;
+            self.send_chunk(write, data, is_compressed).await?;
+            self.chunks.record(read);
+            self.sent += read as u64;
+            self.tracker.progress(&upload.id, self.sent);
+        }
+
+        if self.sent != upload.size {
+            return Err(eyre!("{} changed while it was being sent", upload.name));
+        }
+
+        let complete = WebSocketMessage::TransferComplete { hash: hash.clone() };
+        send_message(write, &complete).await?;
+
+        self.wait_for_hash(read, &hash).await
+    }
+
+    /// Wait for the receiver to answer with the hash of what it saved
+    ///
+    /// The receiver may still cancel, pause or resume the file until it has all of it.
+    async fn wait_for_hash(&self, read: &mut ReadStream, hash: &str) -> Result<SendOutcome> {
+        let upload = self.upload;
+
+        loop {
+            match next_message(read).await? {
+                WebSocketMessage::TransferComplete {
+                    hash: received_hash,
+                } => {
+                    info!("Sent {}", upload.name);
+                    let hash_status = if received_hash == hash {
+                        HashStatus::Verified
+                    } else {
+                        HashStatus::Mismatch
+                    };
+                    self.tracker
+                        .update(&upload.id, |transfer| transfer.hash_status = hash_status);
+                    self.tracker
+                        .finish(&upload.id, TransferState::Completed, None);
+                    return Ok(SendOutcome::Sent);
+                }
+                WebSocketMessage::TransferCancel { transfer_id } if transfer_id == upload.id => {
+                    return Ok(self.cancelled_by_receiver());
+                }
+                // Nothing is left to send
+                WebSocketMessage::TransferPause { transfer_id }
+                | WebSocketMessage::TransferResume { transfer_id }
+                    if transfer_id == upload.id => {}
+                message => return Err(unexpected(&upload.name, message)),
             }
-            Err(_) => todo!(),
-            _ => todo!(),
         }
     }
 
-    Ok(())
+    /// Wait for the receiver to decide on the offer, `None` once it was accepted
+    async fn wait_for_accept(
+        &mut self,
+        write: &mut WriteSink,
+        read: &mut ReadStream,
+    ) -> Result<Option<SendOutcome>> {
+        let upload = self.upload;
+
+        loop {
+            tokio::select! {
+                message = next_message(read) => match message? {
+                    WebSocketMessage::FileAccept { accept: true, .. } => return Ok(None),
+                    WebSocketMessage::FileAccept {
+                        accept: false,
+                        reason,
+                    } => {
+                        info!("{} was rejected: {:?}", upload.name, reason);
+                        self.tracker
+                            .finish(&upload.id, TransferState::Rejected, reason.clone());
+                        return Ok(Some(SendOutcome::Rejected { reason }));
+                    }
+                    message => return Err(unexpected(&upload.name, message)),
+                },
+                Some(action) = self.actions.recv() => {
+                    if action == TransferAction::Cancel {
+                        self.cancel(write).await?;
+                        return Ok(Some(SendOutcome::Cancelled { by_receiver: false }));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Carry out what the user and the receiver asked since the last chunk, waiting while
+    /// the transfer is paused or the receiver is behind, `None` to send the next chunk
+    async fn check(
+        &mut self,
+        write: &mut WriteSink,
+        read: &mut ReadStream,
+    ) -> Result<Option<SendOutcome>> {
+        loop {
+            let outcome = tokio::select! {
+                biased;
+                Some(action) = self.actions.recv() => self.act(action, write).await?,
+                message = read.next() => {
+                    let message = message.ok_or_else(|| eyre!("The receiver closed the connection"))?;
+                    self.receive(message?)?
+                }
+                () = std::future::ready(()), if !self.paused && self.window_open() => {
+                    return Ok(None);
+                }
+            };
+
+            if outcome.is_some() {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    async fn act(
+        &mut self,
+        action: TransferAction,
+        write: &mut WriteSink,
+    ) -> Result<Option<SendOutcome>> {
+        let transfer_id = self.upload.id.clone();
+
+        match action {
+            TransferAction::Pause => {
+                info!("Pausing {}", self.upload.name);
+                send_message(write, &WebSocketMessage::TransferPause { transfer_id }).await?;
+                self.set_paused(true);
+            }
+            TransferAction::Resume => {
+                info!("Resuming {}", self.upload.name);
+                send_message(write, &WebSocketMessage::TransferResume { transfer_id }).await?;
+                self.set_paused(false);
+            }
+            TransferAction::Cancel => {
+                self.cancel(write).await?;
+                return Ok(Some(SendOutcome::Cancelled { by_receiver: false }));
+            }
+        }
 
-    // loop {
-    //     let msg = read.next().await?;
-    //     match msg {
-    //         Some(Ok(Message::Text(text))) => {
-    //             info!("Received text message: {text}");
-    //             match WebSocketMessage::from_json(&text) {
-    //                 Ok(ws_msg) => {
-    //                     info!("Parsed WebSocket message: {:?}", ws_msg);
-    //                     handle_websocket_message(ws_msg);
-    //                 }
-    //                 Err(e) => {
-    //                     error!("Failed to parse JSON message: {}", e);
-    //                     info!("Raw message: {text}");
-    //                 }
-    //             }
-    //         }
-    //         Some(Ok(Message::Ping(_))) => {
-    //             info!("Received ping, sending pong");
-    //             write
-    //                 .send(Message::Pong(Bytes::from_static(b"pong")))
-    //                 .await?;
-    //         }
-    //         Some(Ok(Message::Pong(_))) => {
-    //             last_pong = Instant::now();
-    //             info!("Received pong - connection healthy");
-    //         }
-    //         Some(Ok(Message::Binary(data))) => {
-    //             info!("Received binary: {} bytes", data.len());
-    //             handle_binary_data(&data);
-    //         }
-    //         Some(Ok(_)) => {
-    //             warn!("Received unknown message type");
-    //         }
-    //         Some(Err(e)) => {
-    //             error!("WebSocket error: {}", e);
-    //             break;
-    //         }
-    //         None => {
-    //             info!("Connection closed by peer");
-    //             break;
-    //         }
-    //     }
-    // }
+        Ok(None)
+    }
+
+    /// Handle a message the receiver sent while chunks were being sent
+    fn receive(&mut self, message: Message) -> Result<Option<SendOutcome>> {
+        let Message::Text(text) = message else {
+            return match message {
+                Message::Close(_) => Err(eyre!("The receiver closed the connection")),
+                _ => Ok(None),
+            };
+        };
+
+        match WebSocketMessage::from_json(&text)? {
+            WebSocketMessage::TransferAck {
+                transfer_id,
+                offset,
+            } if transfer_id == self.upload.id => {
+                // Acknowledging more than was sent would open the window past the buffer
+                if offset <= self.sent {
+                    self.acked = self.acked.max(offset);
+                } else {
+                    warn!(
+                        "The receiver acknowledged {} bytes of {}, only {} were sent",
+                        offset, self.upload.name, self.sent
+                    );
+                }
+            }
+            // Late acknowledgements of a file that was cancelled
+            WebSocketMessage::TransferAck { .. } => {}
+            WebSocketMessage::TransferPause { transfer_id } if transfer_id == self.upload.id => {
+                info!("The receiver paused {}", self.upload.name);
+                self.set_paused(true);
+            }
+            WebSocketMessage::TransferResume { transfer_id } if transfer_id == self.upload.id => {
+                info!("The receiver resumed {}", self.upload.name);
+                self.set_paused(false);
+            }
+            WebSocketMessage::TransferCancel { transfer_id } if transfer_id == self.upload.id => {
+                return Ok(Some(self.cancelled_by_receiver()));
+            }
+            message => return Err(unexpected(&self.upload.name, message)),
+        }
+
+        Ok(None)
+    }
+
+    /// Send a chunk at the current offset, as a binary message if the receiver takes those
+    async fn send_chunk(
+        &self,
+        write: &mut WriteSink,
+        data: Vec<u8>,
+        compressed: bool,
+    ) -> Result<()> {
+        if self.session.binary_chunks {
+            let chunk = BinaryChunk {
+                offset: self.sent,
+                compressed,
+                data: &data,
+            };
+            write.send(Message::binary(chunk.encode())).await?;
+            return Ok(());
+        }
+
+        let chunk = WebSocketMessage::TransferChunk {
+            data,
+            offset: self.sent,
+            compressed,
+        };
+        send_message(write, &chunk).await
+    }
+
+    /// Whether the next chunk fits in what the receiver can hold
+    const fn window_open(&self) -> bool {
+        self.sent - self.acked + self.chunks.size() as u64 <= self.session.limits.buffer_size as u64
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if self.paused && !paused {
+            self.chunks.resume();
+        }
+        self.paused = paused;
+        self.tracker.pause(&self.upload.id, paused);
+    }
+
+    fn cancelled_by_receiver(&self) -> SendOutcome {
+        info!("The receiver cancelled {}", self.upload.name);
+        self.tracker.finish(
+            &self.upload.id,
+            TransferState::Cancelled,
+            Some("Cancelled by the receiver".to_string()),
+        );
+        SendOutcome::Cancelled { by_receiver: true }
+    }
+
+    /// Tell the receiver the transfer was cancelled here
+    async fn cancel(&self, write: &mut WriteSink) -> Result<()> {
+        info!("Cancelling {}", self.upload.name);
+        let transfer_id = self.upload.id.clone();
+        send_message(write, &WebSocketMessage::TransferCancel { transfer_id }).await
+    }
+}
+
+/// Exchange hellos with the receiver and sign its challenge, failing clearly if the two
+/// can't talk to each other, the receiver doesn't trust this peer or it isn't `peer_id`
+pub async fn handshake(
+    write: &mut WriteSink,
+    read: &mut ReadStream,
+    hello: &Hello,
+    key_manager: &KeyManager,
+    peer_id: Option<&str>,
+) -> Result<Session> {
+    timeout(
+        HANDSHAKE_TIMEOUT,
+        exchange_handshake(write, read, hello, key_manager, peer_id),
+    )
+    .await
+    .map_err(|_| eyre!("The receiver didn't answer the handshake, it may run an older alacrite"))?
+}
+
+async fn exchange_handshake(
+    write: &mut WriteSink,
+    read: &mut ReadStream,
+    hello: &Hello,
+    key_manager: &KeyManager,
+    peer_id: Option<&str>,
+) -> Result<Session> {
+    let (session, transcript) = exchange_hellos(write, read, hello).await?;
+    if let Some(peer_id) = peer_id
+        && session.peer_id.as_deref() != Some(peer_id)
+    {
+        return Err(eyre!(
+            "Expected {} to answer, but {} did",
+            peer_id,
+            session.peer_id.as_deref().unwrap_or("a peer without an id")
+        ));
+    }
+
+    let signature = auth::sign(key_manager, &transcript)?;
+    send_message(write, &WebSocketMessage::Auth { signature }).await?;
+
+    match next_message(read).await? {
+        WebSocketMessage::AuthResponse { success: true, .. } => Ok(session),
+        WebSocketMessage::AuthResponse {
+            success: false,
+            message,
+        } => Err(eyre!(
+            "The receiver didn't let this peer in: {}",
+            message.as_deref().unwrap_or("no reason given")
+        )),
+        message => Err(refused("an answer to the signature", message)),
+    }
 }
 
-fn handle_websocket_message(msg: WebSocketMessage) {
-    match msg {
-        WebSocketMessage::FileOffer { filename, size, .. } => {
-            info!("File offer received: {} ({} bytes)", filename, size);
-            // TODO: Implement file offer handling
+/// Exchange hellos with the receiver, returning what was agreed and the transcript of its
+/// challenge to sign
+async fn exchange_hellos(
+    write: &mut WriteSink,
+    read: &mut ReadStream,
+    hello: &Hello,
+) -> Result<(Session, Vec<u8>)> {
+    send_message(write, &WebSocketMessage::Hello(hello.clone())).await?;
+    let remote = match next_message(read).await? {
+        WebSocketMessage::Hello(remote) => remote,
+        message => return Err(refused("a hello", message)),
+    };
+    let session = hello.negotiate(&remote)?;
+
+    match next_message(read).await? {
+        WebSocketMessage::AuthChallenge { nonce } => {
+            Ok((session, auth::transcript(&nonce, hello, &remote)?))
         }
+        message => Err(refused("a challenge", message)),
+    }
+}
+
+/// Pair with the peer at `addr` waiting for `code`, trusting its key and returning its id
+/// once it trusts the key of `key_manager`
+pub async fn pair(
+    addr: SocketAddr,
+    code: &str,
+    hello: &Hello,
+    key_manager: &KeyManager,
+) -> Result<String> {
+    let (ws_stream, _) = connect_async(format!("ws://{addr}"))
+        .await
+        .map_err(|e| eyre!("Failed to connect to {}: {}", addr, e))?;
+    let (mut write, mut read) = ws_stream.split();
+
+    let peer_id = timeout(
+        PAIRING_TIMEOUT,
+        exchange_pairing(&mut write, &mut read, code, hello, key_manager),
+    )
+    .await
+    .map_err(|_| eyre!("{} didn't finish pairing in time", addr))??;
+
+    let _ = write.send(Message::Close(None)).await;
+    Ok(peer_id)
+}
+
+async fn exchange_pairing(
+    write: &mut WriteSink,
+    read: &mut ReadStream,
+    code: &str,
+    hello: &Hello,
+    key_manager: &KeyManager,
+) -> Result<String> {
+    let own_id = hello
+        .peer_id
+        .as_deref()
+        .ok_or_else(|| eyre!("Only peers running discovery can pair"))?;
+    let (session, _) = exchange_hellos(write, read, hello).await?;
+    let peer_id = session
+        .peer_id
+        .ok_or_else(|| eyre!("The peer didn't say hello with its id"))?;
+
+    let spake = Spake::start(Role::Guest, code, own_id, &peer_id);
+    let request = WebSocketMessage::PairRequest {
+        nameplate: pairing::nameplate(code).to_string(),
+        message: spake.message(),
+    };
+    send_message(write, &request).await?;
+    let key = match next_message(read).await? {
+        WebSocketMessage::PairResponse { message } => spake.finish(&message)?,
+        message => return Err(refused("a pairing message", message)),
+    };
+
+    let public_key = key_manager.get_public_key();
+    let confirm = WebSocketMessage::PairConfirm {
+        public_key: public_key.to_openssh()?,
+        mac: key.confirm(Role::Guest, own_id, public_key)?,
+    };
+    send_message(write, &confirm).await?;
+
+    let (public_key, mac) = match next_message(read).await? {
+        WebSocketMessage::PairConfirm { public_key, mac } => (public_key, mac),
+        message => return Err(refused("a public key", message)),
+    };
+    let public_key = PublicKey::from_openssh(&public_key)
+        .map_err(|e| eyre!("{} sent an invalid public key: {}", peer_id, e))?;
+    key.verify(Role::Host, &peer_id, &public_key, &mac)?;
+
+    key_manager.add_trusted_key(peer_id.clone(), public_key);
+    key_manager.save_trusted_keys()?;
+    Ok(peer_id)
+}
+
+fn refused(expected: &str, message: WebSocketMessage) -> color_eyre::Report {
+    match message {
         WebSocketMessage::Error { message } => {
-            error!("Received error message: {}", message);
-        }
-        _ => {
-            info!("Unhandled message type: {:?}", msg);
+            eyre!("The receiver refused the connection: {}", message)
         }
+        message => eyre!("Expected {} from the receiver, got {:?}", expected, message),
+    }
+}
+
+fn unexpected(name: &str, message: WebSocketMessage) -> color_eyre::Report {
+    match message {
+        WebSocketMessage::Error { message } => eyre!("Failed to send {}: {}", name, message),
+        message => eyre!("Unexpected reply while sending {}: {:?}", name, message),
     }
 }
 
-fn handle_binary_data(data: &[u8]) {
-    info!("Handling binary data: {} bytes", data.len());
-    // TODO: Implement binary data handling (file chunks)
+async fn send_message(write: &mut WriteSink, message: &WebSocketMessage) -> Result<()> {
+    write.send(Message::text(message.to_json()?)).await?;
+    Ok(())
+}
+
+/// Wait for the next protocol message, skipping control frames and acknowledgements of
+/// chunks that no longer matter
+async fn next_message(read: &mut ReadStream) -> Result<WebSocketMessage> {
+    while let Some(message) = read.next().await {
+        match message? {
+            Message::Text(text) => match WebSocketMessage::from_json(&text)? {
+                WebSocketMessage::TransferAck { .. } => {}
+                message => return Ok(message),
+            },
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    Err(eyre!("The receiver closed the connection"))
 }
@@ -1,16 +1,120 @@
-use std::time::{Duration, Instant};
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use color_eyre::Result;
-use futures::{SinkExt, StreamExt};
-use tokio::time::interval;
-use tokio_tungstenite::tungstenite::{Bytes, Message};
-use tracing::{error, info, warn};
+use color_eyre::{Result, eyre::eyre};
+use futures::{SinkExt, StreamExt, stream::SplitSink};
+use ssh_key::PublicKey;
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc::UnboundedReceiver, oneshot},
+    time::interval,
+};
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{Bytes, Message},
+};
+use tracing::{debug, error, info, warn};
 
-use crate::websockets::messages::WebSocketMessage;
+use crate::{
+    daemon::{Daemon, PendingPairing},
+    rate_limit::RateLimit,
+    ssh::key_manager::KeyManager,
+    transfers::{
+        compression::Compression,
+        download::{self, Download},
+        offers::{Decision, Offer},
+        tracker::{HashStatus, Transfer, TransferAction, TransferState, TransferTracker},
+    },
+    websockets::{
+        auth,
+        hello::{Hello, Session, max_chunk_size},
+        messages::{BinaryChunk, WebSocketMessage},
+        pairing::{PairingKey, Role, Spake},
+    },
+};
+
+type ServerSink = SplitSink<WebSocketStream<TcpStream>, Message>;
+
+/// A throughput test in progress
+struct SpeedTest {
+    size: u64,
+    received: u64,
+    started: Instant,
+}
+
+/// An offer waiting for the user to accept or reject it
+struct PendingOffer {
+    offer: Offer,
+    decision: oneshot::Receiver<Decision>,
+    deadline: Instant,
+}
+
+/// What an incoming connection is in the middle of
+struct Connection {
+    peer: SocketAddr,
+    /// Files are only accepted when running as a daemon
+    daemon: Option<Arc<Daemon>>,
+    speed_test: Option<SpeedTest>,
+    pending: Option<PendingOffer>,
+    download: Option<Download>,
+    /// What the user asks of the latest offer
+    actions: Option<UnboundedReceiver<TransferAction>>,
+    /// How fast chunks are read from this peer
+    limit: RateLimit,
+    /// Whether the download was cancelled here, and the chunks the sender sent before it
+    /// heard are dropped
+    discarding: bool,
+    /// How the sender compresses the chunks of the download
+    compression: Option<Compression>,
+    /// What was agreed with the sender, once it said hello
+    session: Option<Session>,
+    /// Transcript of the challenge and hellos the sender has to sign, and whether it did
+    /// with a trusted key
+    challenge: Option<Vec<u8>>,
+    authenticated: bool,
+    key_manager: Arc<KeyManager>,
+    /// Key derived from the code of the pairing the peer started, until it confirms it
+    pairing: Option<(PairingKey, PendingPairing)>,
+}
 
 pub async fn handle_server_connection(
-    ws_stream: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+    ws_stream: WebSocketStream<TcpStream>,
+    peer: SocketAddr,
+    key_manager: Arc<KeyManager>,
+    daemon: Option<Arc<Daemon>>,
 ) -> Result<()> {
+    let limit = daemon
+        .as_ref()
+        .map(|daemon| daemon.download_limiter.limit(peer.ip(), None))
+        .unwrap_or_default();
+    let mut connection = Connection {
+        peer,
+        daemon,
+        limit,
+        speed_test: None,
+        pending: None,
+        download: None,
+        actions: None,
+        discarding: false,
+        compression: None,
+        session: None,
+        challenge: None,
+        authenticated: false,
+        key_manager,
+        pairing: None,
+    };
+
+    let result = serve(ws_stream, &mut connection).await;
+    connection.close().await;
+    info!("Incoming connection closed");
+    result
+}
+
+async fn serve(ws_stream: WebSocketStream<TcpStream>, connection: &mut Connection) -> Result<()> {
     let (mut write, mut read) = ws_stream.split();
     let mut last_pong = Instant::now();
     let mut ping_interval = interval(Duration::from_secs(30)); // Ping every 30 seconds
@@ -27,15 +131,13 @@ pub async fn handle_server_connection(
             msg = read.next() => {
                 match msg {
                     Some(Ok(Message::Text(text))) => {
-                        info!("Received text message: {text}");
+                        debug!("Received text message of {} bytes", text.len());
                         match WebSocketMessage::from_json(&text) {
                             Ok(ws_msg) => {
-                                info!("Parsed WebSocket message: {:?}", ws_msg);
-                                handle_websocket_message(ws_msg);
+                                connection.handle_message(ws_msg, &mut write).await?;
                             }
                             Err(e) => {
                                 error!("Failed to parse JSON message: {}", e);
-                                info!("Raw message: {text}");
                             }
                         }
                     }
@@ -50,28 +152,39 @@ pub async fn handle_server_connection(
                         info!("Received pong - connection healthy");
                     }
                     Some(Ok(Message::Binary(data))) => {
-                        info!("Received binary: {} bytes", data.len());
-                        handle_binary_data(&data);
+                        connection.handle_binary(&data, &mut write).await?;
+                    }
+                    Some(Ok(Message::Close(_))) => {
+                        info!("Connection closed by peer");
+                        return Ok(());
                     }
                     Some(Ok(_)) => {
                         warn!("Received unknown message type");
                     }
                     Some(Err(e)) => {
                         error!("WebSocket error: {}", e);
-                        break;
+                        return Ok(());
                     }
                     None => {
                         info!("Connection closed by peer");
-                        break;
+                        return Ok(());
                     }
                 }
             }
+            // Answer the sender once the user decided on its offer
+            decision = wait_for_decision(&mut connection.pending), if connection.pending.is_some() => {
+                connection.decided(decision, &mut write).await?;
+            }
+            // Pause, resume or cancel the transfer when the user asks
+            Some(action) = next_action(&mut connection.actions) => {
+                connection.act(action, &mut write).await?;
+            }
             // Send periodic pings
             _ = ping_interval.tick() => {
                 let time_since_last_pong = last_pong.elapsed();
                 if time_since_last_pong > Duration::from_secs(60) {
                     warn!("No pong received for {}s, connection may be dead", time_since_last_pong.as_secs());
-                    break;
+                    return Ok(());
                 }
 
                 write
@@ -81,22 +194,668 @@ pub async fn handle_server_connection(
             }
         }
     }
+}
 
-    info!("Incoming connection closed");
-    Ok(())
+/// Wait for the user to decide on the pending offer, `None` if it timed out
+async fn wait_for_decision(pending: &mut Option<PendingOffer>) -> Option<Decision> {
+    let pending = pending.as_mut()?;
+    tokio::time::timeout_at(pending.deadline.into(), &mut pending.decision)
+        .await
+        .ok()?
+        .ok()
 }
 
-fn handle_websocket_message(msg: WebSocketMessage) {
-    match msg {
-        WebSocketMessage::Auth { token } => {
-            info!("Authentication received: {token}");
-            // TODO: Implement authentication handling
+/// Wait for the user to ask something of the latest offer
+async fn next_action(
+    actions: &mut Option<UnboundedReceiver<TransferAction>>,
+) -> Option<TransferAction> {
+    actions.as_mut()?.recv().await
+}
+
+impl Connection {
+    async fn handle_message(
+        &mut self,
+        msg: WebSocketMessage,
+        write: &mut ServerSink,
+    ) -> Result<()> {
+        match msg {
+            msg if !self.authenticated => return self.handshake(msg, write).await,
+            WebSocketMessage::SpeedTest { size } => {
+                info!("Starting throughput test of {} bytes", size);
+                self.speed_test = Some(SpeedTest {
+                    size,
+                    received: 0,
+                    started: Instant::now(),
+                });
+            }
+            WebSocketMessage::FileOffer {
+                transfer_id,
+                filename,
+                size,
+                hash,
+                mime_type,
+            } => {
+                let offer = Offer {
+                    id: transfer_id,
+                    filename,
+                    size,
+                    hash,
+                    mime_type,
+                    from: self.peer,
+                    peer: None,
+                    hostname: None,
+                    received_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                };
+                self.discarding = false;
+                self.compression = None;
+                self.handle_offer(offer, write).await?;
+            }
+            WebSocketMessage::TransferStart {
+                chunk_size,
+                compression,
+            } => {
+                self.start(chunk_size, compression, write).await?;
+            }
+            WebSocketMessage::TransferChunk { .. } | WebSocketMessage::TransferComplete { .. }
+                if self.discarding => {}
+            WebSocketMessage::TransferChunk {
+                data,
+                offset,
+                compressed,
+            } => {
+                self.receive_chunk(offset, &data, compressed, write).await?;
+            }
+            WebSocketMessage::TransferComplete { hash } => {
+                self.complete(hash, write).await?;
+            }
+            WebSocketMessage::TransferPause { transfer_id } => {
+                info!("The sender paused {}", transfer_id);
+                if let Some(transfers) = self.transfers() {
+                    transfers.pause(&transfer_id, true);
+                }
+            }
+            WebSocketMessage::TransferResume { transfer_id } => {
+                info!("The sender resumed {}", transfer_id);
+                if let Some(transfers) = self.transfers() {
+                    transfers.pause(&transfer_id, false);
+                }
+            }
+            WebSocketMessage::TransferCancel { transfer_id } => {
+                info!("The sender cancelled {}", transfer_id);
+                self.finish(
+                    &transfer_id,
+                    TransferState::Cancelled,
+                    Some("Cancelled by the sender".to_string()),
+                );
+                self.drop_transfer(&transfer_id).await;
+            }
+            ws_msg => {
+                info!("Parsed WebSocket message: {:?}", ws_msg);
+                handle_websocket_message(ws_msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the sender sends chunks the way this end takes them
+    async fn start(
+        &mut self,
+        chunk_size: usize,
+        compression: Option<Compression>,
+        write: &mut ServerSink,
+    ) -> Result<()> {
+        let Some(daemon) = self.daemon.as_ref().filter(|_| self.download.is_some()) else {
+            return send(write, &error("No transfer was accepted")).await;
+        };
+        if chunk_size > max_chunk_size() {
+            let message = format!("Chunks are at most {} bytes", max_chunk_size());
+            return send(write, &error(&message)).await;
+        }
+        if compression.is_some() && !daemon.compression {
+            return send(write, &error("Chunks can't be compressed")).await;
+        }
+
+        debug!(
+            "Transfer starting with chunks of up to {} bytes, compressed with {:?}",
+            chunk_size, compression
+        );
+        self.compression = compression;
+        Ok(())
+    }
+
+    /// Write a chunk of the accepted download and acknowledge it, failing the download on a
+    /// bad one
+    async fn receive_chunk(
+        &mut self,
+        offset: u64,
+        data: &[u8],
+        compressed: bool,
+        write: &mut ServerSink,
+    ) -> Result<()> {
+        let Some(download) = &mut self.download else {
+            return send(write, &error("No transfer was accepted")).await;
+        };
+
+        let written = match chunk_data(data, compressed, self.compression) {
+            Ok(chunk) => download.write_chunk(offset, &chunk).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            if let Some(download) = self.download.take() {
+                self.finish(&download.id, TransferState::Failed, Some(e.to_string()));
+                download.abort().await;
+            }
+            return send(write, &error(&e.to_string())).await;
         }
-        WebSocketMessage::AuthResponse { success, message } => {
-            info!("Authentication response: {success} - {message:?}");
-            // TODO: Implement authentication response handling
+        if let Some(daemon) = &self.daemon {
+            daemon.transfers.progress(&download.id, download.received);
         }
-        _ => {}
+        let ack = WebSocketMessage::TransferAck {
+            transfer_id: download.id.clone(),
+            offset: download.received,
+        };
+
+        // Acknowledged once the limit allows, which slows the sender down
+        self.limit.acquire(data.len()).await;
+        send(write, &ack).await
+    }
+
+    /// Answer the sender's hello, closing the connection if the two can't talk to each other
+    async fn hello(&mut self, remote: &Hello, write: &mut ServerSink) -> Result<()> {
+        let hello = self
+            .daemon
+            .as_deref()
+            .map_or_else(|| Hello::new(None, false), Daemon::hello);
+
+        match hello.negotiate(remote) {
+            Ok(session) => {
+                debug!("{} said hello: {:?}", self.peer, session);
+                self.session = Some(session);
+                let nonce = auth::challenge();
+                self.challenge = Some(auth::transcript(&nonce, remote, &hello)?);
+
+                send(write, &WebSocketMessage::Hello(hello)).await?;
+                send(write, &WebSocketMessage::AuthChallenge { nonce }).await
+            }
+            Err(e) => {
+                send(write, &error(&e.to_string())).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Handle what the peer sends before it authenticated, closing the connection on
+    /// anything else
+    async fn handshake(&mut self, msg: WebSocketMessage, write: &mut ServerSink) -> Result<()> {
+        match msg {
+            WebSocketMessage::Hello(remote) if self.session.is_none() => {
+                self.hello(&remote, write).await
+            }
+            WebSocketMessage::Auth { signature } => self.authenticate(&signature, write).await,
+            WebSocketMessage::PairRequest { nameplate, message } => {
+                self.start_pairing(&nameplate, &message, write).await
+            }
+            WebSocketMessage::PairConfirm { public_key, mac } => {
+                self.confirm_pairing(&public_key, &mac, write).await
+            }
+            msg => {
+                send(write, &error("Say hello and authenticate first")).await?;
+                Err(eyre!("{} sent {:?} before authenticating", self.peer, msg))
+            }
+        }
+    }
+
+    /// Answer a peer that was given a pairing code, if `alacrite pair` waits for it
+    async fn start_pairing(
+        &mut self,
+        nameplate: &str,
+        message: &[u8],
+        write: &mut ServerSink,
+    ) -> Result<()> {
+        let Some(pending) = self
+            .daemon
+            .as_ref()
+            .filter(|_| self.session.is_some())
+            .and_then(|daemon| daemon.take_pairing(nameplate))
+        else {
+            send(write, &error("Not waiting for that pairing code")).await?;
+            return Err(eyre!("{} paired with a code nobody waits for", self.peer));
+        };
+
+        let Some(guest_id) = self
+            .session
+            .as_ref()
+            .and_then(|session| session.peer_id.clone())
+        else {
+            let e = eyre!("The peer didn't say hello with its id");
+            return self.fail_pairing(pending, e, write).await;
+        };
+        let own_id = self.daemon.as_deref().map(Daemon::id).unwrap_or_default();
+
+        let spake = Spake::start(Role::Host, &pending.code, own_id, &guest_id);
+        let response = WebSocketMessage::PairResponse {
+            message: spake.message(),
+        };
+        match spake.finish(message) {
+            Ok(key) => {
+                self.pairing = Some((key, pending));
+                send(write, &response).await
+            }
+            Err(e) => self.fail_pairing(pending, e, write).await,
+        }
+    }
+
+    /// Trust the key the peer sent if it knew the code, and send this end's back
+    async fn confirm_pairing(
+        &mut self,
+        public_key: &str,
+        mac: &[u8],
+        write: &mut ServerSink,
+    ) -> Result<()> {
+        let Some((key, pending)) = self.pairing.take() else {
+            send(write, &error("Send a pairing request first")).await?;
+            return Err(eyre!("{} confirmed a pairing it didn't start", self.peer));
+        };
+
+        match self.trust(&key, public_key, mac) {
+            Ok((peer_id, confirm)) => {
+                send(write, &confirm).await?;
+                info!("{} paired as {}", self.peer, peer_id);
+                let _ = pending.paired.send(Ok(peer_id));
+                Ok(())
+            }
+            Err(e) => self.fail_pairing(pending, e, write).await,
+        }
+    }
+
+    /// Trust `public_key` for the peer id said hello with if the MAC shows the peer knew the
+    /// code, returning the id and the message confirming this end's key
+    fn trust(
+        &self,
+        key: &PairingKey,
+        public_key: &str,
+        mac: &[u8],
+    ) -> Result<(String, WebSocketMessage)> {
+        let peer_id = self
+            .session
+            .as_ref()
+            .and_then(|session| session.peer_id.clone())
+            .ok_or_else(|| eyre!("The peer didn't say hello with its id"))?;
+        let own_id = self.daemon.as_deref().map(Daemon::id).unwrap_or_default();
+
+        let public_key = PublicKey::from_openssh(public_key)
+            .map_err(|e| eyre!("{} sent an invalid public key: {}", peer_id, e))?;
+        key.verify(Role::Guest, &peer_id, &public_key, mac)?;
+
+        let own_key = self.key_manager.get_public_key();
+        let confirm = WebSocketMessage::PairConfirm {
+            public_key: own_key.to_openssh()?,
+            mac: key.confirm(Role::Host, own_id, own_key)?,
+        };
+        self.key_manager
+            .add_trusted_key(peer_id.clone(), public_key);
+        self.key_manager.save_trusted_keys()?;
+
+        Ok((peer_id, confirm))
+    }
+
+    /// Tell the peer and the waiting `alacrite pair` why pairing failed, closing the
+    /// connection
+    async fn fail_pairing(
+        &self,
+        pending: PendingPairing,
+        e: color_eyre::Report,
+        write: &mut ServerSink,
+    ) -> Result<()> {
+        let message = e.to_string();
+        let _ = pending.paired.send(Err(e));
+        send(write, &error(&message)).await?;
+        Err(eyre!("Failed to pair with {}: {}", self.peer, message))
+    }
+
+    /// Check the sender signed the challenge with the trusted key of the peer it said hello
+    /// as, closing the connection if it didn't
+    async fn authenticate(&mut self, signature: &str, write: &mut ServerSink) -> Result<()> {
+        let Some(transcript) = self.challenge.take() else {
+            send(write, &error("Say hello first")).await?;
+            return Err(eyre!("{} authenticated before saying hello", self.peer));
+        };
+        let peer_id = self
+            .session
+            .as_ref()
+            .and_then(|session| session.peer_id.as_deref());
+
+        match auth::verify(&self.key_manager, peer_id, &transcript, signature) {
+            Ok(()) => {
+                info!(
+                    "{} authenticated as {}",
+                    self.peer,
+                    peer_id.unwrap_or_default()
+                );
+                self.authenticated = true;
+                let response = WebSocketMessage::AuthResponse {
+                    success: true,
+                    message: None,
+                };
+                send(write, &response).await
+            }
+            Err(e) => {
+                let response = WebSocketMessage::AuthResponse {
+                    success: false,
+                    message: Some(e.to_string()),
+                };
+                send(write, &response).await?;
+                Err(eyre!("Refused {}: {}", self.peer, e))
+            }
+        }
+    }
+
+    /// Save the download once the sender sent every chunk, echoing the hash of what was saved
+    async fn complete(&mut self, hash: String, write: &mut ServerSink) -> Result<()> {
+        let Some(download) = self.download.take() else {
+            return send(write, &error("No transfer was accepted")).await;
+        };
+
+        let id = download.id.clone();
+        match download.finish(&hash).await {
+            Ok((path, actual_hash)) => {
+                if let Some(transfers) = self.transfers() {
+                    transfers.update(&id, |transfer| {
+                        transfer.hash_status = if actual_hash == hash {
+                            HashStatus::Verified
+                        } else {
+                            HashStatus::Mismatch
+                        };
+                        transfer.path = Some(path);
+                    });
+                    transfers.finish(&id, TransferState::Completed, None);
+                }
+                // Echo what was saved, so the sender can tell whether it matches
+                let complete = WebSocketMessage::TransferComplete { hash: actual_hash };
+                send(write, &complete).await
+            }
+            Err(e) => {
+                self.finish(&id, TransferState::Failed, Some(e.to_string()));
+                send(write, &error(&e.to_string())).await
+            }
+        }
+    }
+
+    /// Accept the offer right away if the download config allows, or queue it for the user
+    async fn handle_offer(&mut self, mut offer: Offer, write: &mut ServerSink) -> Result<()> {
+        let Some(daemon) = self.daemon.clone() else {
+            return send(
+                write,
+                &reject("Not accepting files, the receiver isn't running `alacrite daemon`"),
+            )
+            .await;
+        };
+
+        if self.pending.is_some() || self.download.is_some() {
+            return send(write, &error("Another transfer is in progress")).await;
+        }
+
+        if let Some(peer) = daemon.peer_at(self.peer.ip()) {
+            offer.peer = Some(peer.id);
+            offer.hostname = Some(peer.hostname);
+        }
+        self.actions = Some(daemon.transfers.insert(Transfer::incoming(&offer)));
+
+        if let Err(e) = download::check_offer(&daemon.downloads, &offer.filename, offer.size) {
+            info!("Rejected {} from {}: {}", offer.filename, self.peer, e);
+            self.finish(&offer.id, TransferState::Rejected, Some(e.to_string()));
+            return send(write, &reject(&e.to_string())).await;
+        }
+
+        if download::auto_accepts(&daemon.downloads, &offer.filename) {
+            info!("Downloading {} automatically", offer.filename);
+            return self.accept(&offer, write).await;
+        }
+
+        let decision = match daemon.offers.push(offer.clone()) {
+            Ok(decision) => decision,
+            Err(e) => {
+                self.finish(&offer.id, TransferState::Rejected, Some(e.to_string()));
+                return send(write, &reject(&e.to_string())).await;
+            }
+        };
+
+        info!(
+            "{} offers {} ({} bytes), accept it with `alacrite accept {}`",
+            offer.hostname.as_deref().unwrap_or("Unknown peer"),
+            offer.filename,
+            offer.size,
+            offer.id
+        );
+
+        self.pending = Some(PendingOffer {
+            offer,
+            decision,
+            deadline: Instant::now() + daemon.confirmation_timeout,
+        });
+        Ok(())
+    }
+
+    /// Tell the sender what the user decided on the pending offer
+    async fn decided(&mut self, decision: Option<Decision>, write: &mut ServerSink) -> Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+
+        match decision {
+            Some(Decision::Accept) => self.accept(&pending.offer, write).await,
+            Some(Decision::Reject { reason }) => {
+                self.finish(&pending.offer.id, TransferState::Rejected, reason.clone());
+                let reply = WebSocketMessage::FileAccept {
+                    accept: false,
+                    reason,
+                };
+                send(write, &reply).await
+            }
+            None => {
+                if let Some(daemon) = &self.daemon {
+                    daemon.offers.remove(&pending.offer.id);
+                }
+                info!("{} wasn't accepted in time", pending.offer.filename);
+                let reason = "Not accepted in time";
+                self.finish(
+                    &pending.offer.id,
+                    TransferState::Rejected,
+                    Some(reason.into()),
+                );
+                send(write, &reject(reason)).await
+            }
+        }
+    }
+
+    async fn accept(&mut self, offer: &Offer, write: &mut ServerSink) -> Result<()> {
+        let Some(daemon) = &self.daemon else {
+            return Ok(());
+        };
+
+        match Download::create(&daemon.downloads, offer).await {
+            Ok(download) => {
+                daemon.transfers.start(&offer.id);
+                self.download = Some(download);
+                let reply = WebSocketMessage::FileAccept {
+                    accept: true,
+                    reason: None,
+                };
+                send(write, &reply).await
+            }
+            Err(e) => {
+                error!("{}", e);
+                daemon
+                    .transfers
+                    .finish(&offer.id, TransferState::Failed, Some(e.to_string()));
+                send(write, &reject("Failed to create the file")).await
+            }
+        }
+    }
+
+    /// Carry out what the user asked of the latest offer, telling the sender
+    async fn act(&mut self, action: TransferAction, write: &mut ServerSink) -> Result<()> {
+        let Some(transfer_id) = self
+            .pending
+            .as_ref()
+            .map(|pending| pending.offer.id.clone())
+            .or_else(|| self.download.as_ref().map(|download| download.id.clone()))
+        else {
+            return Ok(());
+        };
+
+        match action {
+            TransferAction::Pause => {
+                info!("Pausing {}", transfer_id);
+                self.set_paused(&transfer_id, true);
+                send(write, &WebSocketMessage::TransferPause { transfer_id }).await
+            }
+            TransferAction::Resume => {
+                info!("Resuming {}", transfer_id);
+                self.set_paused(&transfer_id, false);
+                send(write, &WebSocketMessage::TransferResume { transfer_id }).await
+            }
+            TransferAction::Cancel => {
+                info!("Cancelling {}", transfer_id);
+                let was_offer = self.pending.is_some();
+                self.drop_transfer(&transfer_id).await;
+
+                if was_offer {
+                    send(write, &reject("Cancelled by the receiver")).await
+                } else {
+                    self.discarding = true;
+                    send(write, &WebSocketMessage::TransferCancel { transfer_id }).await
+                }
+            }
+        }
+    }
+
+    fn set_paused(&self, id: &str, paused: bool) {
+        if let Some(transfers) = self.transfers() {
+            transfers.pause(id, paused);
+        }
+    }
+
+    /// Withdraw a cancelled offer or stop a cancelled download
+    async fn drop_transfer(&mut self, id: &str) {
+        if let Some(pending) = self.pending.take_if(|pending| pending.offer.id == id)
+            && let Some(daemon) = &self.daemon
+        {
+            daemon.offers.remove(&pending.offer.id);
+        }
+        if let Some(download) = self.download.take_if(|download| download.id == id) {
+            download.cancel().await;
+        }
+    }
+
+    async fn handle_binary(&mut self, data: &[u8], write: &mut ServerSink) -> Result<()> {
+        if !self.authenticated {
+            return Err(eyre!("{} sent data before authenticating", self.peer));
+        }
+
+        let Some(test) = &mut self.speed_test else {
+            if self.discarding {
+                return Ok(());
+            }
+            if self.download.is_none() {
+                info!("Received binary: {} bytes", data.len());
+                handle_binary_data(data);
+                return Ok(());
+            }
+
+            let chunk = BinaryChunk::decode(data)?;
+            return self
+                .receive_chunk(chunk.offset, chunk.data, chunk.compressed, write)
+                .await;
+        };
+
+        test.received += data.len() as u64;
+        if test.received >= test.size {
+            let result = WebSocketMessage::SpeedTestResult {
+                received: test.received,
+                elapsed_ms: u64::try_from(test.started.elapsed().as_millis())?,
+            };
+            send(write, &result).await?;
+            self.speed_test = None;
+        }
+
+        Ok(())
+    }
+
+    /// Withdraw the pending offer and delete any unfinished download
+    async fn close(&mut self) {
+        if let (Some(pending), Some(daemon)) = (self.pending.take(), &self.daemon) {
+            daemon.offers.remove(&pending.offer.id);
+            let reason = "The sender disconnected";
+            self.finish(
+                &pending.offer.id,
+                TransferState::Failed,
+                Some(reason.into()),
+            );
+        }
+        if let Some(download) = self.download.take() {
+            warn!("Connection closed before {} was complete", download.id);
+            let reason = "The connection closed before the file was complete";
+            self.finish(&download.id, TransferState::Failed, Some(reason.into()));
+            download.abort().await;
+        }
+    }
+
+    fn transfers(&self) -> Option<&TransferTracker> {
+        self.daemon.as_deref().map(|daemon| &daemon.transfers)
+    }
+
+    fn finish(&self, id: &str, state: TransferState, error: Option<String>) {
+        if let Some(transfers) = self.transfers() {
+            transfers.finish(id, state, error);
+        }
+    }
+}
+
+async fn send(write: &mut ServerSink, message: &WebSocketMessage) -> Result<()> {
+    write.send(Message::text(message.to_json()?)).await?;
+    Ok(())
+}
+
+fn reject(reason: &str) -> WebSocketMessage {
+    WebSocketMessage::FileAccept {
+        accept: false,
+        reason: Some(reason.to_string()),
+    }
+}
+
+/// Contents of a chunk, decompressed if the sender compressed it
+fn chunk_data(
+    data: &[u8],
+    compressed: bool,
+    compression: Option<Compression>,
+) -> Result<Cow<'_, [u8]>> {
+    if data.len() > max_chunk_size() {
+        return Err(eyre!(
+            "Received a chunk larger than {} bytes",
+            max_chunk_size()
+        ));
+    }
+    if !compressed {
+        return Ok(Cow::Borrowed(data));
+    }
+
+    let compression =
+        compression.ok_or_else(|| eyre!("Received a compressed chunk, but no compression"))?;
+    Ok(Cow::Owned(compression.decompress(data, max_chunk_size())?))
+}
+
+fn error(message: &str) -> WebSocketMessage {
+    WebSocketMessage::Error {
+        message: message.to_string(),
+    }
+}
+
+fn handle_websocket_message(msg: WebSocketMessage) {
+    // Only the connecting end is answered with these
+    if let WebSocketMessage::AuthResponse { success, message } = msg {
+        info!("Authentication response: {success} - {message:?}");
     }
 }
 
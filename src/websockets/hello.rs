@@ -0,0 +1,140 @@
+use color_eyre::{Result, eyre::eyre};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+use crate::transfers::{
+    chunking,
+    compression::{self, Compression},
+};
+
+/// Version of the WebSocket protocol, peers only talk to peers speaking the same one
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Bytes of chunks held in memory before they are written, advertised to senders
+const BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// How files are hashed to check they arrived intact
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+}
+
+/// What a peer can do, the two ends use what they have in common
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Features {
+    /// Compressions the peer can decompress chunks with
+    pub compression: Vec<Compression>,
+    /// Whether the peer takes chunks as binary messages rather than JSON
+    pub binary_chunks: bool,
+    /// Hashes the peer can check files with, in order of preference
+    pub hashes: Vec<HashAlgorithm>,
+}
+
+/// How much a peer takes at once when receiving
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Limits {
+    /// Largest chunk, in bytes before compression
+    pub max_chunk_size: usize,
+    /// Bytes of chunks the peer holds before writing them, and so how many may be sent
+    /// without being acknowledged
+    pub buffer_size: usize,
+}
+
+/// First message on every connection, sent by the connecting end and answered by the other
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    /// Id the peer is discovered by, if it runs discovery
+    pub peer_id: Option<String>,
+    pub features: Features,
+    pub limits: Limits,
+}
+
+/// What was agreed with a peer in the hello exchange
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub peer_id: Option<String>,
+    /// Compressions both ends allow, in this end's order of preference
+    pub compression: Vec<Compression>,
+    pub binary_chunks: bool,
+    pub hash: HashAlgorithm,
+    /// What the peer takes when receiving
+    pub limits: Limits,
+}
+
+impl Hello {
+    /// This end's hello, offering compression if it is enabled
+    #[must_use]
+    pub fn new(peer_id: Option<String>, compression: bool) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            peer_id,
+            features: Features {
+                compression: if compression {
+                    compression::SUPPORTED.to_vec()
+                } else {
+                    Vec::new()
+                },
+                binary_chunks: true,
+                hashes: vec![HashAlgorithm::Sha256],
+            },
+            limits: Limits {
+                max_chunk_size: max_chunk_size(),
+                buffer_size: BUFFER_SIZE,
+            },
+        }
+    }
+
+    /// What this end and the peer that sent `remote` can do together, failing if they
+    /// can't talk to each other at all
+    pub fn negotiate(&self, remote: &Self) -> Result<Session> {
+        let peer = remote.peer_id.as_deref().unwrap_or("The peer");
+
+        if remote.version != self.version {
+            return Err(eyre!(
+                "{} speaks protocol version {}, but {} speaks {}, update the older alacrite",
+                peer,
+                remote.version,
+                self.peer_id.as_deref().unwrap_or("this peer"),
+                self.version
+            ));
+        }
+
+        let hash = self
+            .features
+            .hashes
+            .iter()
+            .copied()
+            .find(|hash| remote.features.hashes.contains(hash))
+            .ok_or_else(|| {
+                eyre!(
+                    "{} checks files with {:?}, this end only with {:?}",
+                    peer,
+                    remote.features.hashes,
+                    self.features.hashes
+                )
+            })?;
+
+        Ok(Session {
+            peer_id: remote.peer_id.clone(),
+            compression: self
+                .features
+                .compression
+                .iter()
+                .copied()
+                .filter(|compression| remote.features.compression.contains(compression))
+                .collect(),
+            binary_chunks: self.features.binary_chunks && remote.features.binary_chunks,
+            hash,
+            limits: remote.limits,
+        })
+    }
+}
+
+/// Largest chunk taken, fitting both the buffer and the frames of the default WebSocket
+/// config connections are accepted with
+#[must_use]
+pub fn max_chunk_size() -> usize {
+    chunking::max_chunk_size(WebSocketConfig::default().max_frame_size).min(BUFFER_SIZE)
+}
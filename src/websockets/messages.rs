@@ -1,21 +1,56 @@
+use color_eyre::{Result, eyre::eyre};
 use serde::{Deserialize, Serialize};
 
+use crate::{transfers::compression::Compression, websockets::hello::Hello};
+
+/// Bytes before the data of a binary chunk, the offset as a big-endian `u64` and a flags byte
+const BINARY_CHUNK_HEADER: usize = 9;
+/// Flag of binary chunks whose data is compressed
+const COMPRESSED_FLAG: u8 = 1;
+
 /// WebSocket message types for file sharing
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum WebSocketMessage {
-    /// Authentication message
+    /// Protocol version, features and limits, exchanged before anything else
+    Hello(Hello),
+
+    /// Sent by the receiving end after its hello, for the connecting end to sign
+    AuthChallenge {
+        nonce: Vec<u8>,
+    },
+    /// SSH signature over the challenge, made with the key of the peer id said hello with
     Auth {
-        token: String,
+        signature: String,
     },
-    /// Response to authentication
+    /// Response to authentication, nothing but the handshake is taken before it succeeds
     AuthResponse {
         success: bool,
         message: Option<String>,
     },
 
+    /// Sent instead of `Auth` by a peer that was given a pairing code, with its SPAKE2
+    /// message and the first group of the code, which the receiving end has to be waiting for
+    PairRequest {
+        nameplate: String,
+        message: Vec<u8>,
+    },
+    /// The receiving end's SPAKE2 message
+    PairResponse {
+        message: Vec<u8>,
+    },
+    /// OpenSSH public key of the peer id said hello with, and a MAC over both made with the
+    /// key derived from the code, sent first by the connecting end
+    PairConfirm {
+        public_key: String,
+        mac: Vec<u8>,
+    },
+
     /// File offer from sender
     FileOffer {
+        /// Identifies the transfer on both ends, chosen by the sender
+        transfer_id: String,
+        /// Path relative to the directory being sent, `/` separated
         filename: String,
         size: u64,
         hash: String,
@@ -29,15 +64,54 @@ pub enum WebSocketMessage {
 
     /// Transfer control messages
     TransferStart {
+        /// Largest chunk the sender will send, chunks vary below it as throughput changes
         chunk_size: usize,
+        /// How chunks may be compressed, one of those the receiver said hello with
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<Compression>,
     },
+    /// Part of the file starting at `offset`, which counts uncompressed bytes, sent as a
+    /// [`BinaryChunk`] instead if both ends support binary chunks
     TransferChunk {
         data: Vec<u8>,
         offset: u64,
+        /// Whether `data` is compressed, chunks that don't shrink are sent as they are
+        #[serde(default)]
+        compressed: bool,
+    },
+    /// Sent by the receiver once everything before `offset` was written, the sender keeps
+    /// at most the `buffer_size` bytes of the receiver's hello sent but not acknowledged
+    TransferAck {
+        transfer_id: String,
+        offset: u64,
     },
+    /// Sent by the sender after the last chunk, and echoed by the receiver once the file
+    /// is saved
     TransferComplete {
         hash: String,
     },
+    /// Either end asks the sender to stop sending chunks until a `TransferResume`
+    TransferPause {
+        transfer_id: String,
+    },
+    TransferResume {
+        transfer_id: String,
+    },
+    /// Either end gives up on an offered or running transfer, the receiver drops the
+    /// chunks still on their way
+    TransferCancel {
+        transfer_id: String,
+    },
+
+    /// Start of a throughput test, followed by `size` bytes of binary messages
+    SpeedTest {
+        size: u64,
+    },
+    /// Throughput test result, as measured by the receiver
+    SpeedTestResult {
+        received: u64,
+        elapsed_ms: u64,
+    },
 
     /// Error message
     Error {
@@ -54,3 +128,34 @@ impl WebSocketMessage {
         serde_json::from_str(json)
     }
 }
+
+/// A chunk of the file being transferred, sent as a binary message
+pub struct BinaryChunk<'a> {
+    pub offset: u64,
+    pub compressed: bool,
+    pub data: &'a [u8],
+}
+
+impl<'a> BinaryChunk<'a> {
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(BINARY_CHUNK_HEADER + self.data.len());
+        message.extend_from_slice(&self.offset.to_be_bytes());
+        message.push(if self.compressed { COMPRESSED_FLAG } else { 0 });
+        message.extend_from_slice(self.data);
+        message
+    }
+
+    pub fn decode(message: &'a [u8]) -> Result<Self> {
+        if message.len() < BINARY_CHUNK_HEADER {
+            return Err(eyre!("Received a binary chunk without a header"));
+        }
+
+        let (offset, rest) = message.split_at(8);
+        Ok(Self {
+            offset: u64::from_be_bytes(offset.try_into()?),
+            compressed: rest[0] & COMPRESSED_FLAG != 0,
+            data: &rest[1..],
+        })
+    }
+}
@@ -1,8 +1,15 @@
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite};
 
+pub mod auth;
 pub mod event_loop;
 pub mod handlers;
+pub mod hello;
 pub mod messages;
+pub mod pairing;
+pub mod speed_test;
+
+pub type ReadStream =
+    futures::stream::SplitStream<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>>;
 
 pub type WriteSink = futures::stream::SplitSink<
     WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
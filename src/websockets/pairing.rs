@@ -0,0 +1,280 @@
+//! Pairing two peers with a short code, so each trusts the other's key from then on
+//!
+//! The peer running `alacrite pair` shows a code and the other types it in. Both run SPAKE2
+//! over Ristretto with the code as the password, which gives them a shared key only if they
+//! used the same code, without the code or anything to guess it offline ever being sent.
+//! The key also covers the ids both ends said hello with, so it only matches if each paired
+//! with the peer the other believes it is. Each then sends its public key with a MAC made
+//! with that key, and trusts the other's once the MAC checks out. A waiting peer only takes
+//! one attempt per code.
+
+use std::time::Duration;
+
+use color_eyre::{Result, eyre::eyre};
+use curve25519_dalek::{
+    RistrettoPoint, Scalar, constants::RISTRETTO_BASEPOINT_POINT, ristretto::CompressedRistretto,
+};
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256, Sha512};
+use ssh_key::PublicKey;
+
+/// How long a code is waited for, and how long pairing with a peer may take
+pub const PAIRING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Characters of codes, Crockford's base32 without the letters mistaken for digits
+const ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// Characters in each of the two groups of a code
+const GROUP_LEN: usize = 4;
+
+/// Labels the fixed points M and N are derived from, so nobody knows their discrete logs
+const POINT_M: &[u8] = b"alacrite-pairing M";
+const POINT_N: &[u8] = b"alacrite-pairing N";
+
+/// Which end of the pairing a peer is, each blinds its message with a different point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Showed the code and waits for it
+    Host,
+    /// Typed the code in and connects to the host
+    Guest,
+}
+
+impl Role {
+    fn point(self) -> RistrettoPoint {
+        hash_to_point(match self {
+            Self::Host => POINT_N,
+            Self::Guest => POINT_M,
+        })
+    }
+
+    const fn other(self) -> Self {
+        match self {
+            Self::Host => Self::Guest,
+            Self::Guest => Self::Host,
+        }
+    }
+
+    const fn label(self) -> &'static [u8] {
+        match self {
+            Self::Host => b"host",
+            Self::Guest => b"guest",
+        }
+    }
+}
+
+/// A fresh code to show, two groups of four characters
+#[must_use]
+pub fn code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code: String = (0..GROUP_LEN * 2)
+        .map(|_| char::from(ALPHABET[rng.gen_range(0..ALPHABET.len())]))
+        .collect();
+    code.insert(GROUP_LEN, '-');
+    code
+}
+
+/// A code as typed in, uppercased and with the letters read as digits turned back into them
+pub fn normalize(code: &str) -> Result<String> {
+    let mut normalized: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        })
+        .collect();
+
+    if normalized.len() != GROUP_LEN * 2 || !normalized.bytes().all(|byte| ALPHABET.contains(&byte))
+    {
+        return Err(eyre!(
+            "{code} isn't a pairing code, they look like {}",
+            self::code()
+        ));
+    }
+    normalized.insert(GROUP_LEN, '-');
+    Ok(normalized)
+}
+
+/// First group of a normalized code, sent in the clear so peers waiting for other codes
+/// can turn the guest away without losing their one attempt
+#[must_use]
+pub fn nameplate(code: &str) -> &str {
+    &code[..GROUP_LEN]
+}
+
+/// One end's half of the SPAKE2 exchange
+pub struct Spake {
+    role: Role,
+    password: Scalar,
+    secret: Scalar,
+    message: [u8; 32],
+    own_id: String,
+    peer_id: String,
+}
+
+impl Spake {
+    /// Start the exchange as `role`, with a normalized code, between the peer with `own_id`
+    /// and the one that said hello with `peer_id`
+    #[must_use]
+    pub fn start(role: Role, code: &str, own_id: &str, peer_id: &str) -> Self {
+        let password = Scalar::from_bytes_mod_order_wide(&Sha512::digest(code).into());
+        let mut bytes = [0; 64];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = Scalar::from_bytes_mod_order_wide(&bytes);
+
+        let message = secret * RISTRETTO_BASEPOINT_POINT + password * role.point();
+        Self {
+            role,
+            password,
+            secret,
+            message: message.compress().to_bytes(),
+            own_id: own_id.to_string(),
+            peer_id: peer_id.to_string(),
+        }
+    }
+
+    /// What to send the other end
+    #[must_use]
+    pub fn message(&self) -> Vec<u8> {
+        self.message.to_vec()
+    }
+
+    /// The key shared with the other end, which only matches theirs if both used the same
+    /// code and agree on who is pairing with whom
+    pub fn finish(self, remote: &[u8]) -> Result<PairingKey> {
+        let point = CompressedRistretto::from_slice(remote)
+            .ok()
+            .and_then(|point| point.decompress())
+            .ok_or_else(|| eyre!("The peer sent an invalid pairing message"))?;
+        let shared = self.secret * (point - self.password * self.role.other().point());
+
+        let (host, guest) = match self.role {
+            Role::Host => ((&self.own_id, &self.message[..]), (&self.peer_id, remote)),
+            Role::Guest => ((&self.peer_id, remote), (&self.own_id, &self.message[..])),
+        };
+        let mut transcript = Sha256::new().chain_update(b"alacrite-pairing");
+        for part in [
+            guest.0.as_bytes(),
+            host.0.as_bytes(),
+            guest.1,
+            host.1,
+            shared.compress().as_bytes(),
+        ] {
+            transcript.update((part.len() as u64).to_be_bytes());
+            transcript.update(part);
+        }
+        Ok(PairingKey(transcript.finalize().into()))
+    }
+}
+
+/// Key both ends derived from the code, proving the public keys they send come from each
+/// other
+pub struct PairingKey([u8; 32]);
+
+impl PairingKey {
+    /// MAC over the id and public key the end playing `role` pairs with
+    pub fn confirm(&self, role: Role, peer_id: &str, public_key: &PublicKey) -> Result<Vec<u8>> {
+        Ok(self
+            .mac(role, peer_id, public_key)?
+            .finalize()
+            .into_bytes()
+            .to_vec())
+    }
+
+    /// Check the other end, playing `role`, knew the code when it sent its id and key
+    pub fn verify(
+        &self,
+        role: Role,
+        peer_id: &str,
+        public_key: &PublicKey,
+        mac: &[u8],
+    ) -> Result<()> {
+        self.mac(role, peer_id, public_key)?
+            .verify_slice(mac)
+            .map_err(|_| eyre!("The pairing codes don't match, or someone else answered"))
+    }
+
+    fn mac(&self, role: Role, peer_id: &str, public_key: &PublicKey) -> Result<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0)?;
+        mac.update(role.label());
+        mac.update(peer_id.as_bytes());
+        mac.update(public_key.to_openssh()?.as_bytes());
+        Ok(mac)
+    }
+}
+
+fn hash_to_point(label: &[u8]) -> RistrettoPoint {
+    RistrettoPoint::from_uniform_bytes(&Sha512::digest(label).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_ID: &str = "guest-id";
+    const HOST_ID: &str = "host-id";
+    const GUEST_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJiula4VgUg4CP9AoIazw1BJAlqiOC0TmHpNVueVD83z";
+    const HOST_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGDecpq2FEaKBOijD940icSIl+7mVUs/x/Lee1PiY9xv";
+
+    /// Run both ends of the exchange, the guest believing it talks to `host_id`
+    fn exchange(guest_code: &str, host_code: &str, host_id: &str) -> (PairingKey, PairingKey) {
+        let guest = Spake::start(Role::Guest, guest_code, GUEST_ID, host_id);
+        let host = Spake::start(Role::Host, host_code, HOST_ID, GUEST_ID);
+        let (guest_message, host_message) = (guest.message(), host.message());
+
+        (
+            guest.finish(&host_message).unwrap(),
+            host.finish(&guest_message).unwrap(),
+        )
+    }
+
+    /// Whether each end takes the other's confirmation
+    fn confirmed(guest: &PairingKey, host: &PairingKey) -> bool {
+        let guest_key = PublicKey::from_openssh(GUEST_KEY).unwrap();
+        let host_key = PublicKey::from_openssh(HOST_KEY).unwrap();
+
+        let guest_mac = guest.confirm(Role::Guest, GUEST_ID, &guest_key).unwrap();
+        let host_mac = host.confirm(Role::Host, HOST_ID, &host_key).unwrap();
+
+        host.verify(Role::Guest, GUEST_ID, &guest_key, &guest_mac)
+            .is_ok()
+            && guest
+                .verify(Role::Host, HOST_ID, &host_key, &host_mac)
+                .is_ok()
+    }
+
+    #[test]
+    fn same_code_derives_same_key() {
+        let code = code();
+        let (guest, host) = exchange(&code, &code, HOST_ID);
+
+        assert_eq!(guest.0, host.0);
+        assert!(confirmed(&guest, &host));
+    }
+
+    #[test]
+    fn wrong_code_fails_verify() {
+        let (guest, host) = exchange("ABCD-EFGH", "ABCD-EFGJ", HOST_ID);
+
+        assert!(!confirmed(&guest, &host));
+    }
+
+    #[test]
+    fn other_peer_fails_verify() {
+        let code = code();
+        let (guest, host) = exchange(&code, &code, "someone-else");
+
+        assert!(!confirmed(&guest, &host));
+    }
+
+    #[test]
+    fn normalize_reads_lookalikes_as_digits() {
+        assert_eq!(normalize("abcd-efgo").unwrap(), "ABCD-EFG0");
+        assert_eq!(normalize(" il23 4567 ").unwrap(), "1123-4567");
+        assert!(normalize("ABCD-EFGU").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::{net::SocketAddr, time::Duration};
+
+use color_eyre::{Result, eyre::eyre};
+use futures::{SinkExt, StreamExt};
+use tokio::time::timeout;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::{
+    ssh::key_manager::KeyManager,
+    websockets::{handlers::client, hello::Hello, messages::WebSocketMessage},
+};
+
+/// Size of the binary messages a throughput test is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Longest a throughput test may take, so slow links don't hold up discovery
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Measure throughput to the WebSocket server at `addr` by sending it `size` bytes
+///
+/// Returns bytes per second, as timed by the receiving end so connection setup isn't counted.
+/// The receiver has to be `peer_id` and trust the key of `key_manager`.
+pub async fn measure_throughput(
+    addr: SocketAddr,
+    peer_id: &str,
+    size: u64,
+    hello: &Hello,
+    key_manager: &KeyManager,
+) -> Result<f64> {
+    timeout(
+        TEST_TIMEOUT,
+        run_test(addr, peer_id, size, hello, key_manager),
+    )
+    .await
+    .map_err(|_| eyre!("Throughput test to {addr} timed out"))?
+}
+
+async fn run_test(
+    addr: SocketAddr,
+    peer_id: &str,
+    size: u64,
+    hello: &Hello,
+    key_manager: &KeyManager,
+) -> Result<f64> {
+    let (ws_stream, _) = connect_async(format!("ws://{addr}")).await?;
+    let (mut write, mut read) = ws_stream.split();
+
+    client::handshake(&mut write, &mut read, hello, key_manager, Some(peer_id)).await?;
+    write
+        .send(Message::text(
+            WebSocketMessage::SpeedTest { size }.to_json()?,
+        ))
+        .await?;
+
+    let chunk = vec![0; CHUNK_SIZE];
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = usize::try_from(remaining).map_or(CHUNK_SIZE, |len| len.min(CHUNK_SIZE));
+        write.send(Message::binary(chunk[..len].to_vec())).await?;
+        remaining -= len as u64;
+    }
+
+    while let Some(msg) = read.next().await {
+        if let Message::Text(text) = msg?
+            && let Ok(WebSocketMessage::SpeedTestResult {
+                received,
+                elapsed_ms,
+            }) = WebSocketMessage::from_json(&text)
+        {
+            write.close().await?;
+
+            #[allow(clippy::cast_precision_loss)]
+            return Ok(received as f64 * 1000.0 / elapsed_ms.max(1) as f64);
+        }
+    }
+
+    Err(eyre!(
+        "Connection to {addr} closed before the throughput test finished"
+    ))
+}